@@ -0,0 +1,249 @@
+use std::cell::RefCell;
+
+use super::{BusDevice, Result, Unit};
+
+const PHYSICAL_SPACE_SIZE: u64 = 0x20000000;
+
+/// Object-safe side of a `BusDevice`, what the bus keeps for devices attached
+/// at runtime. Every `BusDevice` that can be cloned for snapshots is one.
+pub trait Attachable: Send {
+    fn read_sized(&self, offset: u32, size: u32) -> Result<u32>;
+    fn write_sized(&self, offset: u32, size: u32, val: u32) -> Result<()>;
+    fn peek_sized(&self, offset: u32, size: u32) -> Option<u32>;
+    fn poke_sized(&self, offset: u32, size: u32, val: u32) -> bool;
+    fn clone_boxed(&self) -> Box<dyn Attachable>;
+}
+
+impl<D: BusDevice + Clone + Send + 'static> Attachable for D {
+    fn read_sized(&self, offset: u32, size: u32) -> Result<u32> {
+        match size {
+            1 => self.read::<u8>(offset).map(Into::into),
+            2 => self.read::<u16>(offset).map(Into::into),
+            _ => self.read::<u32>(offset),
+        }
+    }
+    fn write_sized(&self, offset: u32, size: u32, val: u32) -> Result<()> {
+        match size {
+            1 => self.write::<u8>(offset, val as u8),
+            2 => self.write::<u16>(offset, val as u16),
+            _ => self.write::<u32>(offset, val),
+        }
+    }
+    fn peek_sized(&self, offset: u32, size: u32) -> Option<u32> {
+        match size {
+            1 => self.peek::<u8>(offset).map(Into::into),
+            2 => self.peek::<u16>(offset).map(Into::into),
+            _ => self.peek::<u32>(offset),
+        }
+    }
+    fn poke_sized(&self, offset: u32, size: u32, val: u32) -> bool {
+        match size {
+            1 => self.poke::<u8>(offset, val as u8),
+            2 => self.poke::<u16>(offset, val as u16),
+            _ => self.poke::<u32>(offset, val),
+        }
+    }
+    fn clone_boxed(&self) -> Box<dyn Attachable> {
+        Box::new(self.clone())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AttachError {
+    /// The range overlaps the device named `with`
+    Overlap { name: &'static str, with: &'static str },
+    /// The range is empty or goes past the 512MiB physical address space
+    BadRange { name: &'static str },
+}
+
+impl std::fmt::Display for AttachError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttachError::Overlap { name, with } => write!(f, "{} overlaps {}", name, with),
+            AttachError::BadRange { name } => write!(f, "{} has a bad address range", name),
+        }
+    }
+}
+
+/// A device and the physical range it answers to, it sees offsets from `base`
+struct Attachment {
+    name: &'static str,
+    base: u32,
+    size: u32,
+    device: Box<dyn Attachable>,
+}
+
+impl Attachment {
+    fn contains(&self, addr: u32) -> bool {
+        addr.wrapping_sub(self.base) < self.size
+    }
+}
+
+impl Clone for Attachment {
+    fn clone(&self) -> Self {
+        Self { name: self.name, base: self.base, size: self.size, device: self.device.clone_boxed() }
+    }
+}
+
+/// Name, base and size of a device hard-wired in the machine
+pub type Builtin = (&'static str, u32, u32);
+
+/// Devices attached to the bus at runtime, looked up by physical address
+/// after the built-in ones
+#[derive(Clone)]
+pub struct DeviceMap {
+    builtin: Vec<Builtin>,
+    attached: RefCell<Vec<Attachment>>,
+}
+
+impl DeviceMap {
+    /// Attached devices can't overlap `builtin`
+    pub fn new(builtin: impl IntoIterator<Item = Builtin>) -> Self {
+        Self { builtin: builtin.into_iter().collect(), attached: RefCell::new(vec![]) }
+    }
+
+    pub fn builtin(&self) -> &[Builtin] {
+        &self.builtin
+    }
+
+    /// Name of the device, built-in or attached, overlapping `base..base + size`
+    pub fn overlapping(&self, base: u32, size: u32) -> Option<&'static str> {
+        let overlaps = |start: u32, len: u32| {
+            (base as u64) < start as u64 + len as u64 && (start as u64) < base as u64 + size as u64
+        };
+        let attached = self.attached.borrow();
+        self.builtin.iter().find(|(_, start, len)| overlaps(*start, *len)).map(|(name, ..)| *name)
+            .or_else(|| attached.iter().find(|att| overlaps(att.base, att.size)).map(|att| att.name))
+    }
+
+    /// Makes `device` answer to physical `base..base + size`
+    pub fn attach(&self, name: &'static str, base: u32, size: u32, device: impl Attachable + 'static) -> std::result::Result<(), AttachError> {
+        if size == 0 || base as u64 + size as u64 > PHYSICAL_SPACE_SIZE {
+            return Err(AttachError::BadRange { name });
+        }
+        if let Some(with) = self.overlapping(base, size) {
+            return Err(AttachError::Overlap { name, with });
+        }
+        self.attached.borrow_mut().push(Attachment { name, base, size, device: Box::new(device) });
+        Ok(())
+    }
+
+    pub fn detach(&self, name: &str) -> bool {
+        let mut attachments = self.attached.borrow_mut();
+        let before = attachments.len();
+        attachments.retain(|att| att.name != name);
+        before != attachments.len()
+    }
+
+    /// Name, base and size of every attached device
+    pub fn list(&self) -> Vec<(&'static str, u32, u32)> {
+        self.attached.borrow().iter().map(|att| (att.name, att.base, att.size)).collect()
+    }
+
+    pub fn name_at(&self, addr: u32) -> Option<&'static str> {
+        self.attached.borrow().iter().find(|att| att.contains(addr)).map(|att| att.name)
+    }
+
+    /// `None` when no attached device answers to `addr`
+    pub fn read<U: Unit>(&self, addr: u32) -> Option<Result<U>> {
+        let attachments = self.attached.borrow();
+        let att = attachments.iter().find(|att| att.contains(addr))?;
+        Some(att.device.read_sized(addr - att.base, U::SIZE).map(U::from_u32))
+    }
+
+    pub fn write<U: Unit>(&self, addr: u32, val: U) -> Option<Result<()>> {
+        let attachments = self.attached.borrow();
+        let att = attachments.iter().find(|att| att.contains(addr))?;
+        Some(att.device.write_sized(addr - att.base, U::SIZE, val.into()))
+    }
+
+    /// `None` when no attached device answers to `addr`, `Some(None)` when
+    /// one does but can't be peeked
+    pub fn peek<U: Unit>(&self, addr: u32) -> Option<Option<U>> {
+        let attachments = self.attached.borrow();
+        let att = attachments.iter().find(|att| att.contains(addr))?;
+        Some(att.device.peek_sized(addr - att.base, U::SIZE).map(U::from_u32))
+    }
+
+    pub fn poke<U: Unit>(&self, addr: u32, val: U) -> Option<bool> {
+        let attachments = self.attached.borrow();
+        let att = attachments.iter().find(|att| att.contains(addr))?;
+        Some(att.device.poke_sized(addr - att.base, U::SIZE, val.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use crate::core::{bus::{BusDevice, BusErrorKind, memory::RamMemory}, machine::Machine};
+    use super::*;
+
+    /// Counts the writes it gets and where the last one landed, reads fail
+    /// past its second word
+    #[derive(Clone, Default)]
+    struct Probe {
+        writes: Cell<u32>,
+        last_offset: Cell<u32>,
+    }
+
+    impl BusDevice for Probe {
+        fn read<U: Unit>(&self, addr: u32) -> Result<U> {
+            match addr {
+                0..4 => Ok(U::from_u32(self.writes.get())),
+                4..8 => Ok(U::from_u32(self.last_offset.get())),
+                _ => Err(BusErrorKind::CannotRead.into()),
+            }
+        }
+        fn write<U: Unit>(&self, addr: u32, _val: U) -> Result<()> {
+            self.writes.set(self.writes.get() + 1);
+            self.last_offset.set(addr);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_attach() {
+        let machine = Machine::new();
+        machine.attach("probe", 0x1F000000, 0x10, Probe::default()).unwrap();
+        machine.write::<u16>(0xBF000006, 0xBEEF).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F000004).unwrap(), 6);
+        machine.write::<u8>(0x1F000000, 1).unwrap();
+        assert_eq!(machine.read::<u32>(0x9F000000).unwrap(), 2);
+
+        let err = machine.read::<u32>(0x1F000008).unwrap_err();
+        assert_eq!((err.kind, err.device), (BusErrorKind::CannotRead, "probe"));
+
+        // past the probe it's Expansion 1 again
+        assert_eq!(machine.read::<u8>(0x1F000010).unwrap(), 0xFF);
+
+        // snapshots get their own copy
+        let snapshot = machine.clone();
+        machine.write::<u32>(0x1F000000, 0).unwrap();
+        assert_eq!(snapshot.read::<u32>(0x1F000000).unwrap(), 2);
+
+        assert!(machine.devices.detach("probe"));
+        assert!(!machine.devices.detach("probe"));
+        assert_eq!(machine.read::<u8>(0x1F000000).unwrap(), 0xFF);
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let machine = Machine::new();
+        machine.attach("sram", 0x1FA00000, 0x1000, RamMemory::new(0x1000)).unwrap();
+        assert_eq!(machine.attach("other", 0x1FA00FFC, 8, Probe::default()),
+            Err(AttachError::Overlap { name: "other", with: "sram" }));
+        assert_eq!(machine.attach("other", 0x1F801062, 2, Probe::default()),
+            Err(AttachError::Overlap { name: "other", with: "ram size" }));
+        assert_eq!(machine.attach("other", 0x007FF000, 0x2000, Probe::default()),
+            Err(AttachError::Overlap { name: "other", with: "ram" }));
+        assert_eq!(machine.attach("other", 0x1FFFFFF0, 0x20, Probe::default()),
+            Err(AttachError::BadRange { name: "other" }));
+        assert_eq!(machine.attach("other", 0x1FA01000, 0, Probe::default()),
+            Err(AttachError::BadRange { name: "other" }));
+        machine.attach("other", 0x1FA01000, 4, Probe::default()).unwrap();
+        assert_eq!(machine.devices.list(), vec![("sram", 0x1FA00000, 0x1000), ("other", 0x1FA01000, 4)]);
+
+        machine.write::<u32>(0xBFA00FFC, 0x12345678).unwrap();
+        assert_eq!(machine.read::<u16>(0x1FA00FFE).unwrap(), 0x1234);
+    }
+}
@@ -0,0 +1,524 @@
+use std::cell::{Cell, RefCell};
+
+use crate::core::bus::mmio::{registers, Mmio, Register, RegisterFile};
+
+/*
+  DMA0 MDEC IN  (RAM to MDEC)
+  DMA1 MDEC OUT (MDEC to RAM)
+  DMA2 GPU (lists + image data)
+  DMA3 CDROM    (CDROM to RAM)
+  DMA4 SPU
+  DMA5 PIO (Expansion Port)
+  DMA6 OTC (reverse clear OT) (GPU related) */
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmaChannel {
+    MdecIn = 0,
+    MdecOut = 1,
+    Gpu = 2,
+    CdRom = 3,
+    Spu = 4,
+    Pio = 5,
+    Otc = 6,
+}
+
+impl DmaChannel {
+    pub const ALL: [DmaChannel; 7] = [
+        DmaChannel::MdecIn, DmaChannel::MdecOut, DmaChannel::Gpu, DmaChannel::CdRom,
+        DmaChannel::Spu, DmaChannel::Pio, DmaChannel::Otc,
+    ];
+}
+
+/// The device side of a DMA channel, one word at a time
+pub trait DmaPort: Send + CloneDmaPort {
+    /// Next word of a device to RAM transfer
+    fn dma_read(&self) -> u32;
+    /// Next word of a RAM to device transfer
+    fn dma_write(&self, val: u32);
+}
+
+/// Lets the DMA controller clone the ports connected to it with the machine
+pub trait CloneDmaPort {
+    fn clone_boxed(&self) -> Box<dyn DmaPort>;
+}
+
+impl<P: DmaPort + Clone + 'static> CloneDmaPort for P {
+    fn clone_boxed(&self) -> Box<dyn DmaPort> {
+        Box::new(self.clone())
+    }
+}
+
+/// What a channel with no device behind it talks to: reads zero, drops writes
+#[derive(Clone)]
+pub struct Unconnected;
+
+impl DmaPort for Unconnected {
+    fn dma_read(&self) -> u32 {
+        0
+    }
+    fn dma_write(&self, _val: u32) {}
+}
+
+/// The RAM side of a transfer, word-aligned physical addresses
+pub trait DmaMemory {
+    fn dma_load(&self, addr: u32) -> u32;
+    fn dma_store(&self, addr: u32, val: u32);
+}
+
+const CHCR_FROM_RAM: u32 = 1 << 0;
+const CHCR_BACKWARDS: u32 = 1 << 1;
+const CHCR_BUSY: u32 = 1 << 24;
+const CHCR_TRIGGER: u32 = 1 << 28;
+/// Bit 23 of a linked list header ends the list, 0x00FFFFFF usually
+const LIST_END: u32 = 1 << 23;
+/// Cycles the CPU waits on each linked list header
+const LIST_HEADER_CYCLES: u64 = 1;
+/// Most linked list nodes walked in one transfer, as many as fit in 8MiB of
+/// RAM. Lists looping back on themselves are cut there.
+const LIST_NODE_LIMIT: u32 = 0x800000 / 4;
+
+pub struct Dma {
+    regs: RegisterFile,
+    /// DICR master flag as of the last `irq_edge`
+    irq_line: Cell<bool>,
+    /// Ports connected to each channel, by number
+    ports: RefCell<Vec<Option<Box<dyn DmaPort>>>>,
+}
+
+impl Clone for Dma {
+    fn clone(&self) -> Self {
+        let ports = self.ports.borrow().iter().map(|port| port.as_ref().map(|port| port.clone_boxed())).collect();
+        Self { regs: self.regs.clone(), irq_line: self.irq_line.clone(), ports: RefCell::new(ports) }
+    }
+}
+
+registers! {
+    /*
+    1F80108xh DMA0 channel 0 - MDECin
+    1F80109xh DMA1 channel 1 - MDECout
+    1F8010Axh DMA2 channel 2 - GPU (lists + image data)
+    1F8010Bxh DMA3 channel 3 - CDROM
+    1F8010Cxh DMA4 channel 4 - SPU
+    1F8010Dxh DMA5 channel 5 - PIO (Expansion Port)
+    1F8010Exh DMA6 channel 6 - OTC (reverse clear OT) (GPU related)
+    1F8010F0h DPCR - DMA Control register
+    1F8010F4h DICR - DMA Interrupt register
+    1F8010F8h unknown
+    1F8010FCh unknown
+    Each channel has MADR (base address) at +0, BCR (block control) at +4
+    and CHCR (channel control) at +8.
+
+    CHCR:
+      0     Transfer Direction    (0=To Main RAM, 1=From Main RAM)
+      1     Memory Address Step   (0=Forward;+4, 1=Backward;-4)
+      2-7   Not used              (always zero)
+      8     Chopping Enable       (0=Normal, 1=Chopping; run CPU during DMA gaps)
+      9-10  SyncMode, Transfer Synchronisation/Mode (0-3):
+              0  Start immediately and transfer all at once (used for CDROM, OTC)
+              1  Sync blocks to DMA requests   (used for MDEC, SPU, and GPU-data)
+              2  Linked-List mode              (used for GPU-command-lists)
+              3  Reserved                      (not used)
+      11-15 Not used              (always zero)
+      16-18 Chopping DMA Window Size (1 SHL N words)
+      19    Not used              (always zero)
+      20-22 Chopping CPU Window Size (1 SHL N clks)
+      23    Not used              (always zero)
+      24    Start/Busy            (0=Stopped/Completed, 1=Start/Enable/Busy)
+      25-27 Not used              (always zero)
+      28    Start/Trigger         (0=Normal, 1=Manual Start; use for SyncMode=0)
+      29    Unknown (R/W) Pause?  (0=No, 1=Pause?)     (For SyncMode=0 only?)
+      30    Unknown (R/W)
+      31    Not used              (always zero)
+    Only bits 24, 28 and 30 of the OTC channel are writable, bit 1 is fixed.
+
+    DICR:
+      0-5   Unknown  (read/write-able)
+      6-14  Not used (always zero)
+      15    Force IRQ (sets bit31)                        (0=None, 1=Force Bit31=1)
+      16-22 IRQ Enable setting bit24-30 upon DMA0..DMA6    (0=None, 1=Enable)
+      23    IRQ Enable setting bit31 when bit24-30=nonzero (0=None, 1=Enable)
+      24-30 IRQ Flags for DMA0..DMA6 (may be reset by writing 1)
+      31    IRQ Master Flag (0=None, 1=IRQ) (Read only)
+    Its top bytes are registers of their own, so a write to the flags only
+    acknowledges the lanes it covers. */
+    pub enum DmaRegister for Dma {
+        Madr0 = 0x1F801080: u32, write = 0x00FFFFFF;
+        Bcr0 = 0x1F801084: u32;
+        Chcr0 = 0x1F801088: u32, write = 0x71770703;
+        Madr1 = 0x1F801090: u32, write = 0x00FFFFFF;
+        Bcr1 = 0x1F801094: u32;
+        Chcr1 = 0x1F801098: u32, write = 0x71770703;
+        Madr2 = 0x1F8010A0: u32, write = 0x00FFFFFF;
+        Bcr2 = 0x1F8010A4: u32;
+        Chcr2 = 0x1F8010A8: u32, write = 0x71770703;
+        Madr3 = 0x1F8010B0: u32, write = 0x00FFFFFF;
+        Bcr3 = 0x1F8010B4: u32;
+        Chcr3 = 0x1F8010B8: u32, write = 0x71770703;
+        Madr4 = 0x1F8010C0: u32, write = 0x00FFFFFF;
+        Bcr4 = 0x1F8010C4: u32;
+        Chcr4 = 0x1F8010C8: u32, write = 0x71770703;
+        Madr5 = 0x1F8010D0: u32, write = 0x00FFFFFF;
+        Bcr5 = 0x1F8010D4: u32;
+        Chcr5 = 0x1F8010D8: u32, write = 0x71770703;
+        Madr6 = 0x1F8010E0: u32, write = 0x00FFFFFF;
+        Bcr6 = 0x1F8010E4: u32;
+        Chcr6 = 0x1F8010E8: u32, reset = CHCR_BACKWARDS, write = 0x51000000;
+        Dpcr = 0x1F8010F0: u32, reset = 0x07654321;
+        Dicr = 0x1F8010F4: u16, write = 0x803F;
+        DicrEnable = 0x1F8010F6: u8;
+        DicrFlags = 0x1F8010F7: u8, write = 0x7F, on_read = Dma::flags, on_peek = Dma::flags, on_write = Dma::flags_written;
+        Unknown0 = 0x1F8010F8: u32, reset = 0x7FFAC68B;
+        Unknown1 = 0x1F8010FC: u32, reset = 0x00FFFFF7;
+    }
+}
+
+const MADR: [DmaRegister; 7] = [
+    DmaRegister::Madr0, DmaRegister::Madr1, DmaRegister::Madr2, DmaRegister::Madr3,
+    DmaRegister::Madr4, DmaRegister::Madr5, DmaRegister::Madr6,
+];
+const BCR: [DmaRegister; 7] = [
+    DmaRegister::Bcr0, DmaRegister::Bcr1, DmaRegister::Bcr2, DmaRegister::Bcr3,
+    DmaRegister::Bcr4, DmaRegister::Bcr5, DmaRegister::Bcr6,
+];
+const CHCR: [DmaRegister; 7] = [
+    DmaRegister::Chcr0, DmaRegister::Chcr1, DmaRegister::Chcr2, DmaRegister::Chcr3,
+    DmaRegister::Chcr4, DmaRegister::Chcr5, DmaRegister::Chcr6,
+];
+
+impl Default for Dma {
+    fn default() -> Self {
+        let ports = DmaChannel::ALL.map(|_| None).into_iter().collect();
+        Self { regs: RegisterFile::new(DmaRegister::MAP), irq_line: Cell::new(false), ports: RefCell::new(ports) }
+    }
+}
+
+impl Mmio for Dma {
+    fn registers(&self) -> &'static [Register<Self>] {
+        DmaRegister::MAP
+    }
+    fn storage(&self) -> &RegisterFile {
+        &self.regs
+    }
+}
+
+impl Dma {
+    /// Plugs a device into `channel`, replacing whatever was there. The
+    /// machine's GPU answers channel 2 until a port is connected to it.
+    pub fn connect(&self, channel: DmaChannel, port: impl DmaPort + 'static) {
+        self.ports.borrow_mut()[channel as usize] = Some(Box::new(port));
+    }
+
+    /// Whether `connect` plugged a port into `channel`
+    pub fn connected(&self, channel: DmaChannel) -> bool {
+        self.ports.borrow()[channel as usize].is_some()
+    }
+
+    /// DICR flags with the master flag in bit 7
+    fn flags(&self) -> u32 {
+        self.regs.get(DmaRegister::DicrFlags) | (self.master_flag() as u32) << 7
+    }
+    fn flags_written(&self, old: u32, new: u32) {
+        self.regs.set(DmaRegister::DicrFlags, old & !new);
+    }
+
+    fn master_flag(&self) -> bool {
+        let force = self.regs.get(DmaRegister::Dicr) & 0x8000 != 0;
+        let enables = self.regs.get(DmaRegister::DicrEnable);
+        let flags = self.regs.get(DmaRegister::DicrFlags);
+        force || (enables & 0x80 != 0 && enables & flags & 0x7F != 0)
+    }
+
+    /// Whether the master flag went up since the last call, the edge raises
+    /// IRQ 3
+    pub fn irq_edge(&self) -> bool {
+        let line = self.master_flag();
+        let was_raised = self.irq_line.replace(line);
+        line && !was_raised
+    }
+
+    fn enabled(&self, channel: usize) -> bool {
+        self.regs.get(DmaRegister::Dpcr) >> (channel * 4 + 3) & 1 != 0
+    }
+
+    /// Enabled channel with a transfer to start, by DPCR priority: the lowest
+    /// value wins, and the highest channel among equals
+    pub fn next_channel(&self) -> Option<DmaChannel> {
+        let dpcr = self.regs.get(DmaRegister::Dpcr);
+        DmaChannel::ALL.into_iter()
+            .filter(|&channel| self.enabled(channel as usize) && self.ready(channel as usize))
+            .min_by_key(|&channel| (dpcr >> (channel as u32 * 4) & 7, 6 - channel as u32))
+    }
+
+    /// Start bit set, and for manual transfers the trigger too
+    fn ready(&self, channel: usize) -> bool {
+        let chcr = self.regs.get(CHCR[channel]);
+        let manual = (chcr >> 9) & 3 == 0;
+        chcr & CHCR_BUSY != 0 && (!manual || chcr & CHCR_TRIGGER != 0)
+    }
+
+    /// Runs the whole transfer of `channel` with the port connected to it,
+    /// or `Unconnected`
+    pub fn transfer(&self, channel: DmaChannel, memory: &impl DmaMemory) -> u64 {
+        match &self.ports.borrow()[channel as usize] {
+            Some(port) => self.transfer_with(channel, &**port, memory),
+            None => self.transfer_with(channel, &Unconnected, memory),
+        }
+    }
+
+    /// Runs the whole transfer of `channel` at once, with `port` on the other
+    /// end, returns the cycles the CPU was kept off the bus. Chopping isn't
+    /// emulated.
+    pub fn transfer_with(&self, channel: DmaChannel, port: &dyn DmaPort, memory: &impl DmaMemory) -> u64 {
+        let index = channel as usize;
+        let chcr = self.regs.get(CHCR[index]);
+        let madr = self.regs.get(MADR[index]);
+        let bcr = self.regs.get(BCR[index]);
+
+        let cycles = match (chcr >> 9) & 3 {
+            0 => {
+                // the address isn't written back
+                let words = match bcr & 0xFFFF { 0 => 0x10000, words => words };
+                self.transfer_block(channel, port, memory, chcr, madr, words);
+                words as u64
+            },
+            1 => {
+                let words = (bcr & 0xFFFF) * (bcr >> 16);
+                let end = self.transfer_block(channel, port, memory, chcr, madr, words);
+                self.regs.set(MADR[index], end & 0x00FFFFFF);
+                self.regs.set(BCR[index], bcr & 0xFFFF);
+                words as u64
+            },
+            2 => {
+                let (end, cycles) = self.transfer_list(port, memory, madr);
+                self.regs.set(MADR[index], end);
+                cycles
+            },
+            // reserved, nothing moves but the transfer still ends
+            _ => 0,
+        };
+
+        self.regs.set(CHCR[index], chcr & !(CHCR_BUSY | CHCR_TRIGGER));
+        let enables = self.regs.get(DmaRegister::DicrEnable);
+        if enables >> index & 1 != 0 {
+            let flags = self.regs.get(DmaRegister::DicrFlags);
+            self.regs.set(DmaRegister::DicrFlags, flags | 1 << index);
+        }
+        cycles
+    }
+
+    /// Moves `words` words starting at `addr`, returns the address past them
+    fn transfer_block(&self, channel: DmaChannel, port: &dyn DmaPort, memory: &impl DmaMemory, chcr: u32, mut addr: u32, words: u32) -> u32 {
+        let step = if chcr & CHCR_BACKWARDS != 0 { 4u32.wrapping_neg() } else { 4 };
+        for remaining in (0..words).rev() {
+            let word_addr = addr & 0x00FFFFFC;
+            match channel {
+                // each entry points to the previous one, the last ends the list
+                DmaChannel::Otc => match remaining {
+                    0 => memory.dma_store(word_addr, 0x00FFFFFF),
+                    _ => memory.dma_store(word_addr, addr.wrapping_sub(4) & 0x00FFFFFC),
+                },
+                _ if chcr & CHCR_FROM_RAM != 0 => port.dma_write(memory.dma_load(word_addr)),
+                _ => memory.dma_store(word_addr, port.dma_read()),
+            }
+            addr = addr.wrapping_add(step);
+        }
+        addr
+    }
+
+    /*
+    Linked list nodes start with a header word, its top byte is the number
+    of words following it, the low 24 bits the address of the next node.
+    The list ends at a node whose next address has bit 23 set. */
+    /// Sends a linked list to the port, returns the last node's next address
+    /// and the cycles it took. Gives up after `LIST_NODE_LIMIT` nodes.
+    fn transfer_list(&self, port: &dyn DmaPort, memory: &impl DmaMemory, madr: u32) -> (u32, u64) {
+        let mut addr = madr & 0x00FFFFFC;
+        let mut cycles = 0;
+        for _ in 0..LIST_NODE_LIMIT {
+            let header = memory.dma_load(addr);
+            let words = header >> 24;
+            for i in 1..=words {
+                port.dma_write(memory.dma_load((addr + i * 4) & 0x00FFFFFC));
+            }
+            cycles += words as u64 + LIST_HEADER_CYCLES;
+            if header & LIST_END != 0 {
+                return (header & 0x00FFFFFF, cycles);
+            }
+            addr = header & 0x00FFFFFC;
+        }
+        (addr, cycles)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use crate::core::{bus::{BusDevice, io::irq::Interrupt}, machine::Machine};
+    use super::*;
+
+    /// Counts up on reads, records writes
+    #[derive(Clone, Default)]
+    struct Probe {
+        next: Arc<Mutex<u32>>,
+        written: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl DmaPort for Probe {
+        fn dma_read(&self) -> u32 {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next
+        }
+        fn dma_write(&self, val: u32) {
+            self.written.lock().unwrap().push(val);
+        }
+    }
+
+    #[test]
+    fn test_ordering_table_clear() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x1F8010F0, 0x08000000).unwrap();
+        machine.write::<u32>(0x1F8010E0, 0x0010000C).unwrap();
+        machine.write::<u32>(0x1F8010E4, 4).unwrap();
+        let cycles = machine.cpu.cycles();
+        machine.write::<u32>(0x1F8010E8, 0x11000002).unwrap();
+
+        assert_eq!(machine.read::<u32>(0x1F8010E8).unwrap(), 0x00000002);
+        assert_eq!(machine.read::<u32>(0x8010000C).unwrap(), 0x00100008);
+        assert_eq!(machine.read::<u32>(0x80100008).unwrap(), 0x00100004);
+        assert_eq!(machine.read::<u32>(0x80100004).unwrap(), 0x00100000);
+        assert_eq!(machine.read::<u32>(0x80100000).unwrap(), 0x00FFFFFF);
+        assert_eq!(machine.read::<u32>(0x1F8010E0).unwrap(), 0x0010000C);
+        assert_eq!(machine.cpu.cycles(), cycles + 4);
+    }
+
+    #[test]
+    fn test_block_transfers() {
+        let machine = Machine::new();
+        let probe = Probe::default();
+        machine.io.dma.connect(DmaChannel::Spu, probe.clone());
+        machine.io.dma.connect(DmaChannel::CdRom, probe.clone());
+
+        // disabled channels wait
+        machine.write::<u32>(0x1F8010C0, 0x1000).unwrap();
+        machine.write::<u32>(0x1F8010C4, 0x00020002).unwrap();
+        machine.write::<u32>(0x1F8010C8, 0x01000200).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010C8).unwrap(), 0x01000200);
+
+        machine.write::<u32>(0x1F8010F0, 0x00080000).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010C8).unwrap(), 0x00000200);
+        assert_eq!(machine.read::<u32>(0x1F8010C0).unwrap(), 0x1010);
+        assert_eq!(machine.read::<u32>(0x1F8010C4).unwrap(), 0x0002);
+        assert_eq!((0..4).map(|i| machine.read::<u32>(0x1000 + i * 4).unwrap()).collect::<Vec<_>>(), [1, 2, 3, 4]);
+
+        // manual mode needs the trigger bit, and leaves MADR alone
+        machine.write::<u32>(0x1F8010F0, 0x00008000).unwrap();
+        machine.write::<u32>(0x1F8010B0, 0x1000).unwrap();
+        machine.write::<u32>(0x1F8010B4, 3).unwrap();
+        machine.write::<u32>(0x1F8010B8, 0x01000001).unwrap();
+        assert!(probe.written.lock().unwrap().is_empty());
+        machine.write::<u32>(0x1F8010B8, 0x11000001).unwrap();
+        assert_eq!(*probe.written.lock().unwrap(), [1, 2, 3]);
+        assert_eq!(machine.read::<u32>(0x1F8010B0).unwrap(), 0x1000);
+    }
+
+    #[test]
+    fn test_linked_list() {
+        let machine = Machine::new();
+        let probe = Probe::default();
+        machine.io.dma.connect(DmaChannel::Gpu, probe.clone());
+        let list: [(u32, u32); 5] = [
+            (0x100, 0x02000200), (0x104, 0xAAAA), (0x108, 0xBBBB),
+            (0x200, 0x00000300),
+            (0x300, 0x01FFFFFF),
+        ];
+        for (addr, val) in list {
+            machine.write::<u32>(addr, val).unwrap();
+        }
+        machine.write::<u32>(0x304, 0xCCCC).unwrap();
+
+        machine.write::<u32>(0x1F8010F0, 0x00000800).unwrap();
+        machine.write::<u32>(0x1F8010A0, 0x100).unwrap();
+        machine.write::<u32>(0x1F8010A8, 0x01000401).unwrap();
+        assert_eq!(*probe.written.lock().unwrap(), [0xAAAA, 0xBBBB, 0xCCCC]);
+        assert_eq!(machine.read::<u32>(0x1F8010A0).unwrap(), 0x00FFFFFF);
+        assert_eq!(machine.read::<u32>(0x1F8010A8).unwrap(), 0x00000401);
+    }
+
+    #[test]
+    fn test_cyclic_list() {
+        let machine = Machine::new();
+        let probe = Probe::default();
+        machine.io.dma.connect(DmaChannel::Pio, probe.clone());
+        machine.write::<u32>(0x1F8010F0, 0x00800000).unwrap();
+
+        // a zero header at 0 links to itself
+        machine.write::<u32>(0x1F8010D0, 0).unwrap();
+        machine.write::<u32>(0x1F8010D8, 0x01000401).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010D8).unwrap(), 0x00000401);
+
+        // two nodes pointing at each other, a word each
+        machine.write::<u32>(0x100, 0x01000200).unwrap();
+        machine.write::<u32>(0x200, 0x01000100).unwrap();
+        machine.write::<u32>(0x1F8010D0, 0x100).unwrap();
+        machine.write::<u32>(0x1F8010D8, 0x01000401).unwrap();
+        assert_eq!(probe.written.lock().unwrap().len(), LIST_NODE_LIMIT as usize);
+
+        // sync mode 3 is reserved, the transfer ends without moving anything
+        machine.write::<u32>(0x1F8010D0, 0x100).unwrap();
+        machine.write::<u32>(0x1F8010D8, 0x01000601).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010D8).unwrap(), 0x00000601);
+        assert_eq!(probe.written.lock().unwrap().len(), LIST_NODE_LIMIT as usize);
+    }
+
+    #[test]
+    fn test_priorities() {
+        let dma = Dma::default();
+        dma.write::<u32>(0x1F8010F0, 0x09090000).unwrap();
+        dma.write::<u32>(0x1F8010C8, 0x01000200).unwrap();
+        dma.write::<u32>(0x1F8010D8, 0x01000200).unwrap();
+        dma.write::<u32>(0x1F8010E8, 0x11000000).unwrap();
+        // OTC and SPU share priority 1 but OTC has the higher number, PIO is disabled
+        assert_eq!(dma.next_channel(), Some(DmaChannel::Otc));
+        dma.write::<u32>(0x1F8010F0, 0x0A090000).unwrap();
+        assert_eq!(dma.next_channel(), Some(DmaChannel::Spu));
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x1F801074, 1 << Interrupt::Dma as u32).unwrap();
+        machine.write::<u32>(0x1F8010F4, 0x00C00000).unwrap();
+        machine.write::<u32>(0x1F8010F0, 0x08000000).unwrap();
+        machine.write::<u32>(0x1F8010E4, 1).unwrap();
+        machine.write::<u32>(0x1F8010E8, 0x11000000).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010F4).unwrap(), 0xC0C00000);
+        assert!(machine.io.irq.pending());
+
+        // no new edge while the master flag stays up
+        machine.write::<u16>(0x1F801070, 0).unwrap();
+        machine.write::<u32>(0x1F8010E8, 0x11000000).unwrap();
+        assert!(!machine.io.irq.pending());
+
+        // writing 1 to a flag clears it, the lower half leaves flags alone
+        machine.write::<u16>(0x1F8010F4, 0x0000).unwrap();
+        assert_eq!(machine.peek::<u32>(0x1F8010F4), Some(0xC0C00000));
+        machine.write::<u32>(0x1F8010F4, 0x40C00000).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F8010F4).unwrap(), 0x00C00000);
+        machine.write::<u8>(0x1F8010F5, 0x80).unwrap();
+        assert!(machine.io.irq.pending());
+    }
+
+    #[test]
+    fn test_snapshot_ports() {
+        let machine = Machine::new();
+        let probe = Probe::default();
+        machine.io.dma.connect(DmaChannel::Pio, probe.clone());
+        let snapshot = machine.clone();
+        for machine in [&machine, &snapshot] {
+            machine.write::<u32>(0x1F8010F0, 0x00800000).unwrap();
+            machine.write::<u32>(0x1F8010D4, 0x00010001).unwrap();
+            machine.write::<u32>(0x1F8010D8, 0x01000200).unwrap();
+        }
+        assert_eq!(*probe.next.lock().unwrap(), 2);
+    }
+}
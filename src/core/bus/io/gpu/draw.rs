@@ -0,0 +1,591 @@
+use super::Gpu;
+
+/*
+Ordered dither, added to the 8-bit color channels before they're cut to
+5 bits when GP0(E1h).9 is set:
+  -4  +0  -3  +1
+  +2  -2  +3  -1
+  -3  +1  -4  +0
+  +3  -1  +2  -2 */
+const DITHER: [[i32; 4]; 4] = [[-4, 0, -3, 1], [2, -2, 3, -1], [-3, 1, -4, 0], [3, -1, 2, -2]];
+
+/// Fraction bits of the interpolated colors and texture coordinates
+const FRACTION: u32 = 16;
+
+/// A corner of a primitive, with the drawing offset applied
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Vertex {
+    pub x: i32,
+    pub y: i32,
+    /// 8-bit red, green and blue
+    pub color: [i32; 3],
+    pub u: i32,
+    pub v: i32,
+}
+
+impl Vertex {
+    /// From a GP0 color word and vertex word, coordinates are signed 11-bit
+    pub fn new(color: u32, position: u32) -> Self {
+        Self {
+            x: sign_extend(position, 11),
+            y: sign_extend(position >> 16, 11),
+            color: [color & 0xFF, color >> 8 & 0xFF, color >> 16 & 0xFF].map(|channel| channel as i32),
+            u: 0,
+            v: 0,
+        }
+    }
+
+    /// Sets the texture coordinates from the low half of a GP0 word
+    pub fn with_texcoord(self, word: u32) -> Self {
+        Self { u: (word & 0xFF) as i32, v: (word >> 8 & 0xFF) as i32, ..self }
+    }
+}
+
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+/// Texels a primitive reads, from the draw mode and its CLUT attribute
+#[derive(Copy, Clone, Debug)]
+pub struct Texture {
+    /// GP0(E1h) bits 0-8, page position and color depth
+    page: u32,
+    /// CLUT attribute, its position in 16 pixel steps
+    clut: u32,
+    /// GP0(E2h)
+    window: u32,
+    /// Texels are drawn as they are instead of modulated by the color
+    raw: bool,
+}
+
+/// How the pixels of a primitive are computed
+#[derive(Copy, Clone, Debug)]
+pub struct Style {
+    pub texture: Option<Texture>,
+    /// Semi-transparency mode, `None` for opaque primitives
+    pub blend: Option<u32>,
+    pub dither: bool,
+}
+
+/// Which side of the edge from `a` to `b` the point `p` is on, twice the
+/// area of the triangle they make
+fn cross(a: &Vertex, b: &Vertex, p: (i32, i32)) -> i64 {
+    (b.x - a.x) as i64 * (p.1 - a.y) as i64 - (b.y - a.y) as i64 * (p.0 - a.x) as i64
+}
+
+/// Fraction bits of the steps the GPU sets up for triangles
+const SETUP_FRACTION: u32 = 12;
+
+/// A color or texture coordinate over a triangle, as a plane in fixed point
+/// set up the way the GPU does: the steps come from a truncated reciprocal
+/// of the area and are rounded up, the values start from the leftmost
+/// vertex, rounded to the nearest.
+struct Gradient {
+    origin: i64,
+    dx: i64,
+    dy: i64,
+}
+
+impl Gradient {
+    fn new(vertices: &[Vertex; 3], area: i64, core: &Vertex, attribute: impl Fn(&Vertex) -> i32) -> Self {
+        let [v0, v1, v2] = vertices;
+        let (a0, a1, a2) = (attribute(v0) as i64, attribute(v1) as i64, attribute(v2) as i64);
+        let reciprocal = (1i128 << (SETUP_FRACTION + 32)) / area as i128;
+        let step = |numerator: i64| ((reciprocal * numerator as i128 + 0xFFFFFFFF) >> 32) as i64;
+        let dx = step((a1 - a0) * (v2.y - v0.y) as i64 - (a2 - a0) * (v1.y - v0.y) as i64);
+        let dy = step((a2 - a0) * (v1.x - v0.x) as i64 - (a1 - a0) * (v2.x - v0.x) as i64);
+        let origin = ((attribute(core) as i64) << SETUP_FRACTION) + (1 << (SETUP_FRACTION - 1))
+            - dx * core.x as i64 - dy * core.y as i64;
+        Self { origin, dx, dy }
+    }
+
+    fn at(&self, x: i32, y: i32) -> i32 {
+        ((self.origin + self.dx * x as i64 + self.dy * y as i64) >> SETUP_FRACTION) as i32
+    }
+}
+
+impl Gpu {
+    /// Drawing area of GP0(E3h) and GP0(E4h), as inclusive (left, top,
+    /// right, bottom)
+    fn drawing_area(&self) -> (i32, i32, i32, i32) {
+        let (top_left, bottom_right) = (self.environment[3].get(), self.environment[4].get());
+        let corner = |word: u32| ((word & 0x3FF) as i32, (word >> 10 & 0x1FF) as i32);
+        let ((left, top), (right, bottom)) = (corner(top_left), corner(bottom_right));
+        (left, top, right, bottom)
+    }
+
+    /// GP0(E5h), added to every vertex
+    fn drawing_offset(&self) -> (i32, i32) {
+        let offset = self.environment[5].get();
+        (sign_extend(offset, 11), sign_extend(offset >> 11, 11))
+    }
+
+    /// What a GP0 command's flags make of the current draw mode. Untextured
+    /// primitives only dither when shaded.
+    pub(super) fn style(&self, op: u8, clut: u32, shaded: bool) -> Style {
+        let draw_mode = self.environment[1].get();
+        let textured = op & 0x04 != 0;
+        let texture = textured.then(|| Texture {
+            page: draw_mode & 0x1FF,
+            clut,
+            window: self.environment[2].get(),
+            raw: op & 0x01 != 0,
+        });
+        let modulated = texture.is_some_and(|texture| !texture.raw);
+        Style {
+            texture,
+            blend: (op & 0x02 != 0).then_some(draw_mode >> 5 & 3),
+            dither: draw_mode & 1 << 9 != 0 && (shaded || modulated),
+        }
+    }
+
+    /*
+    GP0(20h..3Fh) polygons, the command's bits:
+      0     Raw texture, not modulated by the color
+      1     Semi-transparent
+      2     Textured
+      3     Four vertices, else three
+      4     Gouraud shaded, a color per vertex, else one for all
+    The words are the first color with the command, then per vertex its
+    color (shaded, but the first), its position and its texture coordinates
+    (textured). The upper half of the first vertex' coordinates is the CLUT,
+    of the second the texture page, which becomes the draw mode. Quads are
+    drawn as the triangles 0-1-2 and 1-2-3. */
+    pub(super) fn polygon(&self, words: &[u32]) {
+        let op = (words[0] >> 24) as u8;
+        let (shaded, textured) = (op & 0x10 != 0, op & 0x04 != 0);
+        let count = if op & 0x08 != 0 { 4 } else { 3 };
+
+        let (offset_x, offset_y) = self.drawing_offset();
+        let mut rest = words[1..].iter().copied();
+        let mut next = || rest.next().unwrap_or_default();
+        let mut vertices = [Vertex::default(); 4];
+        let (mut clut, mut page) = (0, None);
+        for (number, vertex) in vertices.iter_mut().take(count).enumerate() {
+            let color = if shaded && number > 0 { next() } else { words[0] };
+            *vertex = Vertex::new(color, next());
+            vertex.x += offset_x;
+            vertex.y += offset_y;
+            if textured {
+                let texcoord = next();
+                match number {
+                    0 => clut = texcoord >> 16,
+                    1 => page = Some(texcoord >> 16),
+                    _ => (),
+                }
+                *vertex = vertex.with_texcoord(texcoord);
+            }
+        }
+        if let Some(page) = page {
+            let draw_mode = self.environment[1].get();
+            self.environment[1].set(draw_mode & !0x9FF | page & 0x9FF);
+        }
+
+        let style = self.style(op, clut, shaded);
+        self.triangle([vertices[0], vertices[1], vertices[2]], &style);
+        if count == 4 {
+            self.triangle([vertices[1], vertices[2], vertices[3]], &style);
+        }
+    }
+
+    /// Draws the pixels whose centers are inside the triangle, or on its top
+    /// and left edges, within the drawing area
+    fn triangle(&self, mut vertices: [Vertex; 3], style: &Style) {
+        let xs = vertices.map(|vertex| vertex.x);
+        let ys = vertices.map(|vertex| vertex.y);
+        let (min_x, max_x) = (*xs.iter().min().unwrap(), *xs.iter().max().unwrap());
+        let (min_y, max_y) = (*ys.iter().min().unwrap(), *ys.iter().max().unwrap());
+        // the GPU skips triangles too large for its setup
+        if max_x - min_x >= 1024 || max_y - min_y >= 512 {
+            return;
+        }
+
+        let mut area = cross(&vertices[0], &vertices[1], (vertices[2].x, vertices[2].y));
+        if area == 0 {
+            return;
+        }
+        if area < 0 {
+            vertices.swap(1, 2);
+            area = -area;
+        }
+        // on ties the GPU starts from the later vertex
+        let core = *vertices.iter().rev().min_by_key(|vertex| vertex.x).unwrap();
+        let gradient = |attribute: &dyn Fn(&Vertex) -> i32| Gradient::new(&vertices, area, &core, attribute);
+        let [red, green, blue] = [0, 1, 2].map(|channel| gradient(&|vertex| vertex.color[channel]));
+        let (u, v) = (gradient(&|vertex| vertex.u), gradient(&|vertex| vertex.v));
+
+        // with the vertices clockwise on screen, points on top edges (going
+        // right) and left edges (going up) belong to the triangle
+        let edges = [(0, 1), (1, 2), (2, 0)].map(|(a, b)| {
+            let (a, b) = (vertices[a], vertices[b]);
+            let top_left = (a.y == b.y && b.x > a.x) || b.y < a.y;
+            (a, b, if top_left { 0 } else { 1 })
+        });
+
+        let (left, top, right, bottom) = self.drawing_area();
+        for y in min_y.max(top)..=max_y.min(bottom) {
+            for x in min_x.max(left)..=max_x.min(right) {
+                if edges.iter().any(|(a, b, bias)| cross(a, b, (x, y)) < *bias) {
+                    continue;
+                }
+                let color = [red.at(x, y), green.at(x, y), blue.at(x, y)];
+                self.plot(x, y, color, (u.at(x, y), v.at(x, y)), style);
+            }
+        }
+    }
+
+    /*
+    GP0(60h..7Fh) rectangles, the command's bits:
+      0     Raw texture, not modulated by the color
+      1     Semi-transparent
+      2     Textured
+      3-4   Size (0=Variable, 1=1x1, 2=8x8, 3=16x16)
+    The words are the color with the command, the top left vertex, its
+    texture coordinates with the CLUT (textured) and the size (variable).
+    Textures come from the draw mode's page, flipped by GP0(E1h) bits 12 and
+    13, and are never dithered. */
+    pub(super) fn rectangle(&self, words: &[u32]) {
+        let op = (words[0] >> 24) as u8;
+        let textured = op & 0x04 != 0;
+        let (offset_x, offset_y) = self.drawing_offset();
+        let mut origin = Vertex::new(words[0], words[1]);
+        let mut clut = 0;
+        if textured {
+            clut = words[2] >> 16;
+            origin = origin.with_texcoord(words[2]);
+        }
+        let (width, height) = match op >> 3 & 3 {
+            0 => {
+                let size = words[2 + textured as usize];
+                (size & 0x3FF, size >> 16 & 0x1FF)
+            },
+            1 => (1, 1),
+            2 => (8, 8),
+            _ => (16, 16),
+        };
+
+        let style = Style { dither: false, ..self.style(op, clut, false) };
+        let draw_mode = self.environment[1].get();
+        let step_u = if draw_mode & 1 << 12 != 0 { -1 } else { 1 };
+        let step_v = if draw_mode & 1 << 13 != 0 { -1 } else { 1 };
+        for row in 0..height as i32 {
+            for column in 0..width as i32 {
+                let (x, y) = (origin.x + offset_x + column, origin.y + offset_y + row);
+                let texcoord = (origin.u + column * step_u, origin.v + row * step_v);
+                self.plot(x, y, origin.color, texcoord, &style);
+            }
+        }
+    }
+
+    /*
+    GP0(40h..5Fh) lines, the command's bits:
+      1     Semi-transparent
+      3     Polyline, else a single line
+      4     Gouraud shaded, a color per vertex, else one for all
+    The words are the first color with the command, then per vertex its
+    color (shaded, but the first) and position. Polylines go on until a
+    terminator word in place of a vertex, or of the color before it. */
+    pub(super) fn line(&self, words: &[u32]) {
+        let op = (words[0] >> 24) as u8;
+        let shaded = op & 0x10 != 0;
+        let end = if op & 0x08 != 0 { words.len() - 1 } else { words.len() };
+        let (offset_x, offset_y) = self.drawing_offset();
+        let vertices: Vec<Vertex> = match shaded {
+            true => words[..end].chunks_exact(2).map(|pair| Vertex::new(pair[0], pair[1])).collect(),
+            false => words[1..end].iter().map(|&position| Vertex::new(words[0], position)).collect(),
+        };
+        // lines are never textured, whatever bit 2 says
+        let style = self.style(op & !0x04, 0, shaded);
+        for pair in vertices.windows(2) {
+            let [start, end] = [pair[0], pair[1]].map(|vertex| Vertex { x: vertex.x + offset_x, y: vertex.y + offset_y, ..vertex });
+            self.segment(start, end, &style);
+        }
+    }
+
+    /// Draws a line with both its ends, a pixel per step along its longer
+    /// axis
+    fn segment(&self, start: Vertex, end: Vertex, style: &Style) {
+        let (dx, dy) = (end.x - start.x, end.y - start.y);
+        // the GPU skips lines too long for its setup
+        if dx.abs() >= 1024 || dy.abs() >= 512 {
+            return;
+        }
+        let steps = dx.abs().max(dy.abs()).max(1) as i64;
+        // fixed point, from the center of the first pixel
+        let walk = |from: i32, to: i32| {
+            let origin = ((from as i64) << FRACTION) + (1 << (FRACTION - 1));
+            let step = (((to - from) as i64) << FRACTION) / steps;
+            move |index: i64| ((origin + step * index) >> FRACTION) as i32
+        };
+        let (x, y) = (walk(start.x, end.x), walk(start.y, end.y));
+        let color = [0, 1, 2].map(|channel| walk(start.color[channel], end.color[channel]));
+        for index in 0..=dx.abs().max(dy.abs()) as i64 {
+            self.plot(x(index), y(index), color.each_ref().map(|channel| channel(index)), (0, 0), style);
+        }
+    }
+
+    /// Fetches the texel at (u, v) through the texture window and the CLUT
+    fn texel(&self, texture: &Texture, u: i32, v: i32) -> u16 {
+        let window = texture.window;
+        let (mask_x, mask_y) = ((window & 0x1F) * 8, (window >> 5 & 0x1F) * 8);
+        let (offset_x, offset_y) = ((window >> 10 & 0x1F) * 8, (window >> 15 & 0x1F) * 8);
+        let u = (u as u32 & 0xFF) & !mask_x | offset_x & mask_x;
+        let v = (v as u32 & 0xFF) & !mask_y | offset_y & mask_y;
+
+        let (page_x, page_y) = ((texture.page & 0xF) * 64, (texture.page >> 4 & 1) * 256);
+        let (clut_x, clut_y) = ((texture.clut & 0x3F) * 16, texture.clut >> 6 & 0x1FF);
+        match texture.page >> 7 & 3 {
+            0 => {
+                let index = self.vram.get(page_x + u / 4, page_y + v) >> (u % 4 * 4) & 0xF;
+                self.vram.get(clut_x + index as u32, clut_y)
+            },
+            1 => {
+                let index = self.vram.get(page_x + u / 2, page_y + v) >> (u % 2 * 8) & 0xFF;
+                self.vram.get(clut_x + index as u32, clut_y)
+            },
+            _ => self.vram.get(page_x + u, page_y + v),
+        }
+    }
+
+    /// Computes and writes a pixel of a primitive: texturing, dithering,
+    /// semi-transparency and the mask settings, outside of the drawing area
+    /// nothing is drawn
+    pub(super) fn plot(&self, x: i32, y: i32, color: [i32; 3], (u, v): (i32, i32), style: &Style) {
+        let (left, top, right, bottom) = self.drawing_area();
+        if x < left || x > right || y < top || y > bottom {
+            return;
+        }
+        let (x, y) = (x as u32, y as u32);
+        let dither = match style.dither {
+            true => DITHER[y as usize & 3][x as usize & 3],
+            false => 0,
+        };
+        let to_5bit = |channel: i32| ((channel + dither).clamp(0, 255) >> 3) as u16;
+
+        let (mut pixel, semi_transparent) = match &style.texture {
+            Some(texture) => {
+                let texel = self.texel(texture, u, v);
+                // all zeroes is the transparent texel
+                if texel == 0 {
+                    return;
+                }
+                let pixel = match texture.raw {
+                    true => texel & 0x7FFF,
+                    false => {
+                        let modulate = |shift: u32, channel: i32| to_5bit(((texel >> shift & 0x1F) as i32 * channel) >> 4);
+                        modulate(0, color[0]) | modulate(5, color[1]) << 5 | modulate(10, color[2]) << 10
+                    },
+                };
+                // only texels with bit 15 set are semi-transparent
+                (pixel | texel & 0x8000, texel & 0x8000 != 0)
+            },
+            None => (to_5bit(color[0]) | to_5bit(color[1]) << 5 | to_5bit(color[2]) << 10, true),
+        };
+
+        if let Some(mode) = style.blend.filter(|_| semi_transparent) {
+            let back = self.vram.get(x, y);
+            let blend = |shift: u32| {
+                let (b, f) = ((back >> shift & 0x1F) as i32, (pixel >> shift & 0x1F) as i32);
+                let mixed = match mode {
+                    0 => (b + f) / 2,
+                    1 => b + f,
+                    2 => b - f,
+                    _ => b + f / 4,
+                };
+                (mixed.clamp(0, 31) as u16) << shift
+            };
+            pixel = pixel & 0x8000 | blend(0) | blend(5) | blend(10);
+        }
+        self.write_pixel(x, y, pixel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A GPU drawing all over VRAM
+    fn gpu() -> Gpu {
+        let gpu = Gpu::default();
+        gpu.gp0(0xE3000000);
+        gpu.gp0(0xE407FFFF);
+        gpu
+    }
+
+    fn send(gpu: &Gpu, words: &[u32]) {
+        for &word in words {
+            gpu.gp0(word);
+        }
+    }
+
+    #[test]
+    fn test_fill_rules() {
+        let gpu = gpu();
+        // the bottom right edge isn't drawn
+        send(&gpu, &[0x200000FF, 0x00000000, 0x00000004, 0x00040000]);
+        for y in 0..6 {
+            for x in 0..6 {
+                assert_eq!(gpu.vram.get(x, y), if x + y < 4 { 0x001F } else { 0 }, "({}, {})", x, y);
+            }
+        }
+
+        // quads don't draw their shared edge twice
+        let quad = [0x2A080808, 0x0000000A, 0x0000000C, 0x0002000A, 0x0002000C];
+        gpu.gp0(0xE1000020);
+        send(&gpu, &quad);
+        assert_eq!([gpu.vram.get(10, 0), gpu.vram.get(11, 1), gpu.vram.get(12, 0), gpu.vram.get(10, 2)], [0x0421, 0x0421, 0, 0]);
+        send(&gpu, &quad);
+        assert_eq!([gpu.vram.get(11, 0), gpu.vram.get(10, 1)], [0x0842, 0x0842]);
+    }
+
+    #[test]
+    fn test_gouraud_and_dithering() {
+        let gpu = gpu();
+        let triangle = [0x30000000, 0x00000000, 0x000000FF, 0x00000010, 0x00000000, 0x00010000];
+        send(&gpu, &triangle);
+        assert_eq!([0, 8, 9, 15].map(|x| gpu.vram.get(x, 0)), [0, 16, 17, 29]);
+
+        gpu.gp0(0xE1000200);
+        send(&gpu, &triangle);
+        assert_eq!([0, 8, 9, 15].map(|x| gpu.vram.get(x, 0)), [0, 15, 17, 30]);
+        // flat polygons aren't dithered
+        send(&gpu, &[0x20838383, 0x00000000, 0x00000010, 0x00010000]);
+        assert_eq!(gpu.vram.get(0, 0), 0x4210);
+    }
+
+    #[test]
+    fn test_gradient_rounding() {
+        let gpu = gpu();
+        // red goes down by 42.5 a row, a step rounded up to -42.4998. From the
+        // leftmost vertex last in order, (0, 6), row 3 falls just short of 128.
+        send(&gpu, &[0x300000FF, 0x00000000, 0x000000FF, 0x00000004, 0x00000000, 0x00060000]);
+        assert_eq!([1, 3, 5].map(|y| gpu.vram.get(0, y)), [212 >> 3, 127 >> 3, 42 >> 3]);
+    }
+
+    #[test]
+    fn test_textures() {
+        let gpu = gpu();
+        // a 4-bit page at (64, 0), its CLUT at (0, 256)
+        gpu.vram.set(64, 0, 0x0021);
+        gpu.vram.set(66, 0, 0x0002);
+        gpu.vram.set(1, 256, 0x001F);
+        gpu.vram.set(2, 256, 0x83E0);
+        gpu.vram.set(102, 100, 0x7FFF);
+
+        // raw, texel 0 is transparent
+        send(&gpu, &[0x2D000000, 0x00640064, 0x40000000, 0x00640068, 0x00010004, 0x00650064, 0x00000100, 0x00650068, 0x00000104]);
+        assert_eq!([100, 101, 102, 103].map(|x| gpu.vram.get(x, 100)), [0x001F, 0x83E0, 0x7FFF, 0]);
+
+        // modulated, only the texel with bit 15 is blended, here additively
+        for x in 100..104 {
+            gpu.vram.set(x, 110, 0x0421);
+        }
+        send(&gpu, &[0x2E404040, 0x006E0064, 0x40000000, 0x006E0068, 0x00210004, 0x006F0064, 0x00000100, 0x006F0068, 0x00000104]);
+        assert_eq!([100, 101, 102].map(|x| gpu.vram.get(x, 110)), [0x000F, 0x8601, 0x0421]);
+        assert_eq!(gpu.status() & 0x1FF, 0x21);
+
+        // the window maps u 0-7 to 8-15
+        gpu.gp0(0xE2000401);
+        send(&gpu, &[0x2D000000, 0x00640078, 0x40000000, 0x00640079, 0x00010001, 0x00650078, 0x00000100, 0x00650079, 0x00000101]);
+        assert_eq!(gpu.vram.get(120, 100), 0x83E0);
+
+        // 8-bit texels, two per halfword
+        gpu.gp0(0xE2000000);
+        gpu.vram.set(128, 5, 0x0200);
+        send(&gpu, &[0x25000000, 0x00000000, 0x40000501, 0x00000001, 0x00820002, 0x00010000, 0x00000601]);
+        assert_eq!(gpu.vram.get(0, 0), 0x83E0);
+    }
+
+    #[test]
+    fn test_semi_transparency_modes() {
+        let gpu = gpu();
+        for mode in 0..4 {
+            gpu.vram.set(mode, 0, 0x3DEF);
+            gpu.gp0(0xE1000000 | mode << 5);
+            send(&gpu, &[0x2A808080, mode, mode + 1, 0x00010000 | mode, 0x00010001 + mode]);
+        }
+        // 15 in the back, 16 in front
+        assert_eq!([0, 1, 2, 3].map(|x| gpu.vram.get(x, 0) & 0x1F), [15, 31, 0, 19]);
+    }
+
+    #[test]
+    fn test_area_offset_and_mask() {
+        let gpu = Gpu::default();
+        gpu.gp0(0xE3002C0A);
+        gpu.gp0(0xE400300C);
+        gpu.gp0(0xE5002805);
+        gpu.gp0(0xE6000003);
+        gpu.vram.set(11, 11, 0x8000);
+        send(&gpu, &[0x2800FF00, 0x00000000, 0x00000014, 0x00140000, 0x00140014]);
+        for y in 9..14 {
+            for x in 9..14 {
+                let expected = match (x, y) {
+                    (11, 11) => 0x8000,
+                    (10..=12, 11..=12) => 0x83E0,
+                    _ => 0,
+                };
+                assert_eq!(gpu.vram.get(x, y), expected, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rectangles() {
+        let gpu = gpu();
+        // variable size, offset, never dithered
+        gpu.gp0(0xE1000200);
+        gpu.gp0(0xE5000801);
+        send(&gpu, &[0x60838383, 0x00000000, 0x00020003]);
+        assert_eq!([(1, 0), (0, 1), (1, 1), (3, 2), (4, 2)].map(|(x, y)| gpu.vram.get(x, y)), [0, 0, 0x4210, 0x4210, 0]);
+        gpu.gp0(0xE5000000);
+
+        // 1x1, 8x8 and 16x16
+        for (op, x, size) in [(0x68, 16, 1), (0x70, 32, 8), (0x78, 64, 16)] {
+            send(&gpu, &[op << 24 | 0xFF, 0x00100000 | x]);
+            assert_eq!(gpu.vram.get(x + size - 1, 16 + size - 1), 0x001F);
+            assert_eq!(gpu.vram.get(x + size, 16), 0);
+            assert_eq!(gpu.vram.get(x, 16 + size), 0);
+        }
+
+        // a 15-bit texture at (0, 256), flipped horizontally
+        for u in 0..4 {
+            gpu.vram.set(u, 256, 0x8000 | (u as u16 + 1));
+        }
+        gpu.gp0(0xE1001110);
+        send(&gpu, &[0x65000000, 0x00200000, 0x00000003, 0x00010004]);
+        assert_eq!([0, 1, 2, 3].map(|x| gpu.vram.get(x, 32)), [0x8004, 0x8003, 0x8002, 0x8001]);
+
+        // semi-transparent, subtracting
+        gpu.gp0(0xE1000040);
+        gpu.vram.set(0, 48, 0x7FFF);
+        send(&gpu, &[0x6A080808, 0x00300000]);
+        assert_eq!(gpu.vram.get(0, 48), 0x7BDE);
+    }
+
+    #[test]
+    fn test_lines() {
+        let gpu = gpu();
+        // both ends are drawn, whatever the direction
+        send(&gpu, &[0x400000FF, 0x00000004, 0x00000000]);
+        assert_eq!([0, 4, 5].map(|x| gpu.vram.get(x, 0)), [0x001F, 0x001F, 0]);
+        send(&gpu, &[0x400000FF, 0x00020010, 0x00000014]);
+        assert_eq!([(16, 2), (17, 2), (18, 1), (19, 1), (20, 0)].map(|(x, y)| gpu.vram.get(x, y)), [0x001F; 5]);
+        assert_eq!(gpu.vram.get(18, 2) | gpu.vram.get(17, 1), 0);
+
+        // shaded, from black to red
+        send(&gpu, &[0x50000000, 0x00080000, 0x000000FF, 0x00080008]);
+        assert_eq!([0, 4, 8].map(|x| gpu.vram.get(x, 8)), [0, 16, 31]);
+
+        // a polyline ends at the terminator
+        send(&gpu, &[0x48FF0000, 0x00100000, 0x00100002, 0x00120002, 0x55555555, 0x00120000]);
+        assert_eq!([(0, 16), (2, 16), (2, 18)].map(|(x, y)| gpu.vram.get(x, y)), [0x7C00; 3]);
+        assert_eq!(gpu.vram.get(0, 18), 0);
+        assert_eq!(gpu.status() & 1 << 28, 1 << 28);
+
+        // shaded ones end on a color, semi-transparency blends every pixel
+        gpu.gp0(0xE1000020);
+        send(&gpu, &[0x5A080808, 0x00180000, 0x00080808, 0x00180002, 0x50005000]);
+        assert_eq!([0, 1, 2].map(|x| gpu.vram.get(x, 24)), [0x0421; 3]);
+    }
+}
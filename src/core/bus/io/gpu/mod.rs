@@ -0,0 +1,488 @@
+pub mod vram;
+pub mod transfer;
+pub mod draw;
+
+use std::cell::{Cell, RefCell};
+
+use crate::core::bus::mmio::{registers, Mmio, Register, RegisterFile};
+use super::{dma::DmaPort, raster::{Raster, Timing}};
+use vram::Vram;
+use transfer::Transfer;
+
+/*
+GPUSTAT:
+  0-3   Texture page X Base   (N*64)                              ;GP0(E1h).0-3
+  4     Texture page Y Base   (N*256) (ie. 0 or 256)              ;GP0(E1h).4
+  5-6   Semi Transparency     (0=B/2+F/2, 1=B+F, 2=B-F, 3=B+F/4)  ;GP0(E1h).5-6
+  7-8   Texture page colors   (0=4bit, 1=8bit, 2=15bit, 3=Reserved);GP0(E1h).7-8
+  9     Dither 24bit to 15bit (0=Off/strip LSBs, 1=Dither Enabled);GP0(E1h).9
+  10    Drawing to display area (0=Prohibited, 1=Allowed)         ;GP0(E1h).10
+  11    Set Mask-bit when drawing pixels (0=No, 1=Yes/Mask)       ;GP0(E6h).0
+  12    Draw Pixels           (0=Always, 1=Not to Masked areas)   ;GP0(E6h).1
+  13    Interlace Field       (or, always 1 when GP1(08h).5=0)
+  14    Flip screen horizontally (0=Off, 1=On, v1 only)           ;GP1(08h).7
+  15    Texture Disable       (0=Normal, 1=Disable Textures)      ;GP0(E1h).11
+  16    Horizontal Resolution 2     (0=256/320/512/640, 1=368)    ;GP1(08h).6
+  17-18 Horizontal Resolution 1     (0=256, 1=320, 2=512, 3=640)  ;GP1(08h).0-1
+  19    Vertical Resolution         (0=240, 1=480, when Bit22=1)  ;GP1(08h).2
+  20    Video Mode                  (0=NTSC/60Hz, 1=PAL/50Hz)     ;GP1(08h).3
+  21    Display Area Color Depth    (0=15bit, 1=24bit)            ;GP1(08h).4
+  22    Vertical Interlace          (0=Off, 1=On)                 ;GP1(08h).5
+  23    Display Enable              (0=Enabled, 1=Disabled)       ;GP1(03h).0
+  24    Interrupt Request (IRQ1)    (0=Off, 1=IRQ)       ;GP0(1Fh)/GP1(02h)
+  25    DMA / Data Request, meaning depends on GP1(04h) DMA Direction:
+          When GP1(04h)=0 ---> Always zero (0)
+          When GP1(04h)=1 ---> FIFO State  (0=Full, 1=Not Full)
+          When GP1(04h)=2 ---> Same as GPUSTAT.28
+          When GP1(04h)=3 ---> Same as GPUSTAT.27
+  26    Ready to receive Cmd Word   (0=No, 1=Ready)  ;GP0(...) ;via GP0
+  27    Ready to send VRAM to CPU   (0=No, 1=Ready)  ;GP0(C0h) ;via GPUREAD
+  28    Ready to receive DMA Block  (0=No, 1=Ready)  ;GP0(...) ;via GP0
+  29-30 DMA Direction (0=Off, 1=?, 2=CPUtoGP0, 3=GPUREADtoCPU)    ;GP1(04h).0-1
+  31    Drawing even/odd lines in interlace mode (0=Even or Vblank, 1=Odd) */
+const STATUS_DISPLAY_DISABLED: u32 = 1 << 23;
+const STATUS_IRQ: u32 = 1 << 24;
+/// GP0 polylines end on a vertex or color word matching this mask
+const POLYLINE_END: u32 = 0x50005000;
+/// Words a polyline gathers before the part of it already sent is drawn,
+/// which keeps unterminated ones from growing the command buffer forever
+const POLYLINE_LIMIT: usize = 256;
+
+/// Words a GP0 command takes, the command word included, `None` for
+/// polylines which go on until their terminator
+fn command_len(op: u8) -> Option<usize> {
+    let textured = (op >> 2 & 1) as usize;
+    let shaded = (op >> 4 & 1) as usize;
+    Some(match op {
+        0x02 => 3,
+        // a color and a vertex per vertex, the first color is in the command
+        0x20..=0x3F => {
+            let vertices = if op & 0x08 != 0 { 4 } else { 3 };
+            1 + vertices * (1 + textured + shaded) - shaded
+        },
+        0x40..=0x5F if op & 0x08 != 0 => return None,
+        0x40..=0x5F => 3 + shaded,
+        // sizes 1x1, 8x8 and 16x16 don't need a size word
+        0x60..=0x7F => 2 + textured + (op & 0x18 == 0) as usize,
+        0x80..=0x9F => 4,
+        0xA0..=0xDF => 3,
+        _ => 1,
+    })
+}
+
+/// GP0 and GP1, the command ports, with the VRAM and display state behind
+#[derive(Clone)]
+pub struct Gpu {
+    regs: RegisterFile,
+    pub vram: Vram,
+    /// Display timing, follows GP1(06h) to GP1(08h)
+    pub raster: Raster,
+    /// Words of the GP0 command being gathered
+    command: RefCell<Vec<u32>>,
+    /// GP0(A0h) rectangle still taking image data from GP0
+    upload: Cell<Option<Transfer>>,
+    /// GP0(C0h) rectangle still giving image data to GPUREAD
+    download: Cell<Option<Transfer>>,
+    /// Value GPUREAD returns
+    read_latch: Cell<u32>,
+    /// GPUSTAT bits set by GP1 commands, the others are computed
+    status: Cell<u32>,
+    /// Parameters of GP0(E0h) to GP0(E6h), 24 bits each
+    environment: [Cell<u32>; 7],
+    /// GP1(05h), first pixel of the display area in VRAM
+    display_start: Cell<u32>,
+    /// GP1(06h) and GP1(07h)
+    horizontal_range: Cell<u32>,
+    vertical_range: Cell<u32>,
+    /// GP0(1Fh) raised the IRQ since the last `take_irq`
+    irq_edge: Cell<bool>,
+}
+
+registers! {
+    /*
+    1F801810h Write GP0     Send GP0 Commands/Packets (Rendering and VRAM Access)
+    1F801814h Write GP1     Send GP1 Commands (Display Control) (and DMA Control)
+    1F801810h Read  GPUREAD Receive responses to GP0(C0h) and GP1(10h) commands
+    1F801814h Read  GPUSTAT Receive GPU Status Register */
+    pub enum GpuRegister for Gpu {
+        Gp0 = 0x1F801810: u32, on_read = Gpu::gpuread, on_peek = Gpu::peek_gpuread, on_write = Gpu::gp0_written;
+        Gp1 = 0x1F801814: u32, on_read = Gpu::status, on_peek = Gpu::status, on_write = Gpu::gp1_written;
+    }
+}
+
+impl Default for Gpu {
+    fn default() -> Self {
+        let gpu = Self {
+            regs: RegisterFile::new(GpuRegister::MAP),
+            vram: Vram::default(),
+            raster: Raster::default(),
+            command: RefCell::new(vec![]),
+            upload: Cell::new(None),
+            download: Cell::new(None),
+            read_latch: Cell::new(0),
+            status: Cell::new(0),
+            environment: Default::default(),
+            display_start: Cell::new(0),
+            horizontal_range: Cell::new(0),
+            vertical_range: Cell::new(0),
+            irq_edge: Cell::new(false),
+        };
+        gpu.reset();
+        gpu
+    }
+}
+
+impl Mmio for Gpu {
+    fn registers(&self) -> &'static [Register<Self>] {
+        GpuRegister::MAP
+    }
+    fn storage(&self) -> &RegisterFile {
+        &self.regs
+    }
+}
+
+/// GPU DMA, channel 2: command lists and image data to GP0, VRAM from GPUREAD
+impl DmaPort for Gpu {
+    fn dma_read(&self) -> u32 {
+        self.gpuread()
+    }
+    fn dma_write(&self, val: u32) {
+        self.gp0(val);
+    }
+}
+
+impl Gpu {
+    pub fn status(&self) -> u32 {
+        let draw_mode = self.environment[1].get();
+        let mask = self.environment[6].get();
+        let mut status = self.status.get() | draw_mode & 0x7FF | (draw_mode >> 11 & 1) << 15 | (mask & 3) << 11;
+
+        let interlaced = status & 1 << 22 != 0;
+        let odd_field = self.raster.frames() & 1 != 0;
+        if !interlaced || odd_field {
+            status |= 1 << 13;
+        }
+        // drawing happens at once, the only wait is for a command's words
+        let idle = self.command.borrow().is_empty();
+        if idle && self.upload.get().is_none() {
+            status |= 1 << 26;
+        }
+        if self.download.get().is_some() {
+            status |= 1 << 27;
+        }
+        if idle {
+            status |= 1 << 28;
+        }
+        status |= match status >> 29 & 3 {
+            0 => 0,
+            1 => 1,
+            2 => status >> 28 & 1,
+            _ => status >> 27 & 1,
+        } << 25;
+        let odd_line = match interlaced && status & 1 << 19 != 0 {
+            true => odd_field,
+            false => self.raster.line() & 1 != 0,
+        };
+        if odd_line && !self.raster.vblank() {
+            status |= 1 << 31;
+        }
+        status
+    }
+
+    fn gpuread(&self) -> u32 {
+        match self.download.get() {
+            Some(transfer) => self.download(transfer),
+            None => self.read_latch.get(),
+        }
+    }
+
+    /// What GPUREAD would return, without moving a VRAM to CPU transfer along
+    fn peek_gpuread(&self) -> u32 {
+        match self.download.get() {
+            Some(transfer) => self.peek_download(transfer),
+            None => self.read_latch.get(),
+        }
+    }
+
+    /// Whether GP0(1Fh) requested IRQ 1 since the last call
+    pub fn take_irq(&self) -> bool {
+        self.irq_edge.replace(false)
+    }
+
+    /// What GP1(05h) to GP1(08h) configured, as (display start, horizontal
+    /// range, vertical range)
+    pub fn display_area(&self) -> (u32, u32, u32) {
+        (self.display_start.get(), self.horizontal_range.get(), self.vertical_range.get())
+    }
+
+    fn gp0_written(&self, _old: u32, word: u32) {
+        self.gp0(word);
+    }
+
+    /// Takes a GP0 word, running the command once it has all of them
+    pub fn gp0(&self, word: u32) {
+        if let Some(transfer) = self.upload.get() {
+            return self.upload(transfer, word);
+        }
+        let mut command = self.command.borrow_mut();
+        command.push(word);
+        let op = (command[0] >> 24) as u8;
+        let complete = match command_len(op) {
+            Some(len) => command.len() == len,
+            // the terminator can only take the place of a vertex, or of the
+            // color before it on shaded lines, past the second vertex
+            None if op & 0x10 != 0 => command.len() >= 5 && command.len() % 2 == 1 && word & 0xF000F000 == POLYLINE_END,
+            None => command.len() >= 4 && word & 0xF000F000 == POLYLINE_END,
+        };
+        let shaded = op & 0x10 != 0;
+        if complete {
+            let words = std::mem::take(&mut *command);
+            drop(command);
+            self.execute(&words);
+        } else if command_len(op).is_none() && command.len() >= POLYLINE_LIMIT && (!shaded || command.len().is_multiple_of(2)) {
+            // long polylines are drawn in pieces, going on from the last vertex
+            let color = if shaded { command[command.len() - 2] } else { command[0] };
+            let rest = vec![(op as u32) << 24 | color & 0xFFFFFF, word];
+            let mut words = std::mem::replace(&mut *command, rest);
+            drop(command);
+            words.push(POLYLINE_END);
+            self.execute(&words);
+        }
+    }
+
+    /*
+    GP0(02h)       Fill Rectangle in VRAM
+    GP0(1Fh)       Interrupt Request (IRQ1)
+    GP0(20h..3Fh)  Render Polygons
+    GP0(40h..5Fh)  Render Lines
+    GP0(60h..7Fh)  Render Rectangles
+    GP0(80h..9Fh)  Copy Rectangle (VRAM to VRAM)
+    GP0(A0h..BFh)  Copy Rectangle (CPU to VRAM)
+    GP0(C0h..DFh)  Copy Rectangle (VRAM to CPU)
+    GP0(E1h)       Draw Mode setting (aka "Texpage")
+    GP0(E2h)       Texture Window setting
+    GP0(E3h)       Set Drawing Area top left (X1,Y1)
+    GP0(E4h)       Set Drawing Area bottom right (X2,Y2)
+    GP0(E5h)       Set Drawing Offset (X,Y)
+    GP0(E6h)       Mask Bit Setting
+    Everything else is a NOP. */
+    fn execute(&self, words: &[u32]) {
+        let op = (words[0] >> 24) as u8;
+        match op {
+            0x1F => {
+                self.status.set(self.status.get() | STATUS_IRQ);
+                self.irq_edge.set(true);
+            },
+            0x02 => self.fill(words[0], words[1], words[2]),
+            0x20..=0x3F => self.polygon(words),
+            0x40..=0x5F => self.line(words),
+            0x60..=0x7F => self.rectangle(words),
+            0x80..=0x9F => self.copy(words[1], words[2], words[3]),
+            0xA0..=0xBF => self.upload.set(Some(Transfer::new(words[1], words[2]))),
+            0xC0..=0xDF => self.download.set(Some(Transfer::new(words[1], words[2]))),
+            0xE1..=0xE6 => self.environment[op as usize - 0xE0].set(words[0] & 0xFFFFFF),
+            _ => (),
+        }
+    }
+
+    fn gp1_written(&self, _old: u32, word: u32) {
+        self.gp1(word);
+    }
+
+    /*
+    GP1(00h)       Reset GPU
+    GP1(01h)       Reset Command Buffer
+    GP1(02h)       Acknowledge GPU Interrupt (IRQ1)
+    GP1(03h)       Display Enable
+    GP1(04h)       DMA Direction / Data Request
+    GP1(05h)       Start of Display area (in VRAM)
+    GP1(06h)       Horizontal Display range (on Screen)
+    GP1(07h)       Vertical Display range (on Screen)
+    GP1(08h)       Display mode
+    GP1(10h..1Fh)  Get GPU Info
+    GP1(40h..FFh)  Mirrors of GP1(00h..3Fh) */
+    pub fn gp1(&self, word: u32) {
+        let param = word & 0xFFFFFF;
+        let status = self.status.get();
+        match word >> 24 & 0x3F {
+            0x00 => self.reset(),
+            0x01 => {
+                self.command.borrow_mut().clear();
+                self.upload.set(None);
+            },
+            0x02 => self.status.set(status & !STATUS_IRQ),
+            0x03 => self.status.set(status & !STATUS_DISPLAY_DISABLED | (param & 1) << 23),
+            0x04 => self.status.set(status & !(3 << 29) | (param & 3) << 29),
+            0x05 => self.display_start.set(param & 0x7FFFF),
+            0x06 => {
+                self.horizontal_range.set(param);
+                self.update_timing();
+            },
+            0x07 => {
+                self.vertical_range.set(param & 0xFFFFF);
+                self.update_timing();
+            },
+            0x08 => {
+                let mode = (param & 0x3F) << 17 | (param >> 6 & 1) << 16 | (param >> 7 & 1) << 14;
+                self.status.set(status & !0x7F4000 | mode);
+                self.update_timing();
+            },
+            0x10..=0x1F => match param & 0xF {
+                2 => self.read_latch.set(self.environment[2].get() & 0xFFFFF),
+                3 => self.read_latch.set(self.environment[3].get() & 0x7FFFF),
+                4 => self.read_latch.set(self.environment[4].get() & 0x7FFFF),
+                5 => self.read_latch.set(self.environment[5].get() & 0x3FFFFF),
+                // GPU version, the 160-pin chip
+                7 => self.read_latch.set(2),
+                8 => self.read_latch.set(0),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    /// GP1(00h), everything but VRAM goes back to its power-on state
+    fn reset(&self) {
+        self.command.borrow_mut().clear();
+        self.upload.set(None);
+        self.download.set(None);
+        self.status.set(STATUS_DISPLAY_DISABLED);
+        for param in &self.environment {
+            param.set(0);
+        }
+        self.display_start.set(0);
+        self.horizontal_range.set(0x200 | 0xC00 << 12);
+        self.vertical_range.set(0x010 | 0x100 << 10);
+        self.update_timing();
+    }
+
+    /// Hands the display mode and ranges over to the raster
+    fn update_timing(&self) {
+        let status = self.status.get();
+        let dot_divider = match status & 1 << 16 != 0 {
+            true => 7,
+            false => [10, 8, 5, 4][(status >> 17 & 3) as usize],
+        };
+        let (horizontal, vertical) = (self.horizontal_range.get(), self.vertical_range.get());
+        self.raster.set_timing(Timing {
+            pal: status & 1 << 20 != 0,
+            dot_divider,
+            horizontal: (horizontal & 0xFFF, horizontal >> 12 & 0xFFF),
+            vertical: (vertical & 0x3FF, vertical >> 10 & 0x3FF),
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::{BusDevice, io::irq::Interrupt}, machine::Machine};
+    use super::*;
+
+    const READY: u32 = 1 << 26 | 1 << 28;
+
+    #[test]
+    fn test_command_lengths() {
+        let gpu = Gpu::default();
+        assert_eq!(gpu.status(), 0x14802000);
+
+        // shaded textured quad, 12 words
+        for word in 0..11 {
+            gpu.gp0(0x3C000000 | word);
+            assert_eq!(gpu.status() & READY, 0);
+        }
+        gpu.gp0(0);
+        assert_eq!(gpu.status() & READY, READY);
+
+        // a shaded polyline ends on a color word, not on a vertex
+        for word in [0x58000000, 0, 0, 0x55555555, 0, 0x55555555] {
+            gpu.gp0(word);
+            assert_eq!(gpu.status() & READY, 0);
+        }
+        gpu.gp0(0x50005000);
+        assert_eq!(gpu.status() & READY, READY);
+
+        // image data isn't taken for commands, 3x3 pixels are 5 words
+        for word in [0xA0000000, 0, 0x00030003, 0xE1000000, 0xE1000000, 0xE1000000, 0xE1000000] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.status() & READY, 1 << 28);
+        gpu.gp0(0xE1000000);
+        gpu.gp0(0xE10001FF);
+        assert_eq!(gpu.status() & 0x87FF, 0x01FF);
+    }
+
+    #[test]
+    fn test_long_polyline() {
+        let gpu = Gpu::default();
+        gpu.gp0(0xE3000000);
+        gpu.gp0(0xE407FFFF);
+        gpu.gp0(0x580000FF);
+        for x in 0..300 {
+            gpu.gp0(0x00280000 | x);
+            gpu.gp0(0x000000FF);
+        }
+        assert!(gpu.command.borrow().len() <= POLYLINE_LIMIT);
+        assert_eq!(gpu.vram.get(250, 40), 0x001F);
+        assert_eq!(gpu.status() & 1 << 28, 0);
+
+        gpu.gp0(0x00280000 | 300);
+        gpu.gp0(0x55555555);
+        assert_eq!(gpu.vram.get(300, 40), 0x001F);
+        assert_eq!(gpu.status() & 1 << 28, 1 << 28);
+    }
+
+    #[test]
+    fn test_display_control() {
+        let gpu = Gpu::default();
+        gpu.gp0(0xE1000A3F);
+        gpu.gp0(0xE6000003);
+        gpu.gp1(0x03000000);
+        gpu.gp1(0x04000002);
+        gpu.gp1(0x080000FF);
+        assert_eq!(gpu.status() & !(1 << 31), 0x567FDA3F);
+        gpu.gp1(0x04000001);
+        assert_eq!(gpu.status() >> 25 & 1, 1);
+
+        gpu.gp0(0xE3000401);
+        gpu.gp1(0x10000003);
+        assert_eq!(gpu.read::<u32>(0x1F801810).unwrap(), 0x401);
+        gpu.gp1(0x10000007);
+        assert_eq!(gpu.read::<u32>(0x1F801810).unwrap(), 2);
+        // mirrors of GP1(00h) reset
+        gpu.gp1(0x40000000);
+        assert_eq!(gpu.status(), 0x14802000);
+        assert_eq!(gpu.raster.frames(), 0);
+    }
+
+    #[test]
+    fn test_dma_command_list() {
+        let machine = Machine::new();
+        let list: [(u32, u32); 5] = [
+            (0x100, 0x02000200), (0x104, 0xE6000003), (0x108, 0xE1000005),
+            (0x200, 0x00000300),
+            (0x300, 0x01FFFFFF),
+        ];
+        for (addr, val) in list {
+            machine.write::<u32>(addr, val).unwrap();
+        }
+        machine.write::<u32>(0x304, 0xE1000A05).unwrap();
+
+        // channel 2 goes to GP0 when nothing else is connected to it
+        machine.write::<u32>(0x1F8010F0, 0x00000800).unwrap();
+        machine.write::<u32>(0x1F8010A0, 0x100).unwrap();
+        machine.write::<u32>(0x1F8010A8, 0x01000401).unwrap();
+        assert_eq!(machine.io.gpu.status() & 0x9FFF, 0x9A05);
+        assert_eq!(machine.read::<u32>(0x1F8010A0).unwrap(), 0x00FFFFFF);
+    }
+
+    #[test]
+    fn test_interrupt_request() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x1F801074, 1 << Interrupt::Gpu as u32).unwrap();
+        machine.write::<u32>(0x1F801810, 0x1F000000).unwrap();
+        assert!(machine.io.irq.pending());
+        assert_ne!(machine.read::<u32>(0x1F801814).unwrap() & 1 << 24, 0);
+        machine.write::<u32>(0x1F801814, 0x02000000).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F801814).unwrap() & 1 << 24, 0);
+    }
+}
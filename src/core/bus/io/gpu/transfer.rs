@@ -0,0 +1,245 @@
+use super::Gpu;
+
+/// A rectangle of VRAM moved to or from the CPU, a pixel at a time
+#[derive(Copy, Clone, Debug)]
+pub struct Transfer {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    /// Pixels moved so far
+    done: u32,
+}
+
+impl Transfer {
+    /// From the position and size words of GP0(A0h) or GP0(C0h). Sizes of 0
+    /// mean the largest ones, 1024 wide and 512 high.
+    pub fn new(position: u32, size: u32) -> Self {
+        Self {
+            x: position & 0x3FF,
+            y: position >> 16 & 0x1FF,
+            width: ((size & 0xFFFF).wrapping_sub(1) & 0x3FF) + 1,
+            height: ((size >> 16).wrapping_sub(1) & 0x1FF) + 1,
+            done: 0,
+        }
+    }
+
+    /// Coordinates of the next pixel, `None` past the last one. VRAM wraps
+    /// them around its edges.
+    pub fn next(&mut self) -> Option<(u32, u32)> {
+        if self.finished() {
+            return None;
+        }
+        let (row, column) = (self.done / self.width, self.done % self.width);
+        self.done += 1;
+        Some((self.x + column, self.y + row))
+    }
+
+    pub fn finished(&self) -> bool {
+        self.done == self.width * self.height
+    }
+}
+
+/// Converts a 24-bit GP0 color to a 15-bit VRAM pixel, by dropping LSBs
+pub fn pixel_from_rgb(color: u32) -> u16 {
+    let (r, g, b) = (color >> 3 & 0x1F, color >> 11 & 0x1F, color >> 19 & 0x1F);
+    (r | g << 5 | b << 10) as u16
+}
+
+impl Gpu {
+    /// Writes a pixel the way VRAM transfers and drawing do, following
+    /// GP0(E6h): pixels with their mask bit set are kept when checking, and
+    /// the mask bit can be forced on the written ones.
+    pub(super) fn write_pixel(&self, x: u32, y: u32, pixel: u16) {
+        let mask = self.environment[6].get();
+        if mask & 2 != 0 && self.vram.get(x, y) & 0x8000 != 0 {
+            return;
+        }
+        self.vram.set(x, y, pixel | ((mask & 1) << 15) as u16);
+    }
+
+    /// GP0(02h), the rectangle starts at a multiple of 16 pixels and spans a
+    /// multiple of 16 of them. Neither the mask nor the drawing area apply.
+    pub(super) fn fill(&self, color: u32, position: u32, size: u32) {
+        let (x, y) = (position & 0x3F0, position >> 16 & 0x1FF);
+        let (width, height) = (((size & 0x3FF) + 0xF) & !0xF, size >> 16 & 0x1FF);
+        let pixel = pixel_from_rgb(color);
+        for row in 0..height {
+            for column in 0..width {
+                self.vram.set(x + column, y + row, pixel);
+            }
+        }
+    }
+
+    /// GP0(80h), row by row, so overlapping copies can repeat pixels. Rows
+    /// go right to left when the destination is right of the source, as
+    /// DuckStation does after checking it on hardware.
+    pub(super) fn copy(&self, source: u32, destination: u32, size: u32) {
+        let (source_x, source_y) = (source & 0x3FF, source >> 16 & 0x1FF);
+        let (destination_x, destination_y) = (destination & 0x3FF, destination >> 16 & 0x1FF);
+        let Transfer { width, height, .. } = Transfer::new(destination, size);
+        let backwards = source_x < destination_x;
+        for row in 0..height {
+            for step in 0..width {
+                let column = if backwards { width - 1 - step } else { step };
+                let pixel = self.vram.get(source_x + column, source_y + row);
+                self.write_pixel(destination_x + column, destination_y + row, pixel);
+            }
+        }
+    }
+
+    /// Takes a word of GP0(A0h) image data, two pixels with the first in the
+    /// low half. The second one of the last word may be left over.
+    pub(super) fn upload(&self, mut transfer: Transfer, word: u32) {
+        for pixel in [word as u16, (word >> 16) as u16] {
+            if let Some((x, y)) = transfer.next() {
+                self.write_pixel(x, y, pixel);
+            }
+        }
+        self.upload.set(Some(transfer).filter(|transfer| !transfer.finished()));
+    }
+
+    /// Gives a word of GP0(C0h) image data, GPUREAD keeps it once the
+    /// rectangle is done
+    pub(super) fn download(&self, mut transfer: Transfer) -> u32 {
+        let word = self.image_word(&mut transfer);
+        self.download.set(Some(transfer).filter(|transfer| !transfer.finished()));
+        self.read_latch.set(word);
+        word
+    }
+
+    /// The word `download` would give, leaving the transfer where it is
+    pub(super) fn peek_download(&self, mut transfer: Transfer) -> u32 {
+        self.image_word(&mut transfer)
+    }
+
+    /// Reads the next two pixels of `transfer`, the first in the low half
+    fn image_word(&self, transfer: &mut Transfer) -> u32 {
+        let mut word = 0;
+        for shift in [0, 16] {
+            if let Some((x, y)) = transfer.next() {
+                word |= (self.vram.get(x, y) as u32) << shift;
+            }
+        }
+        word
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::{BusDevice, mmio::Mmio}, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_fill() {
+        let gpu = Gpu::default();
+        gpu.gp0(0xE6000003);
+        // x is rounded down and the width up to 16 pixels, both wrap around
+        for word in [0x02FF8040, 0x01FF03F5, 0x00020011] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.vram.get(0x3F0, 0x1FF), 0x7E08);
+        assert_eq!(gpu.vram.get(0x00F, 0x000), 0x7E08);
+        assert_eq!(gpu.vram.get(0x010, 0x000), 0);
+        assert_eq!(gpu.vram.get(0x3EF, 0x1FF), 0);
+        assert_eq!(gpu.vram.get(0x000, 0x001), 0);
+    }
+
+    #[test]
+    fn test_cpu_transfers() {
+        let gpu = Gpu::default();
+        // 3x2 pixels over the bottom right corner, with the mask bit forced
+        gpu.gp0(0xE6000001);
+        for word in [0xA0000000, 0x01FF03FE, 0x00020003, 0x00020001, 0x00040003] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.status() & 1 << 26, 0);
+        gpu.gp0(0x00060005);
+        assert_eq!(gpu.vram.get(0x3FE, 0x1FF), 0x8001);
+        assert_eq!(gpu.vram.get(0x000, 0x1FF), 0x8003);
+        assert_eq!(gpu.vram.get(0x3FF, 0x000), 0x8005);
+        assert_eq!(gpu.vram.get(0x001, 0x000), 0);
+
+        // masked pixels are kept when checking
+        gpu.gp0(0xE6000002);
+        for word in [0xA0000000, 0x01FF03FE, 0x00010002, 0x12340000] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.status() & 1 << 26, 1 << 26);
+        assert_eq!(gpu.vram.get(0x3FE, 0x1FF), 0x8001);
+
+        for word in [0xC0000000, 0x01FF03FE, 0x00020003] {
+            gpu.gp0(word);
+        }
+        // peeking doesn't move the transfer along
+        let words: Vec<u32> = (0..3).map(|_| {
+            assert_ne!(gpu.status() & 1 << 27, 0);
+            let peeked = gpu.peek::<u32>(0x1F801810);
+            assert_eq!(peeked, gpu.peek::<u32>(0x1F801810));
+            let word = gpu.read::<u32>(0x1F801810).unwrap();
+            assert_eq!(peeked, Some(word));
+            word
+        }).collect();
+        assert_eq!(words, [0x80028001, 0x80048003, 0x80068005]);
+        assert_eq!(gpu.status() & 1 << 27, 0);
+        assert_eq!(gpu.read::<u32>(0x1F801810).unwrap(), 0x80068005);
+    }
+
+    #[test]
+    fn test_vram_copy() {
+        let gpu = Gpu::default();
+        for x in 0..4 {
+            gpu.vram.set(x, 0, x as u16 + 1);
+        }
+        gpu.vram.set(12, 10, 0x8000);
+        gpu.gp0(0xE6000002);
+        // a 4x1 source copied onto itself shifted right by one goes right to
+        // left, so it moves over whole
+        for word in [0x80000000, 0x00000000, 0x00000001, 0x00010004] {
+            gpu.gp0(word);
+        }
+        assert_eq!((0..5).map(|x| gpu.vram.get(x, 0)).collect::<Vec<_>>(), [1, 1, 2, 3, 4]);
+
+        // shifted left it goes left to right
+        for word in [0x80000000, 0x00000001, 0x00000000, 0x00010004] {
+            gpu.gp0(word);
+        }
+        assert_eq!((0..5).map(|x| gpu.vram.get(x, 0)).collect::<Vec<_>>(), [1, 2, 3, 4, 4]);
+
+        for word in [0x80000000, 0x00000000, 0x000A000A, 0x00010004] {
+            gpu.gp0(word);
+        }
+        assert_eq!((10..14).map(|x| gpu.vram.get(x, 10)).collect::<Vec<_>>(), [1, 2, 0x8000, 4]);
+    }
+
+    #[test]
+    fn test_dma_transfers() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x1F8010F0, 0x00000800).unwrap();
+        machine.write::<u32>(0x1000, 0x00020001).unwrap();
+        machine.write::<u32>(0x1004, 0x00040003).unwrap();
+
+        // the command through the port, the image data by blocks
+        for word in [0xA0000000, 0x00100010, 0x00020002] {
+            machine.write::<u32>(0x1F801810, word).unwrap();
+        }
+        machine.write::<u32>(0x1F8010A0, 0x1000).unwrap();
+        machine.write::<u32>(0x1F8010A4, 0x00020001).unwrap();
+        machine.write::<u32>(0x1F8010A8, 0x01000201).unwrap();
+        let gpu = &machine.io.gpu;
+        assert_eq!([gpu.vram.get(16, 16), gpu.vram.get(17, 16), gpu.vram.get(16, 17), gpu.vram.get(17, 17)], [1, 2, 3, 4]);
+
+        // GPUREAD back to RAM, the DMA request follows bit 27
+        machine.write::<u32>(0x1F801814, 0x04000003).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F801814).unwrap() & 1 << 25, 0);
+        for word in [0xC0000000, 0x00100010, 0x00020002] {
+            machine.write::<u32>(0x1F801810, word).unwrap();
+        }
+        assert_ne!(machine.read::<u32>(0x1F801814).unwrap() & 1 << 25, 0);
+        machine.write::<u32>(0x1F8010A0, 0x2000).unwrap();
+        machine.write::<u32>(0x1F8010A4, 0x00020001).unwrap();
+        machine.write::<u32>(0x1F8010A8, 0x01000200).unwrap();
+        assert_eq!([machine.read::<u32>(0x2000).unwrap(), machine.read::<u32>(0x2004).unwrap()], [0x00020001, 0x00040003]);
+        assert_eq!(machine.read::<u32>(0x1F801814).unwrap() & 1 << 25, 0);
+    }
+}
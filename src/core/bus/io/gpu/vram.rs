@@ -0,0 +1,41 @@
+use std::cell::Cell;
+
+pub const VRAM_WIDTH: u32 = 1024;
+pub const VRAM_HEIGHT: u32 = 512;
+
+/// The GPU's 1MiB frame buffer, 1024x512 halfwords. Coordinates wrap
+/// around its edges.
+#[derive(Clone)]
+pub struct Vram {
+    pixels: Box<[Cell<u16>]>,
+}
+
+impl Default for Vram {
+    fn default() -> Self {
+        Self { pixels: (0..VRAM_WIDTH * VRAM_HEIGHT).map(|_| Cell::new(0)).collect() }
+    }
+}
+
+impl Vram {
+    fn index(x: u32, y: u32) -> usize {
+        ((y % VRAM_HEIGHT) * VRAM_WIDTH + x % VRAM_WIDTH) as usize
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> u16 {
+        self.pixels[Self::index(x, y)].get()
+    }
+
+    pub fn set(&self, x: u32, y: u32, pixel: u16) {
+        self.pixels[Self::index(x, y)].set(pixel);
+    }
+
+    /// The whole frame buffer as a binary PPM, 15-bit colors scaled to 24
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut image = format!("P6\n{} {}\n255\n", VRAM_WIDTH, VRAM_HEIGHT).into_bytes();
+        for pixel in self.pixels.iter().map(Cell::get) {
+            let expand = |shift: u16| ((pixel >> shift & 0x1F) << 3 | (pixel >> shift & 0x1F) >> 2) as u8;
+            image.extend([expand(0), expand(5), expand(10)]);
+        }
+        image
+    }
+}
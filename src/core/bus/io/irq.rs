@@ -0,0 +1,152 @@
+use crate::core::bus::mmio::{registers, Mmio, Register, RegisterFile};
+
+/*
+0     IRQ0 VBLANK (PAL=50Hz, NTSC=60Hz)
+1     IRQ1 GPU   Can be requested via GP0(1Fh) command (rarely used)
+2     IRQ2 CDROM
+3     IRQ3 DMA
+4     IRQ4 TMR0  Timer 0 aka Root Counter 0 (Sysclk or Dotclk)
+5     IRQ5 TMR1  Timer 1 aka Root Counter 1 (Sysclk or H-blank)
+6     IRQ6 TMR2  Timer 2 aka Root Counter 2 (Sysclk or Sysclk/8)
+7     IRQ7 Controller and Memory Card - Byte Received Interrupt
+8     IRQ8 SIO
+9     IRQ9 SPU
+10    IRQ10 Controller - Lightpen Interrupt. Also shared by PIO and DTL cards.
+11-15 Not used (always zero)
+16-31 Garbage */
+/// Interrupt lines, by their bit in I_STAT and I_MASK
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Gpu = 1,
+    CdRom = 2,
+    Dma = 3,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+    Controller = 7,
+    Sio = 8,
+    Spu = 9,
+    Lightpen = 10,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 11] = [
+        Interrupt::VBlank, Interrupt::Gpu, Interrupt::CdRom, Interrupt::Dma, Interrupt::Timer0,
+        Interrupt::Timer1, Interrupt::Timer2, Interrupt::Controller, Interrupt::Sio, Interrupt::Spu,
+        Interrupt::Lightpen,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interrupt::VBlank => "vblank",
+            Interrupt::Gpu => "gpu",
+            Interrupt::CdRom => "cdrom",
+            Interrupt::Dma => "dma",
+            Interrupt::Timer0 => "timer0",
+            Interrupt::Timer1 => "timer1",
+            Interrupt::Timer2 => "timer2",
+            Interrupt::Controller => "controller",
+            Interrupt::Sio => "sio",
+            Interrupt::Spu => "spu",
+            Interrupt::Lightpen => "lightpen",
+        }
+    }
+}
+
+/// I_STAT and I_MASK, what drives the CPU's hardware interrupt line 2
+#[derive(Clone)]
+pub struct InterruptController {
+    regs: RegisterFile,
+}
+
+registers! {
+    /*
+    1F801070h 2    I_STAT - Interrupt status register
+    1F801074h 2    I_MASK - Interrupt mask register
+    I_STAT bits are set by the devices and acknowledged by writing 0 to them,
+    writing 1 leaves them unchanged. */
+    pub enum InterruptRegister for InterruptController {
+        Status = 0x1F801070: u32, write = 0x7FF, on_write = InterruptController::acknowledged;
+        Mask = 0x1F801074: u32, write = 0x7FF;
+    }
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self { regs: RegisterFile::new(InterruptRegister::MAP) }
+    }
+}
+
+impl Mmio for InterruptController {
+    fn registers(&self) -> &'static [Register<Self>] {
+        InterruptRegister::MAP
+    }
+    fn storage(&self) -> &RegisterFile {
+        &self.regs
+    }
+}
+
+impl InterruptController {
+    fn acknowledged(&self, old: u32, new: u32) {
+        self.regs.set(InterruptRegister::Status, old & new);
+    }
+
+    /// Sets the line's bit in I_STAT, devices call it on their IRQ edge
+    pub fn request(&self, irq: Interrupt) {
+        let status = self.regs.get(InterruptRegister::Status);
+        self.regs.set(InterruptRegister::Status, status | 1 << irq as u32);
+    }
+
+    pub fn status(&self) -> u32 {
+        self.regs.get(InterruptRegister::Status)
+    }
+    pub fn mask(&self) -> u32 {
+        self.regs.get(InterruptRegister::Mask)
+    }
+
+    /// Whether the CPU sees its interrupt line raised
+    pub fn pending(&self) -> bool {
+        self.status() & self.mask() != 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine, mips::cop0::ExceptionsCodes};
+    use super::*;
+
+    #[test]
+    fn test_acknowledge() {
+        let irq = InterruptController::default();
+        irq.request(Interrupt::Dma);
+        irq.request(Interrupt::VBlank);
+        assert!(!irq.pending());
+        irq.write::<u16>(0x1F801074, 0x0008).unwrap();
+        assert!(irq.pending());
+
+        // writing 1 keeps a bit, 0 acknowledges it
+        irq.write::<u16>(0x1F801070, !0x0008).unwrap();
+        assert_eq!(irq.read::<u32>(0x1F801070).unwrap(), 0x0001);
+        assert!(!irq.pending());
+        irq.request(Interrupt::Timer2);
+        irq.write::<u8>(0x1F801071, 0x00).unwrap();
+        assert_eq!(irq.status(), 0x0041);
+    }
+
+    #[test]
+    fn test_interrupt_exception() {
+        let machine = Machine::with_program(&[0x24020001]); // addiu v0, zero, 1
+        machine.cpu.cop0.system_status.set(0x00000401);
+        machine.write::<u32>(0x1F801074, 1 << Interrupt::Timer0 as u32).unwrap();
+        machine.io.irq.request(Interrupt::Timer0);
+
+        machine.step();
+        assert_eq!(machine.cpu.pc(), 0x80000080);
+        assert_eq!(machine.cpu.cop0.return_address_from_trap.get(), 0x80010000);
+        assert_eq!(machine.cpu.cop0.exception_cause.get(), 0x400 | (ExceptionsCodes::Interrupt as u32) << 2);
+        assert_eq!(machine.cpu.gpr(2), 0);
+        // interrupts are off in the handler
+        assert_eq!(machine.cpu.cop0.system_status.get() & 0x3F, 0x04);
+    }
+}
@@ -1,98 +1,158 @@
-use std::cell::Cell;
+use crate::core::bus::mmio::{registers, Mmio, Register, RegisterFile};
 
-use crate::core::bus::mmio;
+/// RAM_SIZE as the BIOS sets it, the 2MB RAM mirrored in the first 8MB
+pub const RAM_SIZE_RESET: u32 = 0x00000B88;
+/// Size of the RAM window at the start of KUSEG, KSEG0 and KSEG1
+pub const RAM_WINDOW_SIZE: u32 = 8 * 1024 * 1024;
+const MB: u32 = 1024 * 1024;
 
-use mmio::U8U16U32 as UU;
-#[derive(Default)]
+/// What an address of the RAM window selects
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RamMapping {
+    /// Offset into the installed RAM
+    Ram(u32),
+    /// Nothing drives the bus
+    HighZ,
+    /// Accesses raise a bus error
+    Locked,
+}
+
+#[derive(Clone)]
 pub struct MemControl {
-    exp1_base: Cell<u32>,
-    exp2_base: Cell<u32>,
-    exp1_size: Cell<u32>,
-    exp3_size: Cell<u32>,
-    bios_rom: Cell<u32>,
-    spu_delay: Cell<u32>,
-    cdrom_delay: Cell<u32>,
-    exp2_size: Cell<u32>,
-    com_delay: Cell<u32>,
-    ram_size: Cell<u32>,
-    cache_control: Cell<u32>
+    regs: RegisterFile,
+}
+
+registers! {
+    /*
+    1F801000h 4    Expansion 1 Base Address (usually 1F000000h)
+    1F801004h 4    Expansion 2 Base Address (usually 1F802000h)
+    1F801008h 4    Expansion 1 Delay/Size (usually 0013243Fh; 512Kbytes 8bit-bus)
+    1F80100Ch 4    Expansion 3 Delay/Size (usually 00003022h; 1 byte)
+    1F801010h 4    BIOS ROM    Delay/Size (usually 0013243Fh; 512Kbytes 8bit-bus)
+    1F801014h 4    SPU_DELAY   Delay/Size (usually 200931E1h)
+    1F801018h 4    CDROM_DELAY Delay/Size (usually 00020843h or 00020943h)
+    1F80101Ch 4    Expansion 2 Delay/Size (usually 00070777h; 128-bytes 8bit-bus)
+    1F801020h 4    COM_DELAY / COMMON_DELAY (00031125h or 0000132Ch or 00001325h)
+    1F801060h 4/2  RAM_SIZE (usually 00000B88h; 2MB RAM mirrored in first 8MB)
+    FFFE0130h 4        Cache Control
+    Bits 24-31 of the base addresses are fixed to 1Fh.
+    */
+    pub enum MemControlRegister for MemControl {
+        Exp1Base = 0x1F801000: u32, reset = 0x1F000000, write = 0x00FFFFFF;
+        Exp2Base = 0x1F801004: u32, reset = 0x1F802000, write = 0x00FFFFFF;
+        Exp1Delay = 0x1F801008: u32, reset = 0x0013243F;
+        Exp3Delay = 0x1F80100C: u32, reset = 0x00003022;
+        BiosRomDelay = 0x1F801010: u32, reset = 0x0013243F;
+        SpuDelay = 0x1F801014: u32, reset = 0x200931E1;
+        CdromDelay = 0x1F801018: u32, reset = 0x00020843;
+        Exp2Delay = 0x1F80101C: u32, reset = 0x00070777;
+        ComDelay = 0x1F801020: u32, reset = 0x00031125;
+        RamSize = 0x1F801060: u32, reset = RAM_SIZE_RESET;
+        CacheControl = 0x1FFE0130: u32;
+    }
+}
+
+impl Default for MemControl {
+    fn default() -> Self {
+        Self { regs: RegisterFile::new(MemControlRegister::MAP) }
+    }
+}
+
+impl Mmio for MemControl {
+    fn registers(&self) -> &'static [Register<Self>] {
+        MemControlRegister::MAP
+    }
+    fn storage(&self) -> &RegisterFile {
+        &self.regs
+    }
 }
 
-/*
-1F801000h 4    Expansion 1 Base Address (usually 1F000000h)
-1F801004h 4    Expansion 2 Base Address (usually 1F802000h)
-1F801008h 4    Expansion 1 Delay/Size (usually 0013243Fh; 512Kbytes 8bit-bus)
-1F80100Ch 4    Expansion 3 Delay/Size (usually 00003022h; 1 byte)
-1F801010h 4    BIOS ROM    Delay/Size (usually 0013243Fh; 512Kbytes 8bit-bus)
-1F801014h 4    SPU_DELAY   Delay/Size (usually 200931E1h)
-1F801018h 4    CDROM_DELAY Delay/Size (usually 00020843h or 00020943h)
-1F80101Ch 4    Expansion 2 Delay/Size (usually 00070777h; 128-bytes 8bit-bus)
-1F801020h 4    COM_DELAY / COMMON_DELAY (00031125h or 0000132Ch or 00001325h)
-1F801060h 4/2  RAM_SIZE (usually 00000B88h; 2MB RAM mirrored in first 8MB)
-FFFE0130h 4        Cache Control
-*/
-impl mmio::Mmio for MemControl {
-    fn interpreter(
-        &self,
-        cmd: mmio::MMIOCommand,
-    ) -> crate::core::bus::Result<Option<mmio::U8U16U32>> {
-        match cmd {
-            mmio::MMIOCommand::ReadU32(0x1F801000) => Ok(Some(UU::U32(self.exp1_base.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801000, val) => {
-                self.exp1_base.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801004) => Ok(Some(UU::U32(self.exp2_base.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801004, val) => {
-                self.exp2_base.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801008) => Ok(Some(UU::U32(self.exp1_size.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801008, val) => {
-                self.exp1_size.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F80100C) => Ok(Some(UU::U32(self.exp3_size.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F80100C, val) => {
-                self.exp3_size.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801010) => Ok(Some(UU::U32(self.bios_rom.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801010, val) => {
-                self.bios_rom.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801014) => Ok(Some(UU::U32(self.spu_delay.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801014, val) => {
-                self.spu_delay.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801018) => Ok(Some(UU::U32(self.cdrom_delay.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801018, val) => {
-                self.cdrom_delay.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F80101C) => Ok(Some(UU::U32(self.exp2_size.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F80101C, val) => {
-                self.exp2_size.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801020) => Ok(Some(UU::U32(self.com_delay.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801020, val) => {
-                self.com_delay.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1F801060) => Ok(Some(UU::U32(self.ram_size.get()))),
-            mmio::MMIOCommand::WriteU32(0x1F801060, val) => {
-                self.ram_size.set(val);
-                Ok(None)
-            },
-            mmio::MMIOCommand::ReadU32(0x1ffe0130) => Ok(Some(UU::U32(self.cache_control.get()))),
-            mmio::MMIOCommand::WriteU32(0x1ffe0130, val) => {
-                self.cache_control.set(val);
-                Ok(None)
-            },
-            _ => Err(crate::core::bus::BusError::BadAddress)
+impl MemControl {
+    /*
+    RAM_SIZE bits 9-11 define the 8MB memory window:
+      0 = 1MB Memory + 7MB Locked
+      1 = 4MB Memory + 4MB Locked
+      2 = 1MB Memory + 1MB HighZ + 6MB Locked
+      3 = 4MB Memory + 4MB HighZ
+      4 = 2MB Memory + 6MB Locked                ;<--- would be correct for PSX
+      5 = 8MB Memory                             ;<--- default by BIOS init
+      6 = 2MB Memory + 2MB HighZ + 4MB Locked    ;<-- HighZ = Second /RAS
+      7 = 8MB Memory
+    The installed RAM (2MB retail, 8MB on DTL-H dev kits) is mirrored
+    across the memory part.
+    */
+    /// Maps `addr`, a physical address inside the RAM window, given
+    /// `installed` bytes of RAM
+    pub fn map_ram(&self, addr: u32, installed: u32) -> RamMapping {
+        let (memory, high_z) = match (self.regs.get(MemControlRegister::RamSize) >> 9) & 7 {
+            0 => (MB, 0),
+            1 => (4 * MB, 0),
+            2 => (MB, MB),
+            3 => (4 * MB, 4 * MB),
+            4 => (2 * MB, 0),
+            6 => (2 * MB, 2 * MB),
+            _ => (8 * MB, 0),
+        };
+        if addr < memory {
+            RamMapping::Ram(addr % installed)
+        } else if addr < memory + high_z {
+            RamMapping::HighZ
+        } else {
+            RamMapping::Locked
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::{Machine, DEV_KIT_RAM_SIZE}};
+    use super::*;
+
+    #[test]
+    fn test_ram_mirroring() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x80000100, 0x12345678).unwrap();
+        assert_eq!(machine.read::<u32>(0x00600100).unwrap(), 0x12345678);
+        assert_eq!(machine.read::<u32>(0xA0200100).unwrap(), 0x12345678);
+
+        // 2MB + 6MB locked, the BIOS writes it as a halfword
+        machine.write::<u16>(0x1F801060, 0x0888).unwrap();
+        assert_eq!(machine.read::<u32>(0x1F801060).unwrap(), 0x00000888);
+        assert_eq!(machine.read::<u32>(0x801FFFFC).unwrap(), 0);
+        assert!(machine.read::<u32>(0x80200100).is_err());
+        assert!(machine.write::<u8>(0x00700000, 1).is_err());
+
+        // 2MB + 2MB HighZ + 4MB locked
+        machine.write::<u32>(0x1F801060, 0x00000C88).unwrap();
+        // nothing drives the bus, it still holds the RAM_SIZE write
+        assert_eq!(machine.read::<u32>(0x80200100).unwrap(), 0x00000C88);
+        assert!(machine.read::<u32>(0x80400100).is_err());
+    }
+
+    #[test]
+    fn test_dev_kit_ram() {
+        let memcontrol = MemControl::default();
+        assert_eq!(memcontrol.map_ram(0x600100, DEV_KIT_RAM_SIZE), RamMapping::Ram(0x600100));
+        assert_eq!(memcontrol.map_ram(0x600100, 2 * MB), RamMapping::Ram(0x000100));
+    }
+
+    #[test]
+    fn test_scratchpad_segments() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x1F800010, 0xCAFE).unwrap();
+        assert_eq!(machine.read::<u32>(0x9F800010).unwrap(), 0xCAFE);
+        assert!(machine.read::<u32>(0xBF800010).is_err());
+        assert!(machine.write::<u32>(0xBF800010, 0).is_err());
+    }
+
+    #[test]
+    fn test_cache_control_segment() {
+        let machine = Machine::new();
+        machine.write::<u32>(0xFFFE0130, 0x00000804).unwrap();
+        assert_eq!(machine.read::<u32>(0xFFFE0130).unwrap(), 0x00000804);
+        // only KSEG2 reaches it
+        assert!(machine.read::<u32>(0x1FFE0130).is_err());
+        assert!(machine.write::<u32>(0x9FFE0130, 0).is_err());
+        assert_eq!(machine.io.memcontrol.regs.get(MemControlRegister::CacheControl), 0x00000804);
+    }
+}
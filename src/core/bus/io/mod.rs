@@ -1,28 +1,112 @@
 pub mod memcontrol;
-use super::{BusDevice, mmio::Mmio};
+pub mod irq;
+pub mod dma;
+pub mod timers;
+pub mod raster;
+pub mod gpu;
+use std::cell::Cell;
 
+use super::{BusDevice, devices::Builtin, mmio::Mmio};
 
-#[derive(Default)]
+/// Ports of the I/O area with a device behind them
+pub const DEVICES: [Builtin; 7] = [
+    ("memcontrol", 0x1F801000, 0x24),
+    ("ram size", 0x1F801060, 4),
+    ("irq", 0x1F801070, 8),
+    ("dma", 0x1F801080, 0x80),
+    ("timers", 0x1F801100, 0x30),
+    ("gpu", 0x1F801810, 8),
+    ("cache control", 0x1FFE0130, 4),
+];
+
+
+#[derive(Clone, Default)]
 pub struct IOMap {
-    memcontrol: memcontrol::MemControl
+    pub memcontrol: memcontrol::MemControl,
+    pub irq: irq::InterruptController,
+    pub dma: dma::Dma,
+    pub timers: timers::Timers,
+    pub gpu: gpu::Gpu,
+    /// Cycle the devices caught up to
+    synced: Cell<u64>,
+}
+
+impl IOMap {
+    /// Lets the devices catch up with the CPU, at cycle `now`. The beam
+    /// position drives the timers and the vblank interrupt.
+    pub fn sync(&self, now: u64) {
+        let raster = &self.gpu.raster;
+        let mut cycles = now.saturating_sub(self.synced.replace(now));
+        while cycles > 0 {
+            let chunk = cycles.min(raster.cycles_to_edge() as u64) as u32;
+            let (hblank, vblank) = (raster.hblank(), raster.vblank());
+            let dots = raster.advance(chunk);
+            self.timers.advance(&self.irq, chunk, dots);
+            if raster.hblank() != hblank {
+                self.timers.set_hblank(&self.irq, !hblank);
+            }
+            if raster.vblank() != vblank {
+                self.timers.set_vblank(!vblank);
+                if !vblank {
+                    self.irq.request(irq::Interrupt::VBlank);
+                }
+            }
+            cycles -= chunk as u64;
+        }
+    }
+
+    /// Whether one of the devices answers at `addr`
+    pub fn claims(&self, addr: u32) -> bool {
+        DEVICES.iter().any(|&(_, base, size)| (base..base + size).contains(&addr))
+    }
 }
 
 impl BusDevice for IOMap {
     fn read<U: super::Unit>(&self, addr: u32 ) -> super::Result<U> {
         match addr {
-            0x1F801000..0x1F801020 => self.memcontrol.read::<U>(addr),
-            0x1F801060 |0x1ffe0130 => self.memcontrol.read::<U>(addr),
+            0x1F801000..0x1F801024 => self.memcontrol.read::<U>(addr),
+            0x1F801060..0x1F801064 | 0x1FFE0130..0x1FFE0134 => self.memcontrol.read::<U>(addr),
+            0x1F801070..0x1F801078 => self.irq.read::<U>(addr),
+            0x1F801080..0x1F801100 => self.dma.read::<U>(addr),
+            0x1F801100..0x1F801130 => self.timers.read::<U>(addr),
+            0x1F801810..0x1F801818 => self.gpu.read::<U>(addr),
             
-            _ => Err( super::BusError::BadAddress )
+            _ => Err( super::BusErrorKind::BadAddress.into() )
         }
     }
 
     fn write<U: super::Unit>(&self, addr: u32, val: U ) -> super::Result<()> {
         match addr {
-            0x1F801000..0x1F801020 => self.memcontrol.write::<U>(addr, val),
-            0x1F801060 |0x1ffe0130 => self.memcontrol.write::<U>(addr, val),
+            0x1F801000..0x1F801024 => self.memcontrol.write::<U>(addr, val),
+            0x1F801060..0x1F801064 | 0x1FFE0130..0x1FFE0134 => self.memcontrol.write::<U>(addr, val),
+            0x1F801070..0x1F801078 => self.irq.write::<U>(addr, val),
+            0x1F801080..0x1F801100 => self.dma.write::<U>(addr, val),
+            0x1F801100..0x1F801130 => self.timers.write::<U>(addr, val),
+            0x1F801810..0x1F801818 => self.gpu.write::<U>(addr, val),
 
-            _ => Err( super::BusError::BadAddress )
+            _ => Err( super::BusErrorKind::BadAddress.into() )
+        }
+    }
+
+    fn peek<U: super::Unit>(&self, addr: u32) -> Option<U> {
+        match addr {
+            0x1F801000..0x1F801024 | 0x1F801060..0x1F801064 | 0x1FFE0130..0x1FFE0134 => self.memcontrol.peek::<U>(addr),
+            0x1F801070..0x1F801078 => self.irq.peek::<U>(addr),
+            0x1F801080..0x1F801100 => self.dma.peek::<U>(addr),
+            0x1F801100..0x1F801130 => self.timers.peek::<U>(addr),
+            0x1F801810..0x1F801818 => self.gpu.peek::<U>(addr),
+            _ => None
+        }
+    }
+
+    fn poke<U: super::Unit>(&self, addr: u32, val: U) -> bool {
+        match addr {
+            0x1F801000..0x1F801024 | 0x1F801060..0x1F801064 | 0x1FFE0130..0x1FFE0134 => self.memcontrol.poke::<U>(addr, val),
+            0x1F801070..0x1F801078 => self.irq.poke::<U>(addr, val),
+            0x1F801080..0x1F801100 => self.dma.poke::<U>(addr, val),
+            0x1F801100..0x1F801130 => self.timers.poke::<U>(addr, val),
+            0x1F801810..0x1F801818 => self.gpu.poke::<U>(addr, val),
+            _ => false
         }
     }
 
@@ -0,0 +1,145 @@
+use std::cell::Cell;
+
+/*
+Video timing, in video clocks (53.69MHz, 11/7 of the system clock):
+  NTSC  3413 video clocks per scanline, 263 scanlines per frame
+  PAL   3406 video clocks per scanline, 314 scanlines per frame
+  The display area is set by GP1(06h) and GP1(07h), after a reset:
+    X1=200h, X2=C00h   the rest of the line is horizontal blanking
+    Y1=010h, Y2=100h   the other lines are vertical blanking
+  The dotclock is the video clock divided by 10, 8, 5, 4 or 7 for the 256,
+  320, 512, 640 and 368 pixel wide modes. */
+/// Positions within a line are kept in 1/7 video clocks, a system clock
+/// cycle is 11 of them
+const SUBCLOCKS: u32 = 7;
+const CYCLE_SUBCLOCKS: u32 = 11;
+
+/// What the GPU's display registers make of the video signal
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Timing {
+    pub pal: bool,
+    /// Video clocks per dot
+    pub dot_divider: u32,
+    /// Video clocks of each line outside the horizontal blank
+    pub horizontal: (u32, u32),
+    /// Lines outside the vertical blank
+    pub vertical: (u32, u32),
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self { pal: false, dot_divider: 10, horizontal: (0x200, 0xC00), vertical: (0x010, 0x100) }
+    }
+}
+
+impl Timing {
+    fn line_clocks(&self) -> u32 {
+        if self.pal { 3406 } else { 3413 }
+    }
+    fn lines(&self) -> u32 {
+        if self.pal { 314 } else { 263 }
+    }
+}
+
+/// Beam position of the video output, the source of the blanking signals
+/// and the dotclock the root counters follow
+#[derive(Clone, Default)]
+pub struct Raster {
+    timing: Cell<Timing>,
+    /// Position in the current line, in 1/7 video clocks
+    position: Cell<u32>,
+    line: Cell<u32>,
+    /// Frames started since reset, their parity is the interlaced field
+    frames: Cell<u64>,
+}
+
+impl Raster {
+    pub fn set_timing(&self, timing: Timing) {
+        self.timing.set(timing);
+    }
+
+    pub fn hblank(&self) -> bool {
+        let (start, end) = self.timing.get().horizontal;
+        !(start..end).contains(&(self.position.get() / SUBCLOCKS))
+    }
+
+    pub fn vblank(&self) -> bool {
+        let (start, end) = self.timing.get().vertical;
+        !(start..end).contains(&self.line.get())
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line.get()
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames.get()
+    }
+
+    /// System clock cycles until the next blanking edge or line start, at
+    /// least one
+    pub fn cycles_to_edge(&self) -> u32 {
+        let timing = self.timing.get();
+        let position = self.position.get();
+        let edge = [timing.horizontal.0, timing.horizontal.1, timing.line_clocks()].into_iter()
+            .map(|clock| clock.min(timing.line_clocks()) * SUBCLOCKS)
+            .filter(|&edge| edge > position)
+            .min()
+            .unwrap_or(position);
+        (edge - position).div_ceil(CYCLE_SUBCLOCKS).max(1)
+    }
+
+    /// System clock cycles until `dots` more dots are drawn
+    pub fn cycles_for_dots(&self, dots: u32) -> u32 {
+        let dot = SUBCLOCKS * self.timing.get().dot_divider;
+        let position = self.position.get();
+        let end = (position / dot + dots) * dot;
+        (end - position).div_ceil(CYCLE_SUBCLOCKS)
+    }
+
+    /// Moves the beam `cycles` system clock cycles ahead, returns the dots
+    /// drawn meanwhile
+    pub fn advance(&self, cycles: u32) -> u32 {
+        let timing = self.timing.get();
+        let dot = SUBCLOCKS * timing.dot_divider;
+        let start = self.position.get();
+        let end = start + cycles * CYCLE_SUBCLOCKS;
+        let line = timing.line_clocks() * SUBCLOCKS;
+        self.position.set(end % line);
+        let lines = self.line.get() + end / line;
+        self.frames.set(self.frames.get() + (lines / timing.lines()) as u64);
+        self.line.set(lines % timing.lines());
+        end / dot - start / dot
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_raster() {
+        let raster = Raster::default();
+        raster.set_timing(Timing { dot_divider: 8, ..Timing::default() });
+        assert!(raster.hblank() && raster.vblank());
+        // 0x200 video clocks are 325.8 cycles
+        assert_eq!(raster.cycles_to_edge(), 326);
+        assert_eq!(raster.advance(326), 64);
+        assert!(!raster.hblank());
+
+        let mut cycles = 326;
+        while raster.line() != 0x010 {
+            let chunk = raster.cycles_to_edge();
+            raster.advance(chunk);
+            cycles += chunk;
+        }
+        assert!(raster.hblank() && !raster.vblank());
+        // 16 lines of 2171.9 cycles, rounded up to the edge
+        assert_eq!(cycles, 34751);
+
+        // the rest of a PAL frame
+        raster.set_timing(Timing { pal: true, ..Timing::default() });
+        raster.advance(3406 * 7 * (314 - 16) / 11 + 1000);
+        assert_eq!((raster.frames(), raster.line()), (1, 0));
+    }
+}
@@ -0,0 +1,416 @@
+use std::cell::Cell;
+
+use crate::core::bus::{BusErrorKind, Result, Unit, mmio::{registers, Mmio, Register, RegisterFile}};
+use super::{irq::{Interrupt, InterruptController}, raster::Raster};
+
+const MODE_SYNC_ENABLE: u32 = 1 << 0;
+const MODE_RESET_AT_TARGET: u32 = 1 << 3;
+const MODE_IRQ_AT_TARGET: u32 = 1 << 4;
+const MODE_IRQ_AT_OVERFLOW: u32 = 1 << 5;
+const MODE_IRQ_REPEAT: u32 = 1 << 6;
+const MODE_IRQ_TOGGLE: u32 = 1 << 7;
+/// Active low, it reads 0 while the timer requests its interrupt
+const MODE_NO_IRQ: u32 = 1 << 10;
+const MODE_REACHED_TARGET: u32 = 1 << 11;
+const MODE_REACHED_OVERFLOW: u32 = 1 << 12;
+
+/// What makes a root counter count
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Source {
+    SystemClock,
+    DotClock,
+    HBlank,
+    /// System clock divided by 8
+    SystemClock8,
+}
+
+/// One root counter, its registers are relative to its base address
+#[derive(Clone)]
+pub struct Timer {
+    index: usize,
+    regs: RegisterFile,
+    /// The blanking signal the timer syncs to is active
+    in_blank: Cell<bool>,
+    /// Sync mode 3 saw its blank and runs freely
+    free_run: Cell<bool>,
+    /// One-shot interrupts fired since the mode was written
+    fired: Cell<bool>,
+    /// System clock cycles not yet counted in the divided by 8 mode
+    prescaler: Cell<u32>,
+}
+
+registers! {
+    /*
+    1F801100h+N*10h 4 Timer Current Counter Value (R/W)
+    1F801104h+N*10h 4 Timer Counter Mode (R/W)
+    1F801108h+N*10h 4 Timer Counter Target Value (R/W)
+    Counter and target are 16-bit, bits 16-31 are garbage.
+
+    Counter mode:
+      0     Synchronization Enable (0=Free Run, 1=Synchronize via Bit1-2)
+      1-2   Synchronization Mode   (0-3, see lists below)
+             Synchronization Modes for Counter 0 and 1 (HBlank and VBlank):
+               0 = Pause counter during Blank(s)
+               1 = Reset counter to 0000h at Blank(s)
+               2 = Reset counter to 0000h at Blank(s) and pause outside of Blank
+               3 = Pause until Blank occurs once, then switch to Free Run
+             Synchronization Modes for Counter 2:
+               0 or 3 = Stop counter at current value (forever, no h/v-blank start)
+               1 or 2 = Free Run (same as when Synchronization Disabled)
+      3     Reset counter to 0000h  (0=After Counter=FFFFh, 1=After Counter=Target)
+      4     IRQ when Counter=Target (0=Disable, 1=Enable)
+      5     IRQ when Counter=FFFFh  (0=Disable, 1=Enable)
+      6     IRQ Once/Repeat Mode    (0=One-shot, 1=Repeatedly)
+      7     IRQ Pulse/Toggle Mode   (0=Short Bit10=0 Pulse, 1=Toggle Bit10 on/off)
+      8-9   Clock Source (0-3, see list below)
+             Counter 0:  0 or 2 = System Clock,  1 or 3 = Dotclock
+             Counter 1:  0 or 2 = System Clock,  1 or 3 = Hblank
+             Counter 2:  0 or 1 = System Clock,  2 or 3 = System Clock/8
+      10    Interrupt Request       (0=Yes, 1=No) (Set after Writing)    (W=1) (R)
+      11    Reached Target Value    (0=No, 1=Yes) (Reset after Reading)        (R)
+      12    Reached FFFFh Value     (0=No, 1=Yes) (Reset after Reading)        (R)
+      13-15 Unknown (seems to be always zero)
+      16-31 Garbage (next opcode)
+    Writing the mode resets the counter to 0000h. */
+    pub enum TimerRegister for Timer {
+        Counter = 0x0: u32, read = 0xFFFF, write = 0xFFFF;
+        Mode = 0x4: u32, read = 0x1FFF, write = 0x3FF, on_read = Timer::mode_read, on_peek = Timer::mode, on_write = Timer::mode_written;
+        Target = 0x8: u32, read = 0xFFFF, write = 0xFFFF;
+    }
+}
+
+impl Mmio for Timer {
+    fn registers(&self) -> &'static [Register<Self>] {
+        TimerRegister::MAP
+    }
+    fn storage(&self) -> &RegisterFile {
+        &self.regs
+    }
+}
+
+impl Timer {
+    pub fn new(index: usize) -> Self {
+        let timer = Self {
+            index,
+            regs: RegisterFile::new(TimerRegister::MAP),
+            in_blank: Cell::new(false),
+            free_run: Cell::new(false),
+            fired: Cell::new(false),
+            prescaler: Cell::new(0),
+        };
+        timer.regs.set(TimerRegister::Mode, MODE_NO_IRQ);
+        timer
+    }
+
+    pub fn counter(&self) -> u32 {
+        self.regs.get(TimerRegister::Counter)
+    }
+    fn mode(&self) -> u32 {
+        self.regs.get(TimerRegister::Mode)
+    }
+    /// Reading the mode clears the reached flags
+    fn mode_read(&self) -> u32 {
+        let mode = self.mode();
+        self.regs.set(TimerRegister::Mode, mode & !(MODE_REACHED_TARGET | MODE_REACHED_OVERFLOW));
+        mode
+    }
+    fn mode_written(&self, _old: u32, new: u32) {
+        self.regs.set(TimerRegister::Mode, new | MODE_NO_IRQ);
+        self.regs.set(TimerRegister::Counter, 0);
+        self.free_run.set(false);
+        self.fired.set(false);
+    }
+
+    fn source(&self) -> Source {
+        match (self.index, (self.mode() >> 8) & 3) {
+            (0, 1 | 3) => Source::DotClock,
+            (1, 1 | 3) => Source::HBlank,
+            (2, 2 | 3) => Source::SystemClock8,
+            _ => Source::SystemClock,
+        }
+    }
+
+    /// Whether the sync mode lets the counter run
+    fn counting(&self) -> bool {
+        let mode = self.mode();
+        if mode & MODE_SYNC_ENABLE == 0 {
+            return true;
+        }
+        match (self.index, (mode >> 1) & 3) {
+            (2, sync) => sync == 1 || sync == 2,
+            (_, 0) => !self.in_blank.get(),
+            (_, 1) => true,
+            (_, 2) => self.in_blank.get(),
+            _ => self.free_run.get(),
+        }
+    }
+
+    /// Counts `cycles` system clock cycles and `dots` dotclock ticks, depending
+    /// on the clock source
+    pub fn advance(&self, irq: &InterruptController, cycles: u32, dots: u32) {
+        let ticks = match self.source() {
+            Source::SystemClock => cycles,
+            Source::DotClock => dots,
+            Source::HBlank => 0,
+            Source::SystemClock8 => {
+                let total = self.prescaler.get() + cycles;
+                self.prescaler.set(total % 8);
+                total / 8
+            },
+        };
+        if self.counting() {
+            self.count(irq, ticks);
+        }
+    }
+
+    /// Start or end of the blank the timer follows, hblank for timer 0 and
+    /// vblank for timer 1
+    pub fn blank(&self, active: bool) {
+        self.in_blank.set(active);
+        if !active || self.mode() & MODE_SYNC_ENABLE == 0 {
+            return;
+        }
+        match (self.mode() >> 1) & 3 {
+            1 | 2 => self.regs.set(TimerRegister::Counter, 0),
+            3 => self.free_run.set(true),
+            _ => (),
+        }
+    }
+
+    /// A horizontal blank started, a tick when it's the clock source
+    pub fn hblank_tick(&self, irq: &InterruptController) {
+        if self.source() == Source::HBlank && self.counting() {
+            self.count(irq, 1);
+        }
+    }
+
+    /// Ticks until the counter reaches a value it interrupts on, if it will
+    fn ticks_to_irq(&self) -> Option<u32> {
+        let mode = self.mode();
+        if !self.counting() || (mode & MODE_IRQ_REPEAT == 0 && self.fired.get()) {
+            return None;
+        }
+        let counter = self.counter();
+        let target = self.regs.get(TimerRegister::Target);
+        let reset = mode & MODE_RESET_AT_TARGET != 0;
+        let distance = |to: u32| match to.wrapping_sub(counter) & 0xFFFF {
+            0 => 0x10000,
+            ticks => ticks,
+        };
+        let to_target = match reset && counter == target {
+            true => target + 1,
+            false => distance(target),
+        };
+        // resetting at the target keeps the counter from going past it
+        let overflows = !reset || target == 0xFFFF || counter > target;
+        [
+            (mode & MODE_IRQ_AT_TARGET != 0).then_some(to_target),
+            (mode & MODE_IRQ_AT_OVERFLOW != 0 && overflows).then(|| distance(0xFFFF)),
+        ].into_iter().flatten().min()
+    }
+
+    /// Runs the counter `ticks` times, jumping from one event to the next
+    fn count(&self, irq: &InterruptController, mut ticks: u32) {
+        let mut counter = self.counter();
+        while ticks > 0 {
+            let mode = self.mode();
+            let target = self.regs.get(TimerRegister::Target);
+            // the counter stays on the target for a tick before going back to 0
+            if mode & MODE_RESET_AT_TARGET != 0 && counter == target {
+                counter = 0;
+                ticks -= 1;
+                if target == 0 {
+                    self.reached(irq, MODE_REACHED_TARGET, MODE_IRQ_AT_TARGET);
+                }
+                continue;
+            }
+            let to_target = if target > counter { target - counter } else { u32::MAX };
+            let to_overflow = if counter < 0xFFFF { 0xFFFF - counter } else { 1 };
+            let step = ticks.min(to_target).min(to_overflow);
+            counter = (counter + step) & 0xFFFF;
+            ticks -= step;
+            if counter == target {
+                self.reached(irq, MODE_REACHED_TARGET, MODE_IRQ_AT_TARGET);
+            }
+            if counter == 0xFFFF {
+                self.reached(irq, MODE_REACHED_OVERFLOW, MODE_IRQ_AT_OVERFLOW);
+            }
+        }
+        self.regs.set(TimerRegister::Counter, counter);
+    }
+
+    fn reached(&self, irq: &InterruptController, flag: u32, irq_enable: u32) {
+        let mode = self.mode() | flag;
+        self.regs.set(TimerRegister::Mode, mode);
+        if mode & irq_enable == 0 || (mode & MODE_IRQ_REPEAT == 0 && self.fired.get()) {
+            return;
+        }
+        self.fired.set(true);
+        // a pulse leaves bit 10 set, a toggle requests on its falling edge
+        if mode & MODE_IRQ_TOGGLE != 0 {
+            self.regs.set(TimerRegister::Mode, mode ^ MODE_NO_IRQ);
+            if mode & MODE_NO_IRQ == 0 {
+                return;
+            }
+        }
+        irq.request([Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2][self.index]);
+    }
+}
+
+/// The three root counters at 0x1F801100, 0x1F801110 and 0x1F801120
+#[derive(Clone)]
+pub struct Timers(pub [Timer; 3]);
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self([Timer::new(0), Timer::new(1), Timer::new(2)])
+    }
+}
+
+impl Timers {
+    fn timer(&self, addr: u32) -> Option<&Timer> {
+        self.0.get(((addr >> 4) & 0xF) as usize)
+    }
+
+    pub fn read<U: Unit>(&self, addr: u32) -> Result<U> {
+        self.timer(addr).ok_or(BusErrorKind::BadAddress.into()).and_then(|timer| timer.read(addr & 0xF))
+    }
+    pub fn write<U: Unit>(&self, addr: u32, val: U) -> Result<()> {
+        self.timer(addr).ok_or(BusErrorKind::BadAddress.into()).and_then(|timer| timer.write(addr & 0xF, val))
+    }
+    pub fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        self.timer(addr).and_then(|timer| timer.peek(addr & 0xF))
+    }
+    pub fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        self.timer(addr).is_some_and(|timer| timer.poke(addr & 0xF, val))
+    }
+
+    pub fn advance(&self, irq: &InterruptController, cycles: u32, dots: u32) {
+        for timer in &self.0 {
+            timer.advance(irq, cycles, dots);
+        }
+    }
+
+    /// System clock cycles until timer `index` interrupts, `None` if it won't
+    /// or counts hblanks, which the raster events already cover
+    pub fn cycles_to_irq(&self, index: usize, raster: &Raster) -> Option<u64> {
+        let timer = &self.0[index];
+        let ticks = timer.ticks_to_irq()?;
+        match timer.source() {
+            Source::SystemClock => Some(ticks as u64),
+            Source::SystemClock8 => Some(ticks as u64 * 8 - timer.prescaler.get() as u64),
+            Source::DotClock => Some(raster.cycles_for_dots(ticks) as u64),
+            Source::HBlank => None,
+        }
+    }
+
+    pub fn set_hblank(&self, irq: &InterruptController, active: bool) {
+        self.0[0].blank(active);
+        if active {
+            self.0[1].hblank_tick(irq);
+        }
+    }
+
+    pub fn set_vblank(&self, active: bool) {
+        self.0[1].blank(active);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_machine_clock() {
+        let machine = Machine::with_program(&[]);
+        machine.write::<u32>(0x1F801124, 0x0200).unwrap();
+        machine.resume(100);
+        // a nop takes a cycle, timer 2 counts every 8th
+        assert_eq!(machine.read::<u32>(0x1F801100).unwrap(), 100);
+        assert_eq!(machine.read::<u32>(0x1F801120).unwrap(), 12);
+    }
+
+    #[test]
+    fn test_target_and_overflow() {
+        let irq = InterruptController::default();
+        let timers = Timers::default();
+        timers.write::<u32>(0x1F801108, 99).unwrap();
+        // reset at target, repeated pulses
+        timers.write::<u32>(0x1F801104, 0x0058).unwrap();
+        timers.advance(&irq, 250, 0);
+        assert_eq!(timers.read::<u32>(0x1F801100).unwrap(), 50);
+        assert_eq!(irq.status(), 1 << 4);
+        assert_eq!(timers.read::<u32>(0x1F801104).unwrap(), 0x0C58);
+        assert_eq!(timers.read::<u32>(0x1F801104).unwrap(), 0x0458);
+
+        // one-shot overflow, the counter keeps running
+        timers.write::<u32>(0x1F801118, 10).unwrap();
+        timers.write::<u32>(0x1F801114, 0x0020).unwrap();
+        timers.advance(&irq, 0x1FFFF, 0);
+        assert_eq!(timers.read::<u32>(0x1F801110).unwrap(), 0xFFFF);
+        assert_eq!(timers.peek::<u32>(0x1F801114), Some(0x1C20));
+        assert_eq!(irq.status(), 1 << 4 | 1 << 5);
+        irq.write::<u32>(0x1F801070, 0).unwrap();
+        timers.advance(&irq, 0x10000, 0);
+        assert_eq!(irq.status() & 1 << 5, 0);
+
+        // writing the mode restarts the counter
+        timers.write::<u16>(0x1F801114, 0x0020).unwrap();
+        assert_eq!(timers.read::<u32>(0x1F801110).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_toggle() {
+        let irq = InterruptController::default();
+        let timers = Timers::default();
+        timers.write::<u32>(0x1F801128, 1).unwrap();
+        // system clock / 8, toggle on every target
+        timers.write::<u32>(0x1F801124, 0x02D8).unwrap();
+        timers.advance(&irq, 15, 0);
+        assert_eq!(timers.read::<u32>(0x1F801120).unwrap(), 1);
+        assert_eq!(timers.peek::<u32>(0x1F801124).unwrap() & MODE_NO_IRQ, 0);
+        assert_eq!(irq.status(), 1 << 6);
+        timers.advance(&irq, 9, 0);
+        assert_eq!(timers.read::<u32>(0x1F801120).unwrap(), 1);
+        assert_ne!(timers.peek::<u32>(0x1F801124).unwrap() & MODE_NO_IRQ, 0);
+
+        // timer 2 sync modes 0 and 3 stop it
+        timers.write::<u32>(0x1F801124, 0x0001).unwrap();
+        timers.advance(&irq, 100, 0);
+        assert_eq!(timers.read::<u32>(0x1F801120).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sync_modes() {
+        let irq = InterruptController::default();
+        let timers = Timers::default();
+        // pause during hblank, dotclock
+        timers.write::<u32>(0x1F801104, 0x0101).unwrap();
+        timers.advance(&irq, 100, 10);
+        timers.set_hblank(&irq, true);
+        timers.advance(&irq, 100, 10);
+        timers.set_hblank(&irq, false);
+        assert_eq!(timers.read::<u32>(0x1F801100).unwrap(), 10);
+
+        // reset at hblank, count only during it
+        timers.write::<u32>(0x1F801104, 0x0005).unwrap();
+        timers.advance(&irq, 100, 0);
+        timers.set_hblank(&irq, true);
+        timers.advance(&irq, 30, 0);
+        timers.set_hblank(&irq, false);
+        timers.advance(&irq, 100, 0);
+        assert_eq!(timers.read::<u32>(0x1F801100).unwrap(), 30);
+
+        // timer 1 waits for a vblank then runs freely, counting hblanks
+        timers.write::<u32>(0x1F801114, 0x0107).unwrap();
+        timers.set_hblank(&irq, true);
+        timers.set_vblank(true);
+        timers.set_vblank(false);
+        for _ in 0..3 {
+            timers.set_hblank(&irq, false);
+            timers.set_hblank(&irq, true);
+        }
+        assert_eq!(timers.read::<u32>(0x1F801110).unwrap(), 3);
+    }
+}
@@ -1,64 +1,114 @@
-use std::{io::Read, vec};
+use std::{cell::Cell, io::Read, vec};
 
-use super::{BusDevice, Unit, BusError};
+use super::{BusDevice, Unit, BusErrorKind};
 
-/// A R/W Memory device
+/// A R/W Memory device. Bytes are packed little-endian in words, so any
+/// access within a word is one shift whatever the host's byte order.
+#[derive(Clone)]
 pub struct Memory {
-    data: Vec<u8>
+    words: Box<[Cell<u32>]>,
+    /// Size in bytes, the last word may be partially used
+    len: usize,
 }
 
 impl Memory {
     pub fn new(size: u32) -> Self {
         Self {
-            data: std::iter::repeat(0).take(size as _).collect()
+            words: (0..(size as usize).div_ceil(4)).map(|_| Cell::new(0)).collect(),
+            len: size as usize,
         }
     }
 
-    unsafe fn as_ptr<U: Unit>(&self) -> * const U {
-        return  std::mem::transmute(self.data.as_ptr());
+    /// Whether `size` bytes at `addr` are all inside the memory
+    fn contains(&self, addr: u32, size: u32) -> bool {
+        addr as usize + size as usize <= self.len
     }
-    unsafe fn as_ptr_mut<U: Unit>(&self) -> * mut U {
-        return  std::mem::transmute(self.data.as_ptr());
+
+    fn read_byte(&self, addr: usize) -> u8 {
+        (self.words[addr / 4].get() >> ((addr % 4) * 8)) as u8
+    }
+
+    fn write_byte(&self, addr: usize, val: u8) {
+        let word = &self.words[addr / 4];
+        let shift = (addr % 4) * 8;
+        word.set(word.get() & !(0xFF << shift) | (val as u32) << shift);
+    }
+
+    /// Copies `data` at `offset`, failing if it doesn't fit
+    pub fn load(&self, offset: u32, data: &[u8]) -> super::Result<()> {
+        if offset as usize + data.len() > self.len {
+            return Err(BusErrorKind::BadAddress.into());
+        }
+        for (i, byte) in data.iter().enumerate() {
+            self.write_byte(offset as usize + i, *byte);
+        }
+        Ok(())
     }
 }
 
 impl From<Vec<u8>> for Memory {
     fn from(value: Vec<u8>) -> Self {
-        Self {
-            data: value
+        let memory = Self::new(value.len() as u32);
+        for (word, bytes) in memory.words.iter().zip(value.chunks(4)) {
+            let mut le = [0u8; 4];
+            le[..bytes.len()].copy_from_slice(bytes);
+            word.set(u32::from_le_bytes(le));
         }
+        memory
     }
 }
 
 impl BusDevice for Memory {
     fn read<U: Unit>(&self, addr: u32 ) -> super::Result<U> {
-        if ( addr < self.data.len() as _) {
-            unsafe {
-                Ok(*self.as_ptr::<U>().byte_offset(addr as _))
-            }
+        if !self.contains(addr, U::SIZE) {
+            return Err(BusErrorKind::BadAddress.into());
+        }
+        let shift = (addr & 3) * 8;
+        if shift + U::SIZE * 8 <= 32 {
+            Ok(U::from_u32(self.words[addr as usize / 4].get() >> shift))
         }
         else {
-            Err(BusError::BadAddress)
+            // straddles two words, only reachable by unaligned accesses
+            let val = (0..U::SIZE).fold(0, |val, i| {
+                val | (self.read_byte((addr + i) as usize) as u32) << (i * 8)
+            });
+            Ok(U::from_u32(val))
         }
     }
 
     fn write<U: Unit>(&self, addr: u32, val: U ) -> super::Result<()> {
-        if ( addr < self.data.len() as _) {
-            unsafe {
-                *self.as_ptr_mut::<U>().byte_offset(addr as _) = val;
-                Ok(())
-            }
+        if !self.contains(addr, U::SIZE) {
+            return Err(BusErrorKind::BadAddress.into());
+        }
+        let val: u32 = val.into();
+        let shift = (addr & 3) * 8;
+        if shift + U::SIZE * 8 <= 32 {
+            let mask = (u32::MAX >> (32 - U::SIZE * 8)) << shift;
+            let word = &self.words[addr as usize / 4];
+            word.set(word.get() & !mask | (val << shift) & mask);
         }
         else {
-            Err(BusError::BadAddress)
+            for i in 0..U::SIZE {
+                self.write_byte((addr + i) as usize, (val >> (i * 8)) as u8);
+            }
         }
+        Ok(())
+    }
+
+    fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        self.read(addr).ok()
+    }
+
+    fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        self.write(addr, val).is_ok()
     }
 
     fn size(&self) -> Option<usize> {
-        Some(self.data.len())
+        Some(self.len)
     }
 }
 
+#[derive(Clone)]
 pub struct RomMemory(Memory);
 
 impl RomMemory {
@@ -87,16 +137,29 @@ impl BusDevice for RomMemory {
     fn read<U: Unit>(&self, addr: u32 ) -> super::Result<U> {
         self.0.read(addr)
     }
+    fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        self.0.peek(addr)
+    }
+    /// Patches the ROM, the CPU can't write it
+    fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        self.0.poke(addr, val)
+    }
     fn size(&self) -> Option<usize> {
         self.0.size()
     }
 }
 
+#[derive(Clone)]
 pub struct RamMemory(Memory);
 impl RamMemory {
     pub fn new(size: u32) -> Self {
         Self(Memory::new(size))
     }
+
+    /// Copies `data` at `offset`, failing if it doesn't fit
+    pub fn load(&self, offset: u32, data: &[u8]) -> super::Result<()> {
+        self.0.load(offset, data)
+    }
 }
 impl BusDevice for RamMemory {
     fn read<U: Unit>(&self, addr: u32 ) -> super::Result<U> {
@@ -107,7 +170,51 @@ impl BusDevice for RamMemory {
         self.0.write(addr, val)
     }
 
+    fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        self.0.peek(addr)
+    }
+
+    fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        self.0.poke(addr, val)
+    }
+
     fn size(&self) -> Option<usize> { 
         self.0.size()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_little_endian_layout() {
+        let memory = Memory::from(vec![0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        assert_eq!(memory.read::<u32>(0), Ok(0x44332211));
+        assert_eq!(memory.read::<u16>(2), Ok(0x4433));
+        assert_eq!(memory.read::<u8>(5), Ok(0x66));
+        // unaligned accesses straddle words
+        assert_eq!(memory.read::<u32>(1), Ok(0x55443322));
+        assert_eq!(memory.read::<u16>(3), Ok(0x5544));
+
+        memory.write::<u16>(1, 0xBBAA).unwrap();
+        assert_eq!(memory.read::<u32>(0), Ok(0x44BBAA11));
+        memory.write::<u32>(2, 0xDDCCBBAA).unwrap();
+        assert_eq!(memory.read::<u32>(0), Ok(0xBBAAAA11));
+        assert_eq!(memory.read::<u16>(4), Ok(0xDDCC));
+    }
+
+    #[test]
+    fn test_bounds() {
+        let memory = Memory::new(6);
+        assert_eq!(memory.size(), Some(6));
+        assert!(memory.read::<u16>(4).is_ok());
+        assert!(memory.read::<u8>(5).is_ok());
+        // the access must fit as a whole, not just its first byte
+        assert!(memory.read::<u32>(4).is_err());
+        assert!(memory.read::<u16>(5).is_err());
+        assert!(memory.write::<u32>(3, 0).is_err());
+        assert!(memory.read::<u32>(u32::MAX).is_err());
+        assert!(memory.load(4, &[1, 2, 3]).is_err());
+    }
+}
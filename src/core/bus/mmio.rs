@@ -1,74 +1,231 @@
-use std::any::TypeId;
+use std::cell::Cell;
 
-use super::{BusDevice, BusError, Result, Unit};
+use super::{BusDevice, BusErrorKind, Result, Unit};
 
-pub enum MMIOCommand {
-    ReadU8(u32),
-    ReadU16(u32),
-    ReadU32(u32),
-    WriteU8(u32, u8),
-    WriteU16(u32, u16),
-    WriteU32(u32, u32),
+/// Computes a register's value instead of reading the storage
+pub type ReadHook<D> = fn(&D) -> u32;
+/// Runs after a write, with the register's value before and after it
+pub type WriteHook<D> = fn(&D, u32, u32);
+
+/// One register of an `Mmio` device, usually declared with `registers!`
+pub struct Register<D> {
+    pub name: &'static str,
+    /// Physical address of the register
+    pub address: u32,
+    /// Width in bytes
+    pub width: u32,
+    pub reset: u32,
+    /// Bits the CPU reads back, the others read as zero
+    pub read_mask: u32,
+    /// Bits the CPU can change, the others keep their value
+    pub write_mask: u32,
+    pub on_read: Option<ReadHook<D>>,
+    /// Side-effect free twin of `on_read`, for debuggers
+    pub on_peek: Option<ReadHook<D>>,
+    pub on_write: Option<WriteHook<D>>,
 }
 
-pub enum U8U16U32 {
-    U8(u8),
-    U16(u16),
-    U32(u32),
+impl<D> Register<D> {
+    /// Byte range of an access of `size` bytes at `addr` falling inside this
+    /// register, as (offset into the register, offset into the access, len)
+    fn overlap(&self, addr: u32, size: u32) -> Option<(u32, u32, u32)> {
+        let start = addr.max(self.address);
+        let end = (addr + size).min(self.address + self.width);
+        (start < end).then(|| (start - self.address, start - addr, end - start))
+    }
 }
 
-pub trait Mmio {
-    fn interpreter(&self, cmd: MMIOCommand) -> Result<Option<U8U16U32>>;
+/// All-ones over `bytes` bytes
+fn lanes(bytes: u32) -> u32 {
+    u32::MAX >> (32 - bytes * 8)
+}
 
-    fn read<U: Unit>(&self, addr: u32) -> Result<U> {
-        if TypeId::of::<U>() == TypeId::of::<u8>() {
-            return match self.interpreter(MMIOCommand::ReadU8(addr)) {
-                Ok(Some(res)) => Ok(U::from_u8u16u32(res)),
-                Err(err) => Err(err),
-                _ => Err(BusError::CannotRead),
-            };
-        } else if TypeId::of::<U>() == TypeId::of::<u16>() {
-            return match self.interpreter(MMIOCommand::ReadU16(addr)) {
-                Ok(Some(res)) => Ok(U::from_u8u16u32(res)),
-                Err(err) => Err(err),
-                _ => Err(BusError::CannotRead),
-            };
-        } else if TypeId::of::<U>() == TypeId::of::<u32>() {
-            return match self.interpreter(MMIOCommand::ReadU32(addr)) {
-                Ok(Some(res)) => Ok(U::from_u8u16u32(res)),
-                Err(err) => Err(err),
-                _ => Err(BusError::CannotRead),
-            };
+/// Values of a device's registers, in declaration order
+#[derive(Clone)]
+pub struct RegisterFile {
+    values: Vec<Cell<u32>>,
+    /// Start, end and index of each register, sorted by address
+    spans: Vec<(u32, u32, usize)>,
+}
+
+impl RegisterFile {
+    pub fn new<D>(registers: &[Register<D>]) -> Self {
+        let mut spans: Vec<_> = registers.iter().enumerate()
+            .map(|(index, reg)| (reg.address, reg.address + reg.width, index))
+            .collect();
+        spans.sort_unstable();
+        Self { values: registers.iter().map(|reg| Cell::new(reg.reset)).collect(), spans }
+    }
+    pub fn get(&self, index: impl Into<usize>) -> u32 {
+        self.values[index.into()].get()
+    }
+    pub fn set(&self, index: impl Into<usize>, val: u32) {
+        self.values[index.into()].set(val)
+    }
+
+    /// Indices of the registers `size` bytes at `addr` touch
+    fn covering(&self, addr: u32, size: u32) -> impl Iterator<Item = usize> + '_ {
+        let first = self.spans.partition_point(|&(_, end, _)| end <= addr);
+        self.spans[first..].iter()
+            .take_while(move |&&(start, ..)| start < addr + size)
+            .map(|&(.., index)| index)
+    }
+}
+
+/// Declares the register map of a device as an enum naming the registers,
+/// with the map itself in its `MAP` constant:
+///
+/// ```ignore
+/// registers! {
+///     pub enum TimerRegister for Timer {
+///         Counter = 0x1F801100: u16, on_read = Timer::counter, on_peek = Timer::counter;
+///         Mode = 0x1F801104: u32, reset = 0x400, write = 0x3FF, on_write = Timer::mode_written;
+///     }
+/// }
+/// ```
+///
+/// `reset` defaults to 0, `read` and `write` masks to all ones.
+macro_rules! registers {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident for $device:ty {
+            $(
+                $(#[$reg_meta:meta])*
+                $reg:ident = $address:literal : $width:ty
+                $(, reset = $reset:expr)?
+                $(, read = $read:expr)?
+                $(, write = $write:expr)?
+                $(, on_read = $on_read:expr)?
+                $(, on_peek = $on_peek:expr)?
+                $(, on_write = $on_write:expr)?
+                ;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        $vis enum $name {
+            $($(#[$reg_meta])* $reg,)*
+        }
+
+        impl $name {
+            $vis const MAP: &'static [$crate::core::bus::mmio::Register<$device>] = &[
+                $($crate::core::bus::mmio::Register {
+                    name: stringify!($reg),
+                    address: $address,
+                    width: std::mem::size_of::<$width>() as u32,
+                    reset: registers!(@or 0 $(, $reset)?),
+                    read_mask: registers!(@or u32::MAX $(, $read)?),
+                    write_mask: registers!(@or u32::MAX $(, $write)?),
+                    on_read: registers!(@or None $(, Some($on_read))?),
+                    on_peek: registers!(@or None $(, Some($on_peek))?),
+                    on_write: registers!(@or None $(, Some($on_write))?),
+                },)*
+            ];
         }
-        unreachable!()
+
+        impl From<$name> for usize {
+            fn from(reg: $name) -> usize {
+                reg as usize
+            }
+        }
+    };
+    (@or $default:expr) => { $default };
+    (@or $default:expr, $value:expr) => { $value };
+}
+pub(crate) use registers;
+
+/// A device made of registers: accesses of any width are split in byte
+/// lanes over the registers they cover, so a 16-bit write to a 32-bit
+/// register only changes its low or high half.
+pub trait Mmio: Sized + 'static {
+    fn registers(&self) -> &'static [Register<Self>];
+    /// Storage behind the registers, as laid out by `registers()`
+    fn storage(&self) -> &RegisterFile;
+
+    fn read<U: Unit>(&self, addr: u32) -> Result<U> {
+        let value = gather(self, addr, U::SIZE, |index, reg| Some(match reg.on_read {
+            Some(hook) => hook(self),
+            None => self.storage().get(index),
+        }));
+        value.map(U::from_u32).ok_or(BusErrorKind::BadAddress.into())
     }
+
     fn write<U: Unit>(&self, addr: u32, val: U) -> Result<()> {
-        if TypeId::of::<U>() == TypeId::of::<u8>() {
-            let val = val.into() as _;
-            return match self.interpreter(MMIOCommand::WriteU8(addr, val)) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            };
-        } else if TypeId::of::<U>() == TypeId::of::<u16>() {
-            let val = val.into() as _;
-
-            return match self.interpreter(MMIOCommand::WriteU16(addr, val)) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            };
-        } else if TypeId::of::<U>() == TypeId::of::<u32>() {
-            let val = val.into() as _;
-
-            return match self.interpreter(MMIOCommand::WriteU32(addr, val)) {
-                Ok(_) => Ok(()),
-                Err(err) => Err(err),
-            };
+        let written = scatter(self, addr, U::SIZE, val.into(), |reg, old, new| {
+            if let Some(hook) = reg.on_write {
+                hook(self, old, new);
+            }
+        });
+        match written {
+            true => Ok(()),
+            false => Err(BusErrorKind::BadAddress.into()),
+        }
+    }
+
+    /// Reads the storage, or the `on_peek` hook of registers computing their
+    /// value. `None` for registers with only an `on_read` hook.
+    fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        let value = gather(self, addr, U::SIZE, |index, reg| match (reg.on_read, reg.on_peek) {
+            (None, _) => Some(self.storage().get(index)),
+            (Some(_), Some(hook)) => Some(hook(self)),
+            (Some(_), None) => None,
+        });
+        value.map(U::from_u32)
+    }
+
+    /// Stores the value without calling `on_write` hooks, refused for
+    /// registers whose value is computed on read
+    fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        let computed = self.storage().covering(addr, U::SIZE)
+            .any(|index| self.registers()[index].on_read.is_some());
+        !computed && scatter(self, addr, U::SIZE, val.into(), |_, _, _| ())
+    }
+}
+
+/// Assembles the byte lanes of the registers covering `size` bytes at `addr`
+/// from their full `value`, `None` if there's none or `value` gives up
+fn gather<D: Mmio>(device: &D, addr: u32, size: u32, value: impl Fn(usize, &Register<D>) -> Option<u32>) -> Option<u32> {
+    let mut result = 0;
+    let mut mapped = false;
+    for index in device.storage().covering(addr, size) {
+        let reg = &device.registers()[index];
+        let Some((reg_offset, offset, len)) = reg.overlap(addr, size) else {
+            continue;
+        };
+        let raw = value(index, reg)? & reg.read_mask;
+        result |= (raw >> (reg_offset * 8) & lanes(len)) << (offset * 8);
+        mapped = true;
+    }
+    mapped.then_some(result)
+}
+
+/// Stores the byte lanes of `val` in the registers they cover, through their
+/// write masks, then calls `written` with the old and new value of each.
+/// `false` if no register is covered.
+fn scatter<D: Mmio>(device: &D, addr: u32, size: u32, val: u32, written: impl Fn(&Register<D>, u32, u32)) -> bool {
+    let mut mapped = false;
+    for index in device.storage().covering(addr, size) {
+        let reg = &device.registers()[index];
+        let Some((reg_offset, offset, len)) = reg.overlap(addr, size) else {
+            continue;
+        };
+        mapped = true;
+        let mask = (lanes(len) << (reg_offset * 8)) & reg.write_mask;
+        if mask == 0 {
+            continue;
         }
-        unreachable!()
+        let bits = (val >> (offset * 8) & lanes(len)) << (reg_offset * 8);
+        let old = device.storage().get(index);
+        let new = (old & !mask) | (bits & mask);
+        device.storage().set(index, new);
+        written(reg, old, new);
     }
+    mapped
 }
 
-pub struct MmioToBusAdapter<M: Mmio>(M);
+#[derive(Clone)]
+pub struct MmioToBusAdapter<M: Mmio>(pub M);
 impl<M: Mmio> BusDevice for MmioToBusAdapter<M> {
     fn read<U: Unit>(&self, addr: u32 ) -> Result<U> {
         self.0.read(addr)
@@ -78,38 +235,120 @@ impl<M: Mmio> BusDevice for MmioToBusAdapter<M> {
         self.0.write(addr,val)
     }
 
+    fn peek<U: Unit>(&self, addr: u32) -> Option<U> {
+        self.0.peek(addr)
+    }
+
+    fn poke<U: Unit>(&self, addr: u32, val: U) -> bool {
+        self.0.poke(addr, val)
+    }
+
     fn size(&self) -> Option<usize> { None }
 }
 
 #[cfg(test)]
 mod test {
     use std::cell::Cell;
-    use crate::core::bus::{BusError, mmio::MmioToBusAdapter, BusDevice};
-    use super::{MMIOCommand, Mmio};
-    struct DummyMmio(Cell<u32>);
+    use crate::core::bus::{mmio::MmioToBusAdapter, BusDevice};
+    use super::{Mmio, Register, RegisterFile};
+
+    struct DummyMmio {
+        regs: RegisterFile,
+        writes: Cell<u32>,
+        fifo: Cell<u32>,
+    }
+
+    registers! {
+        enum DummyRegister for DummyMmio {
+            Data = 0x0: u32;
+            Status = 0x4: u16, reset = 0x8001, write = 0x00FF;
+            Port = 0x6: u16, read = 0, on_write = DummyMmio::port_written;
+            Counter = 0x8: u32, on_read = DummyMmio::counter;
+            /// Reading pops it, peeking doesn't
+            Fifo = 0xC: u32, on_read = DummyMmio::pop, on_peek = DummyMmio::front;
+        }
+    }
+
+    impl DummyMmio {
+        fn new() -> Self {
+            Self { regs: RegisterFile::new(DummyRegister::MAP), writes: Cell::new(0), fifo: Cell::new(7) }
+        }
+        fn port_written(&self, _old: u32, new: u32) {
+            self.writes.set(self.writes.get() + new);
+        }
+        fn counter(&self) -> u32 {
+            self.writes.get() * 2
+        }
+        fn pop(&self) -> u32 {
+            self.fifo.replace(self.fifo.get() + 1)
+        }
+        fn front(&self) -> u32 {
+            self.fifo.get()
+        }
+    }
 
     impl super::Mmio for DummyMmio {
-        fn interpreter(
-            &self,
-            cmd: super::MMIOCommand,
-        ) -> crate::core::bus::Result<Option<super::U8U16U32>> {
-            match cmd {
-                MMIOCommand::ReadU32(0) => Ok(Some(super::U8U16U32::U32(self.0.get()))),
-                MMIOCommand::WriteU32(0, val) => {
-                    self.0.set(val);
-                    Ok(None)
-                },
-                _ => Err(BusError::BadAddress)
-            }
+        fn registers(&self) -> &'static [Register<Self>] {
+            DummyRegister::MAP
+        }
+        fn storage(&self) -> &RegisterFile {
+            &self.regs
         }
     }
+
     #[test]
     fn test_mmio() {
-        let dummy = MmioToBusAdapter(DummyMmio(Cell::new(0)));
+        let dummy = MmioToBusAdapter(DummyMmio::new());
         let val: u32 = 1;
         dummy.write::<u32>(0, val).expect("Error: didn't write!");
         assert_eq!(dummy.read::<u32>(0).expect("Error: Didn't read"), val);
 
 
     }
+
+    #[test]
+    fn test_byte_lanes() {
+        let dummy = DummyMmio::new();
+        dummy.write::<u32>(0x0, 0x11223344).unwrap();
+        dummy.write::<u8>(0x2, 0xAA).unwrap();
+        dummy.write::<u16>(0x0, 0xBBCC).unwrap();
+        assert_eq!(dummy.read::<u32>(0x0).unwrap(), 0x11AABBCC);
+        assert_eq!(dummy.read::<u16>(0x2).unwrap(), 0x11AA);
+        assert_eq!(dummy.read::<u8>(0x1).unwrap(), 0xBB);
+        assert_eq!(dummy.regs.get(DummyRegister::Data), 0x11AABBCC);
+
+        // the upper byte of Status is read-only, Port is write-only
+        dummy.write::<u32>(0x4, 0x00050302).unwrap();
+        assert_eq!(dummy.regs.get(DummyRegister::Status), 0x8002);
+        assert_eq!(dummy.read::<u32>(0x4).unwrap(), 0x00008002);
+        assert_eq!(dummy.regs.get(DummyRegister::Port), 5);
+        dummy.write::<u8>(0x7, 0x01).unwrap();
+        assert_eq!(dummy.writes.get(), 5 + 0x105);
+        assert_eq!(dummy.read::<u32>(0x8).unwrap(), 0x214);
+        assert_eq!(dummy.regs.get(DummyRegister::Counter), 0);
+
+        assert!(dummy.read::<u32>(0x10).is_err());
+        assert!(dummy.write::<u8>(0x10, 0).is_err());
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let dummy = DummyMmio::new();
+        assert_eq!(dummy.peek::<u32>(0xC), Some(7));
+        assert_eq!(dummy.peek::<u32>(0xC), Some(7));
+        assert_eq!(dummy.read::<u32>(0xC).unwrap(), 7);
+        assert_eq!(dummy.peek::<u32>(0xC), Some(8));
+        assert_eq!(dummy.regs.get(DummyRegister::Fifo), 0);
+        // computed without a side-effect free twin
+        assert_eq!(dummy.peek::<u32>(0x8), None);
+        assert_eq!(dummy.peek::<u32>(0x10), None);
+
+        // no hooks, but masks and byte lanes still apply
+        assert!(dummy.poke::<u32>(0x4, 0x00050302));
+        assert_eq!(dummy.writes.get(), 0);
+        assert_eq!(dummy.peek::<u16>(0x4), Some(0x8002));
+        assert_eq!(dummy.regs.get(DummyRegister::Port), 5);
+        assert!(!dummy.poke::<u32>(0x8, 1));
+        assert!(!dummy.poke::<u8>(0x10, 1));
+    }
 }
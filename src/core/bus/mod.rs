@@ -1,72 +1,101 @@
 pub mod memory;
 pub mod mmio;
 pub mod io;
-use mmio::U8U16U32;
+pub mod policy;
+pub mod pages;
+pub mod devices;
 pub trait Unit: Sized + Into<u32> + Copy + Default + std::fmt::Display + 'static {
     const SIZE: u32 = std::mem::size_of::<Self>() as _;
-    fn from_u8u16u32(from: U8U16U32) -> Self;
+    /// Keeps the low `SIZE` bytes of `val`
+    fn from_u32(val: u32) -> Self;
 }
 impl Unit for u32 {
     const SIZE: u32 = std::mem::size_of::<u32>() as _;
-
-    fn from_u8u16u32(from: U8U16U32) -> Self {
-        match from {
-            U8U16U32::U32(val) => val,
-            _ => panic!("Mismateched data type")
-        }
+    fn from_u32(val: u32) -> Self {
+        val
     }
 }
 impl Unit for u16 {
     const SIZE: u32 = std::mem::size_of::<u16>() as _;
-    fn from_u8u16u32(from: U8U16U32) -> Self {
-        match from {
-            U8U16U32::U16(val) => val,
-            _ => panic!("Mismateched data type")
-        }
+    fn from_u32(val: u32) -> Self {
+        val as u16
     }
 }
 impl Unit for u8 {
     const SIZE: u32 = std::mem::size_of::<u8>() as _;
-
-    fn from_u8u16u32(from: U8U16U32) -> Self {
-        match from {
-            U8U16U32::U8(val) => val,
-            _ => panic!("Mismateched data type")
-        }
+    fn from_u32(val: u32) -> Self {
+        val as u8
     }
 }
 
-#[derive(Copy,Clone,Debug)]
-pub enum BusError {
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum BusErrorKind {
     BadAddress,
+    /// The address isn't a multiple of the access size
+    Misaligned,
     CannotWrite,
     CannotRead
 }
-pub type Result<T> = core::result::Result<T, BusError>;
 
-pub trait BusDevice {
-    fn read<U: Unit>(&self, addr: u32 ) -> Result<U> {
-        Err(BusError::CannotRead)
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Direction {
+    Read,
+    Write,
+    Fetch,
+}
+
+/// A failed access. Devices only know what went wrong, the machine fills
+/// in the rest with `at` before the error leaves the bus.
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub struct BusError {
+    pub kind: BusErrorKind,
+    pub addr: u32,
+    /// Access width in bytes
+    pub size: u32,
+    pub direction: Direction,
+    pub device: &'static str,
+    /// Instruction that made the access
+    pub pc: u32,
+}
+
+impl From<BusErrorKind> for BusError {
+    fn from(kind: BusErrorKind) -> Self {
+        Self { kind, addr: 0, size: 0, direction: Direction::Read, device: "", pc: 0 }
     }
-    fn write<U: Unit>(&self, addr: u32, val: U ) -> Result<()> {
-        Err(BusError::CannotWrite)
+}
+
+impl BusError {
+    pub fn at(self, addr: u32, size: u32, direction: Direction, device: &'static str, pc: u32) -> Self {
+        Self { addr, size, direction, device, pc, ..self }
     }
-    fn size(&self) -> Option<usize> { None }
 }
 
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} on {}-byte {:?} at {:#010x} ({}) by pc {:#010x}",
+            self.kind, self.size, self.direction, self.addr, self.device, self.pc)
+    }
+}
 
+pub type Result<T> = core::result::Result<T, BusError>;
 
-#[derive(Default)]
-pub struct DummyDevice;
-impl BusDevice for DummyDevice {
+pub trait BusDevice {
     fn read<U: Unit>(&self, addr: u32 ) -> Result<U> {
-        println!("Dummy device read at {:}", addr);
-        Ok(U::default())
+        Err(BusErrorKind::CannotRead.into())
     }
     fn write<U: Unit>(&self, addr: u32, val: U ) -> Result<()> {
-        println!("Dummy device write at {:}, value is {}", addr, val);
-
-        Ok(())
+        Err(BusErrorKind::CannotWrite.into())
+    }
+    /// Reads for debuggers and tools: no FIFO pops, IRQ acks or anything
+    /// else a read could trigger. `None` if the value can't be had that way.
+    fn peek<U: Unit>(&self, _addr: u32) -> Option<U> {
+        None
+    }
+    /// Writes for debuggers and tools, storing the value without running
+    /// what a write would trigger. `false` if the device doesn't allow it.
+    fn poke<U: Unit>(&self, _addr: u32, _val: U) -> bool {
+        false
     }
     fn size(&self) -> Option<usize> { None }
 }
+
@@ -0,0 +1,99 @@
+use std::cell::Cell;
+
+/// Pages are 64KiB, the RAM_SIZE banks and the BIOS are multiples of it
+pub const PAGE_SHIFT: u32 = 16;
+pub const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+pub const PAGE_MASK: u32 = PAGE_SIZE - 1;
+const PAGE_COUNT: usize = 1 << (32 - PAGE_SHIFT);
+/// Segments a physical page shows up in, KUSEG, KSEG0 and KSEG1
+const SEGMENTS: [u32; 3] = [0x00000000, 0x80000000, 0xA0000000];
+const KSEG1: u32 = 0xA0000000;
+
+/// What backs a page of the virtual address space
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Page {
+    /// Decoded by the machine on every access: I/O, unmapped and locked
+    /// areas, or anything that needs checks like isolated caches
+    Slow,
+    /// RAM, at this offset
+    Ram(u32),
+    /// BIOS ROM, at this offset
+    Rom(u32),
+    /// Scratchpad, only its first 1KiB, the rest of the page is unmapped
+    Scratchpad,
+}
+
+/// One lookup per access for RAM, scratchpad and BIOS. The machine rebuilds
+/// the entries whenever what backs them changes (RAM_SIZE, cache isolation).
+#[derive(Clone)]
+pub struct PageTable {
+    read: Box<[Cell<Page>]>,
+    write: Box<[Cell<Page>]>,
+}
+
+impl Default for PageTable {
+    fn default() -> Self {
+        Self {
+            read: (0..PAGE_COUNT).map(|_| Cell::new(Page::Slow)).collect(),
+            write: (0..PAGE_COUNT).map(|_| Cell::new(Page::Slow)).collect(),
+        }
+    }
+}
+
+impl PageTable {
+    pub fn read(&self, addr: u32) -> Page {
+        self.read[(addr >> PAGE_SHIFT) as usize].get()
+    }
+
+    pub fn write(&self, addr: u32) -> Page {
+        self.write[(addr >> PAGE_SHIFT) as usize].get()
+    }
+
+    /// Maps the page holding physical `addr` in every segment, or only the
+    /// cached ones
+    pub fn map(&self, addr: u32, read: Page, write: Page, cached_only: bool) {
+        for segment in SEGMENTS {
+            if cached_only && segment == KSEG1 {
+                continue;
+            }
+            let index = ((segment | addr) >> PAGE_SHIFT) as usize;
+            self.read[index].set(read);
+            self.write[index].set(write);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_page_table() {
+        let machine = Machine::new();
+        let pages = &machine.pages;
+        assert_eq!(pages.read(0x80012345), Page::Ram(0x10000));
+        assert_eq!(pages.write(0x00612345), Page::Ram(0x10000));
+        assert_eq!(pages.read(0xBFC01234), Page::Rom(0));
+        assert_eq!(pages.write(0xBFC01234), Page::Slow);
+        assert_eq!(pages.read(0x1F800000), Page::Scratchpad);
+        assert_eq!(pages.read(0xBF800000), Page::Slow);
+        // I/O shares the scratchpad page, past its first 1KiB
+        assert_eq!(machine.read::<u32>(0x1F801060).unwrap(), 0xB88);
+
+        // RAM_SIZE changes remap the window
+        machine.write::<u32>(0x1F801060, 0x00000C88).unwrap();
+        assert_eq!(pages.read(0x80212345), Page::Slow);
+        assert_eq!(pages.read(0x80112345), Page::Ram(0x110000));
+
+        // isolated caches swallow every write
+        machine.write::<u32>(0x80000100, 1).unwrap();
+        machine.cpu.write_cop0(&machine, 12, 0x00010000);
+        assert_eq!(pages.write(0x80000100), Page::Slow);
+        assert_eq!(pages.read(0x80000100), Page::Ram(0));
+        machine.write::<u32>(0x80000100, 2).unwrap();
+        machine.cpu.write_cop0(&machine, 12, 0);
+        assert_eq!(machine.read::<u32>(0x80000100).unwrap(), 1);
+        assert_eq!(pages.write(0x80000100), Page::Ram(0));
+    }
+}
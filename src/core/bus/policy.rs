@@ -0,0 +1,171 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+/// Parts of the address space with no emulated hardware behind them
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BusRegion {
+    /// 1F000000h-1F7FFFFFh, cartridge port
+    Expansion1,
+    /// 1F802000h-1F802FFFh, debug and dev kit I/O ports
+    Expansion2,
+    /// 1FA00000h-1FBFFFFFh, dev kit SRAM BIOS
+    Expansion3,
+    /// Ports of the 1F801000h I/O area that aren't implemented yet
+    Io,
+    /// Writes to the BIOS ROM
+    BiosRom,
+    /// RAM window parts locked by RAM_SIZE
+    LockedRam,
+    /// Everything else
+    Unmapped,
+}
+
+const REGIONS: [(BusRegion, &str); 7] = [
+    (BusRegion::Expansion1, "exp1"),
+    (BusRegion::Expansion2, "exp2"),
+    (BusRegion::Expansion3, "exp3"),
+    (BusRegion::Io, "io"),
+    (BusRegion::BiosRom, "bios"),
+    (BusRegion::LockedRam, "locked"),
+    (BusRegion::Unmapped, "unmapped"),
+];
+
+impl BusRegion {
+    pub fn name(self) -> &'static str {
+        REGIONS[self as usize].1
+    }
+    pub fn parse(text: &str) -> Option<Self> {
+        REGIONS.iter().find(|(_, name)| *name == text).map(|(region, _)| *region)
+    }
+    /// Whether open bus reads find all ones rather than the last value on the
+    /// bus. The 8-bit cartridge port floats high, the BIOS relies on it to
+    /// find no cartridge at 1F000084h.
+    pub fn pulled_up(self) -> bool {
+        self == BusRegion::Expansion1
+    }
+}
+
+/// What the bus does on an access to a `BusRegion`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessPolicy {
+    /// Fail the access, the CPU raises a bus error exception
+    BusError,
+    /// Print the first access to each address, then act as `OpenBus`
+    Log,
+    /// Reads return the open bus value, writes are dropped, see
+    /// `BusRegion::pulled_up`
+    OpenBus,
+    /// Act as `OpenBus` and stop the machine with `StopReason::UnmappedAccess`
+    Halt,
+}
+
+impl AccessPolicy {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "error" => Some(Self::BusError),
+            "log" => Some(Self::Log),
+            "open" => Some(Self::OpenBus),
+            "halt" => Some(Self::Halt),
+            _ => None,
+        }
+    }
+}
+
+/// Per region access policies of a machine
+#[derive(Clone)]
+pub struct BusPolicy {
+    policies: [Cell<AccessPolicy>; REGIONS.len()],
+    /// Addresses already reported by `AccessPolicy::Log`
+    logged: RefCell<HashSet<u32>>,
+}
+
+impl Default for BusPolicy {
+    fn default() -> Self {
+        let policy = |region| Cell::new(match region {
+            BusRegion::Expansion2 | BusRegion::Io => AccessPolicy::Log,
+            BusRegion::Expansion1 | BusRegion::Expansion3 | BusRegion::BiosRom => AccessPolicy::OpenBus,
+            BusRegion::LockedRam | BusRegion::Unmapped => AccessPolicy::BusError,
+        });
+        Self {
+            policies: REGIONS.map(|(region, _)| policy(region)),
+            logged: Default::default(),
+        }
+    }
+}
+
+impl BusPolicy {
+    pub fn get(&self, region: BusRegion) -> AccessPolicy {
+        self.policies[region as usize].get()
+    }
+    pub fn set(&self, region: BusRegion, policy: AccessPolicy) {
+        self.policies[region as usize].set(policy)
+    }
+    pub fn all(&self) -> Vec<(BusRegion, AccessPolicy)> {
+        REGIONS.iter().map(|(region, _)| (*region, self.get(*region))).collect()
+    }
+
+    /// Whether `addr` wasn't logged before, remembering it
+    pub fn first_access(&self, addr: u32) -> bool {
+        self.logged.borrow_mut().insert(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, debug::StopReason, machine::Machine, mips::cop0::ExceptionsCodes};
+    use super::*;
+
+    #[test]
+    fn test_unmapped_policies() {
+        let machine = Machine::new();
+        assert_eq!(machine.read::<u8>(0x1F000084).unwrap(), 0xFF);
+        machine.write::<u32>(0xBFC00000, 0x12345678).expect("ROM writes are ignored");
+        assert_eq!(machine.read::<u32>(0xBFC00000).unwrap(), 0);
+
+        machine.bus_policy.set(BusRegion::Expansion1, AccessPolicy::BusError);
+        assert!(machine.read::<u8>(0x1F000084).is_err());
+
+        machine.bus_policy.set(BusRegion::Expansion1, AccessPolicy::Halt);
+        machine.read::<u16>(0x1F000084).unwrap();
+        assert_eq!(machine.debug.take_stop(), Some(StopReason::UnmappedAccess {
+            pc: machine.cpu.current_pc(), addr: 0x1F000084, size: 2, write: false, region: BusRegion::Expansion1,
+        }));
+        // tools don't stop the machine
+        assert_eq!(machine.peek::<u16>(0x1F000084), None);
+        assert_eq!(machine.debug.take_stop(), None);
+
+        // the policy covers ports nothing answers to, not device errors
+        assert!(machine.read::<u32>(0x1F801200).is_ok());
+        assert!(machine.read::<u32>(0x1F80110C).is_err());
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let machine = Machine::new();
+        machine.write::<u32>(0x80000000, 0x12345678).unwrap();
+        assert_eq!(machine.read::<u32>(0x1FA00000).unwrap(), 0x12345678);
+        assert_eq!(machine.read::<u8>(0x1FA00001).unwrap(), 0x56);
+        assert_eq!(machine.read::<u16>(0x1F802002).unwrap(), 0x1234);
+
+        // a byte access only changes its own lane
+        machine.write::<u8>(0x80000002, 0xAB).unwrap();
+        assert_eq!(machine.read::<u32>(0x1FA00000).unwrap(), 0x12AB5678);
+
+        // the cartridge port floats high and leaves the bus that way
+        assert_eq!(machine.read::<u32>(0x1F000080).unwrap(), 0xFFFFFFFF);
+        assert_eq!(machine.read::<u16>(0x1F000084).unwrap(), 0xFFFF);
+        assert_eq!(machine.read::<u32>(0x1FA00000).unwrap(), 0xFFFFFFFF);
+    }
+
+    #[test]
+    fn test_bus_error_exception() {
+        // lw v0, 0(v1) into the locked part of a 1MB + 7MB locked window
+        let machine = Machine::with_program(&[0x8C620000]);
+        machine.cpu.set_gpr(3, 0x80100000);
+        machine.write::<u16>(0x1F801060, 0).unwrap();
+        machine.debug.break_on_exception.set(true);
+
+        assert_eq!(machine.step(), Some(StopReason::Exception { pc: 0x80010000, code: ExceptionsCodes::DataError }));
+        assert_eq!(machine.cpu.cop0.return_address_from_trap.get(), 0x80010000);
+    }
+}
@@ -0,0 +1,126 @@
+use crate::core::{machine::Machine, mips::disasm::REG_NAMES};
+
+use super::symbols::SymbolTable;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operand {
+    Register(usize),
+    Pc,
+    Hi,
+    Lo,
+    Value(u32),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// Breakpoint condition, `<operand> <compare> <operand>` on unsigned values,
+/// like `$v0 == 0x10` or `$a0 >= $a1`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Condition {
+    pub lhs: Operand,
+    pub compare: Compare,
+    pub rhs: Operand,
+}
+
+impl Operand {
+    /// Registers by name with an optional `$`, `pc`, `hi`, `lo`, `rN`,
+    /// numbers (hex with `0x`) and symbols
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Option<Self> {
+        let name = text.strip_prefix('$').unwrap_or(text);
+        if let Some(reg) = REG_NAMES.iter().position(|reg| *reg == name) {
+            return Some(Operand::Register(reg));
+        }
+        match name {
+            "pc" => return Some(Operand::Pc),
+            "hi" => return Some(Operand::Hi),
+            "lo" => return Some(Operand::Lo),
+            "s8" => return Some(Operand::Register(30)),
+            _ => (),
+        }
+        if let Some(reg) = name.strip_prefix('r').and_then(|reg| reg.parse::<usize>().ok()) {
+            return (reg < 32).then_some(Operand::Register(reg));
+        }
+        parse_number(text).or_else(|| symbols.lookup(text)).map(Operand::Value)
+    }
+
+    pub fn eval(&self, machine: &Machine) -> u32 {
+        match self {
+            Operand::Register(reg) => machine.cpu.gpr(*reg),
+            Operand::Pc => machine.cpu.pc(),
+            Operand::Hi => machine.cpu.hi(),
+            Operand::Lo => machine.cpu.lo(),
+            Operand::Value(val) => *val,
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Option<Self> {
+        let compares = [
+            ("==", Compare::Equal), ("!=", Compare::NotEqual),
+            ("<=", Compare::LessEqual), (">=", Compare::GreaterEqual),
+            ("<", Compare::Less), (">", Compare::Greater),
+        ];
+        let (op, compare) = compares.iter().find(|(op, _)| text.contains(op))?;
+        let (lhs, rhs) = text.split_once(op)?;
+        Some(Condition {
+            lhs: Operand::parse(lhs.trim(), symbols)?,
+            compare: *compare,
+            rhs: Operand::parse(rhs.trim(), symbols)?,
+        })
+    }
+
+    pub fn eval(&self, machine: &Machine) -> bool {
+        let (lhs, rhs) = (self.lhs.eval(machine), self.rhs.eval(machine));
+        match self.compare {
+            Compare::Equal => lhs == rhs,
+            Compare::NotEqual => lhs != rhs,
+            Compare::Less => lhs < rhs,
+            Compare::LessEqual => lhs <= rhs,
+            Compare::Greater => lhs > rhs,
+            Compare::GreaterEqual => lhs >= rhs,
+        }
+    }
+}
+
+/// `0x` prefixed hex or decimal
+pub fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse::<u32>().ok(),
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "${}", REG_NAMES[*reg]),
+            Operand::Pc => write!(f, "pc"),
+            Operand::Hi => write!(f, "hi"),
+            Operand::Lo => write!(f, "lo"),
+            Operand::Value(val) => write!(f, "{:#x}", val),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self.compare {
+            Compare::Equal => "==",
+            Compare::NotEqual => "!=",
+            Compare::Less => "<",
+            Compare::LessEqual => "<=",
+            Compare::Greater => ">",
+            Compare::GreaterEqual => ">=",
+        };
+        write!(f, "{} {} {}", self.lhs, op, self.rhs)
+    }
+}
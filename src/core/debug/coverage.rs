@@ -0,0 +1,216 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Result;
+
+use crate::core::{bus::{BusDevice, io::memcontrol::RAM_WINDOW_SIZE}, machine::{Machine, MASK_ADDRESS_SPACE}, mips::disasm::disassemble};
+
+use super::symbols::SymbolTable;
+
+/// Executable memory whose words are tracked
+#[derive(Clone)]
+struct Region {
+    name: &'static str,
+    /// Physical address of the first word
+    base: u32,
+    /// Physical range the memory is mirrored across
+    span: u32,
+    /// Address the listing shows, KSEG0 for RAM and KSEG1 for the BIOS
+    display_base: u32,
+    /// One bit per word, LSB first
+    bitmap: Vec<Cell<u8>>,
+}
+
+impl Region {
+    fn new(name: &'static str, base: u32, span: u32, display_base: u32, size: u32) -> Self {
+        Self { name, base, span, display_base, bitmap: (0..size / 32).map(|_| Cell::new(0)).collect() }
+    }
+    fn words(&self) -> u32 {
+        self.bitmap.len() as u32 * 8
+    }
+    /// Word index of a virtual address inside this region
+    fn word(&self, addr: u32) -> Option<u32> {
+        let offset = (addr & MASK_ADDRESS_SPACE).wrapping_sub(self.base);
+        (offset < self.span).then_some(offset / 4 % self.words())
+    }
+    fn executed(&self, word: u32) -> bool {
+        self.bitmap[word as usize / 8].get() & (1 << (word % 8)) != 0
+    }
+    fn count(&self) -> u32 {
+        self.bitmap.iter().map(|byte| byte.get().count_ones()).sum()
+    }
+}
+
+/// Remembers which instruction words of RAM and BIOS have been executed
+#[derive(Clone)]
+pub struct Coverage {
+    enabled: Cell<bool>,
+    regions: [Region; 2],
+}
+
+impl Coverage {
+    pub fn new(ram_size: u32, bios_base: u32, bios_size: u32) -> Self {
+        Self {
+            enabled: Cell::new(false),
+            regions: [
+                Region::new("ram", 0, RAM_WINDOW_SIZE, 0x80000000, ram_size),
+                Region::new("bios", bios_base, bios_size, 0xA0000000 | bios_base, bios_size),
+            ],
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.get()
+    }
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.set(enabled);
+    }
+    pub fn reset(&self) {
+        for region in &self.regions {
+            region.bitmap.iter().for_each(|byte| byte.set(0));
+        }
+    }
+
+    /// Marks the instruction at `pc` as executed
+    pub fn record(&self, pc: u32) {
+        for region in &self.regions {
+            if let Some(word) = region.word(pc) {
+                let byte = &region.bitmap[word as usize / 8];
+                byte.set(byte.get() | 1 << (word % 8));
+                return;
+            }
+        }
+    }
+
+    pub fn executed(&self, addr: u32) -> bool {
+        self.regions.iter().any(|region| region.word(addr).is_some_and(|word| region.executed(word)))
+    }
+
+    /// The RAM bitmap followed by the BIOS one, bit n of byte k stands for
+    /// the word at offset (k * 8 + n) * 4 of its region
+    pub fn bitmap(&self) -> Vec<u8> {
+        self.regions.iter().flat_map(|region| region.bitmap.iter().map(Cell::get)).collect()
+    }
+
+    /// Disassembly of executed code, `+` marks executed instructions and
+    /// `-` the ones of an entered function that never ran
+    pub fn listing(&self, machine: &Machine) -> String {
+        let symbols = machine.symbols.borrow();
+        let mut output = String::new();
+        for region in &self.regions {
+            output += &format!("{}: {} of {} words executed\n", region.name, region.count(), region.words());
+        }
+
+        for region in &self.regions {
+            // executed and total words of every sized function in the region
+            let mut functions = HashMap::new();
+            for sym in symbols.symbols().filter(|sym| sym.size != 0) {
+                let Some(start) = region.word(sym.address) else {
+                    continue;
+                };
+                let end = (start + sym.size / 4).min(region.words());
+                let executed = (start..end).filter(|word| region.executed(*word)).count();
+                if executed != 0 {
+                    functions.insert(sym.address, (executed, end - start));
+                }
+            }
+
+            let mut previous = None;
+            for word in 0..region.words() {
+                let addr = region.display_base + word * 4;
+                let executed = region.executed(word);
+                let function = symbols.resolve(addr)
+                    .and_then(|(sym, offset)| functions.get(&sym.address).map(|stats| (sym, offset, stats)));
+                if !executed && function.is_none() {
+                    continue;
+                }
+                match function {
+                    Some((sym, 0, (executed, total))) => output += &format!("\n{}: {} of {} instructions executed\n", sym.name, executed, total),
+                    _ if previous != word.checked_sub(1) => output += &format!("\n{}:\n", symbols.format(addr)),
+                    _ => (),
+                }
+                let disassembly = match machine.peek::<u32>(addr) {
+                    Some(inst) => disassemble(inst, addr, Some(&symbols)),
+                    None => String::new(),
+                };
+                output += &format!("{} {:08x}  {}\n", if executed { '+' } else { '-' }, addr, disassembly);
+                previous = Some(word);
+            }
+        }
+        output
+    }
+
+    /// Source line coverage in lcov's tracefile format, from ELF line info
+    pub fn lcov(&self, symbols: &SymbolTable) -> String {
+        let mut files: BTreeMap<&str, BTreeMap<u32, bool>> = BTreeMap::new();
+        for line in symbols.lines() {
+            let hit = (line.address..line.end).step_by(4).any(|addr| self.executed(addr));
+            *files.entry(&line.file).or_default().entry(line.line).or_default() |= hit;
+        }
+
+        let mut output = String::new();
+        for (file, lines) in files {
+            output += &format!("TN:\nSF:{}\n", file);
+            for (line, hit) in &lines {
+                output += &format!("DA:{},{}\n", line, *hit as u32);
+            }
+            let hits = lines.values().filter(|hit| **hit).count();
+            output += &format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), hits);
+        }
+        output
+    }
+
+    /// Writes `<prefix>.bitmap`, `<prefix>.lst` and, when there's line info,
+    /// `<prefix>.info`
+    pub fn save(&self, machine: &Machine, prefix: &str) -> Result<()> {
+        std::fs::write(format!("{}.bitmap", prefix), self.bitmap())?;
+        std::fs::write(format!("{}.lst", prefix), self.listing(machine))?;
+        let symbols = machine.symbols.borrow();
+        if !symbols.lines().is_empty() {
+            std::fs::write(format!("{}.info", prefix), self.lcov(&symbols))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{loader::elf::{Elf, ElfLine}, machine::Machine};
+
+    #[test]
+    fn test_coverage() {
+        let machine = Machine::with_program(&[
+            0x10000002, // main: b skip
+            0x00000000, // nop
+            0x24020001, // addiu v0, zero, 1
+            0x24030002, // skip: addiu v1, zero, 2
+            0x1000FFFF, // b .
+            0x00000000, // nop
+        ]);
+        machine.symbols.borrow_mut().insert("main", 0x80010000, 0x18);
+
+        machine.coverage.set_enabled(true);
+        for _ in 0..6 {
+            machine.step();
+        }
+
+        assert!(machine.coverage.executed(0x00010000));
+        assert!(!machine.coverage.executed(0x80010008));
+        assert_eq!(machine.coverage.bitmap()[0x10000 / 32], 0b111011);
+
+        let listing = machine.coverage.listing(&machine);
+        assert!(listing.starts_with("ram: 5 of 524288 words executed\nbios: 0 of 131072 words executed\n"));
+        assert!(listing.contains("\nmain: 5 of 6 instructions executed\n+ 80010000  beq $zero, $zero, 0x8001000c <main+0xc>\n"), "{}", listing);
+        assert!(listing.contains("\n- 80010008  addiu $v0, $zero, 1\n"), "{}", listing);
+
+        let lines = [(0x80010000, 12, 3), (0x80010008, 4, 4), (0x8001000C, 12, 5)];
+        let elf = Elf {
+            entry: 0x80010000,
+            segments: vec![],
+            symbols: vec![],
+            lines: lines.iter().map(|(address, len, line)| ElfLine { address: *address, end: address + len, file: "main.c".to_string(), line: *line }).collect(),
+        };
+        let mut symbols = machine.symbols.borrow_mut();
+        symbols.add_elf(&elf);
+        assert_eq!(machine.coverage.lcov(&symbols), "TN:\nSF:main.c\nDA:3,1\nDA:4,0\nDA:5,1\nLF:3\nLH:2\nend_of_record\n");
+    }
+}
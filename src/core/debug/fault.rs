@@ -0,0 +1,136 @@
+use std::fmt::{Display, Formatter};
+
+use crate::core::{bus::{BusDevice, BusError, io::irq::Interrupt}, machine::Machine, mips::{Coprocessor, cop0::COP0_REGISTERS, disasm::{disassemble, REG_NAMES}, mips::Mips}};
+
+/// Instructions shown before and after PC in a fault report
+const CONTEXT_INSTRUCTIONS: u32 = 8;
+
+/// Something the guest can't recover from, the machine stops for good
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fault {
+    /// A bus error raising its exception would loop, like one fetching the
+    /// exception handler itself
+    Bus(BusError),
+    /// An instruction the emulator doesn't implement
+    Unimplemented { pc: u32, inst: u32 },
+}
+
+impl Display for Fault {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fault::Bus(err) => write!(f, "unrecoverable bus error: {}", err),
+            Fault::Unimplemented { pc, inst } => write!(f, "unimplemented instruction {:08x} at {:#010x}", inst, pc),
+        }
+    }
+}
+
+pub fn registers(cpu: &Mips) -> String {
+    let mut output: String = REG_NAMES.chunks(4).enumerate()
+        .map(|(row, names)| {
+            let columns: Vec<String> = names.iter().enumerate()
+                .map(|(col, name)| format!("{:<4} {:08x}", name, cpu.gpr(row * 4 + col)))
+                .collect();
+            columns.join("  ") + "\n"
+        })
+        .collect();
+    output += &format!("{:<4} {:08x}  {:<4} {:08x}  {:<4} {:08x}\n", "pc", cpu.pc(), "hi", cpu.hi(), "lo", cpu.lo());
+    output
+}
+
+pub fn cop0_registers(cpu: &Mips) -> String {
+    COP0_REGISTERS.iter()
+        .map(|(reg, name)| format!("{:<8} {:08x}\n", name, cpu.cop0.read(*reg)))
+        .collect()
+}
+
+/// Disassembles `inst`, or the word at `addr` when not given, prefixed by
+/// `marker` and the symbol it falls in
+pub fn instruction_line(machine: &Machine, addr: u32, inst: Option<u32>, marker: &str) -> String {
+    let symbols = machine.symbols.borrow();
+    let label = match symbols.resolve(addr) {
+        Some(_) => symbols.format(addr),
+        None => String::new(),
+    };
+    match inst.or_else(|| machine.peek::<u32>(addr)) {
+        Some(inst) => format!("{} {:08x}  {:<24} {}\n", marker, addr, label, disassemble(inst, addr, Some(&symbols))),
+        None => format!("{} {:08x}  {:<24} <unreadable>\n", marker, addr, label),
+    }
+}
+
+/// Machine state for a post-mortem: the fault if any, registers, COP0, the
+/// last executed instructions and the code around the faulting PC
+pub fn report(machine: &Machine, fault: Option<&Fault>) -> String {
+    let cpu = &machine.cpu;
+    let pc = cpu.current_pc();
+    let mut output = String::new();
+    if let Some(fault) = fault {
+        output += &format!("Fault: {}\n", fault);
+    }
+    output += &format!("PC: {}, {} cycles\n", machine.symbols.borrow().format(pc), cpu.cycles());
+    if let Some(err) = cpu.last_bus_error.get() {
+        output += &format!("Last bus error: {}\n", err);
+    }
+    let irq = &machine.io.irq;
+    let pending: Vec<_> = Interrupt::ALL.iter()
+        .filter(|line| (irq.status() & irq.mask()) >> **line as u32 & 1 != 0)
+        .map(|line| line.name())
+        .collect();
+    output += &format!("I_STAT: {:#06x}, I_MASK: {:#06x}, pending: {}\n", irq.status(), irq.mask(), pending.join(" "));
+
+    output += "\nRegisters:\n";
+    output += &registers(cpu);
+    output += "\nCOP0:\n";
+    output += &cop0_registers(cpu);
+
+    output += "\nLast executed instructions:\n";
+    for (addr, inst) in cpu.history() {
+        output += &instruction_line(machine, addr, Some(inst), "  ");
+    }
+
+    output += "\nAround PC:\n";
+    let start = pc.wrapping_sub(CONTEXT_INSTRUCTIONS * 4);
+    for i in 0..CONTEXT_INSTRUCTIONS * 2 + 1 {
+        let addr = start.wrapping_add(i * 4);
+        output += &instruction_line(machine, addr, None, if addr == pc { "=>" } else { "  " });
+    }
+    output
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::{BusErrorKind, Direction}, debug::StopReason, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_fault_report() {
+        let machine = Machine::with_program(&[
+            0x24020001, // addiu v0, zero, 1
+            0x88430000, // lwl v1, 0(v0)
+            0x00000000, // nop
+        ]);
+        machine.symbols.borrow_mut().insert("main", 0x80010000, 12);
+
+        machine.step();
+        let fault = Fault::Unimplemented { pc: 0x80010004, inst: 0x88430000 };
+        assert_eq!(machine.step(), Some(StopReason::Fault(fault)));
+
+        let report = report(&machine, Some(&fault));
+        assert!(report.starts_with("Fault: unimplemented instruction 88430000 at 0x80010004\nPC: main+0x4, 2 cycles\n"), "{}", report);
+        assert!(report.contains("v0   00000001"), "{}", report);
+        assert!(report.contains("\nLast executed instructions:\n   80010000  main                     addiu $v0, $zero, 1\n"), "{}", report);
+        assert!(report.contains("=> 80010004  main+0x4"), "{}", report);
+    }
+
+    #[test]
+    fn test_bus_error_context() {
+        // lh v0, 1(zero) then the exception vector in an unmapped segment
+        let machine = Machine::with_program(&[0x84020001]);
+        machine.step();
+        let err = machine.cpu.last_bus_error.get().unwrap();
+        assert_eq!(err.kind, BusErrorKind::Misaligned);
+        assert_eq!((err.addr, err.size, err.direction, err.device, err.pc), (1, 2, Direction::Read, "ram", 0x80010000));
+        assert_eq!(machine.cpu.cop0.bad_virtual_address.get(), 1);
+        assert_eq!(machine.cpu.pc(), 0x80000080);
+        assert_eq!(err.to_string(), "Misaligned on 2-byte Read at 0x00000001 (ram) by pc 0x80010000");
+    }
+}
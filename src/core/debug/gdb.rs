@@ -0,0 +1,453 @@
+use std::io::{ErrorKind, Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::core::{bus::BusDevice, machine::Machine, mips::{Coprocessor, cop0::{ExceptionsCodes, COP0_REGISTERS}}};
+
+use super::{AddressSpace, StopReason, WatchKind, Watchpoint, fault::Fault};
+
+/// Instructions executed between two checks for a Ctrl-C from GDB
+const POLL_INTERVAL: u64 = 100_000;
+/// Largest packet GDB may send us, as advertised in `qSupported`
+const PACKET_SIZE: u32 = 0x1000;
+/// Largest `m` read, so its hex reply still fits in a packet
+const MAX_MEMORY_READ: u32 = PACKET_SIZE / 2;
+
+// GDB's own signal numbers, not the host ones
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+/*
+GDB's register layout for 32-bit MIPS targets:
+0-31    GPRs
+32      SR
+33      LO
+34      HI
+35      BadVaddr
+36      CAUSE
+37      PC
+38-69   FPRs (none such in PSX)
+70-71   FCSR, FIR */
+const REGISTER_COUNT: usize = 72;
+
+enum Resume {
+    Continue,
+    Step,
+}
+
+/// How a GDB session ended
+#[derive(Debug, PartialEq)]
+pub enum Session {
+    /// GDB detached or went away, the machine is left to run on its own
+    Detached,
+    /// GDB killed the program, the machine must not run any further
+    Killed,
+}
+
+/// Waits for GDB on localhost and serves it until it detaches or disconnects
+pub fn serve(machine: &Machine, port: u16) -> Result<Session> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB on 127.0.0.1:{}", listener.local_addr()?.port());
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(machine, stream).run()
+}
+
+/// GDB remote serial protocol server driving a machine
+pub struct GdbStub<'a> {
+    machine: &'a Machine,
+    stream: TcpStream,
+    last_stop: Option<StopReason>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(machine: &'a Machine, stream: TcpStream) -> Self {
+        Self { machine, stream, last_stop: None }
+    }
+
+    pub fn run(&mut self) -> Result<Session> {
+        self.machine.debug.break_on_exception.set(true);
+        let result = self.serve_packets();
+        self.machine.debug.break_on_exception.set(false);
+        result
+    }
+
+    fn serve_packets(&mut self) -> Result<Session> {
+        while let Some(packet) = self.receive()? {
+            let reply = match packet.as_bytes().first() {
+                Some(b'c') => self.resume(&packet[1..], Resume::Continue)?,
+                Some(b's') => self.resume(&packet[1..], Resume::Step)?,
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(Session::Detached);
+                },
+                Some(b'k') => return Ok(Session::Killed),
+                _ => self.command(&packet),
+            };
+            self.send(&reply)?;
+        }
+        Ok(Session::Detached)
+    }
+
+    fn command(&mut self, packet: &str) -> String {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => Some(stop_reply(self.last_stop)),
+            "g" => Some((0..REGISTER_COUNT).map(|reg| hex_u32(self.read_register(reg))).collect()),
+            "G" => parse_hex_u32s(args).map(|values| {
+                values.iter().enumerate().for_each(|(reg, val)| self.write_register(reg, *val));
+                "OK".to_string()
+            }),
+            "p" => usize::from_str_radix(args, 16).ok().map(|reg| hex_u32(self.read_register(reg))),
+            "P" => args.split_once('=').and_then(|(reg, val)| {
+                let reg = usize::from_str_radix(reg, 16).ok()?;
+                self.write_register(reg, *parse_hex_u32s(val)?.first()?);
+                Some("OK".to_string())
+            }),
+            "m" => parse_addr_len(args)
+                .filter(|(_, len)| *len <= MAX_MEMORY_READ)
+                .and_then(|(addr, len)| self.read_memory(addr, len))
+                .map(|bytes| bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+            "M" => args.split_once(':').and_then(|(range, data)| {
+                let (addr, _) = parse_addr_len(range)?;
+                self.write_memory(addr, &parse_hex_bytes(data)?)?;
+                Some("OK".to_string())
+            }),
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "H" => Some("OK".to_string()),
+            "T" => Some("OK".to_string()),
+            "q" => self.query(args),
+            _ => Some(String::new()),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn query(&self, query: &str) -> Option<String> {
+        let reply = match query.split(':').next() {
+            Some("Supported") => format!("PacketSize={:x}", PACKET_SIZE),
+            Some("Attached") => "1".to_string(),
+            Some("C") => "QC1".to_string(),
+            Some("fThreadInfo") => "m1".to_string(),
+            Some("sThreadInfo") => "l".to_string(),
+            Some("Rcmd") => {
+                let command = String::from_utf8(parse_hex_bytes(query.strip_prefix("Rcmd,")?)?).ok()?;
+                let output = match command.trim() {
+                    "cop0" => self.cop0_dump(),
+                    _ => "Unknown monitor command, try \"cop0\"\n".to_string(),
+                };
+                output.bytes().map(|b| format!("{:02x}", b)).collect()
+            },
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn cop0_dump(&self) -> String {
+        COP0_REGISTERS.iter()
+            .map(|(reg, name)| format!("{:<8} {:08x}\n", name, self.machine.cpu.cop0.read(*reg)))
+            .collect()
+    }
+
+    fn breakpoint(&self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let debug = &self.machine.debug;
+
+        let watch_kind = match kind {
+            // software and hardware breakpoints are the same thing here
+            "0" | "1" => {
+                if insert { debug.add_breakpoint(addr, None) } else { debug.remove_breakpoint(addr); }
+                return Some("OK".to_string());
+            },
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return Some(String::new()),
+        };
+        let watchpoint = Watchpoint { addr, len, kind: watch_kind, space: AddressSpace::Physical };
+        if insert { debug.add_watchpoint(watchpoint) } else { debug.remove_watchpoint(watchpoint); }
+        Some("OK".to_string())
+    }
+
+    fn resume(&mut self, args: &str, mode: Resume) -> Result<String> {
+        if let Ok(addr) = u32::from_str_radix(args, 16) {
+            self.machine.cpu.set_pc(addr);
+        }
+
+        let mut reason = self.machine.single_step();
+        if let (Resume::Continue, StopReason::Step) = (mode, reason) {
+            self.stream.set_nonblocking(true)?;
+            let resumed = loop {
+                if let Some(stop) = self.machine.resume(POLL_INTERVAL) {
+                    break Ok(Some(stop));
+                }
+                let mut byte = [0u8];
+                match self.stream.read(&mut byte) {
+                    Ok(1) if byte[0] == 0x03 => break Ok(None),
+                    Ok(0) => break Ok(None),
+                    Err(err) if err.kind() != ErrorKind::WouldBlock => break Err(err),
+                    _ => (),
+                }
+            };
+            self.stream.set_nonblocking(false)?;
+            match resumed? {
+                Some(stop) => reason = stop,
+                None => {
+                    self.last_stop = None;
+                    return Ok(format!("S{:02x}", SIGINT));
+                }
+            }
+        }
+        self.last_stop = Some(reason);
+        Ok(stop_reply(self.last_stop))
+    }
+
+    fn read_register(&self, reg: usize) -> u32 {
+        let cpu = &self.machine.cpu;
+        match reg {
+            0..=31 => cpu.gpr(reg),
+            32 => cpu.cop0.read(12),
+            33 => cpu.lo(),
+            34 => cpu.hi(),
+            35 => cpu.cop0.read(8),
+            36 => cpu.cop0.read(13),
+            37 => cpu.pc(),
+            _ => 0,
+        }
+    }
+
+    fn write_register(&self, reg: usize, val: u32) {
+        let cpu = &self.machine.cpu;
+        match reg {
+            0..=31 => cpu.set_gpr(reg, val),
+            32 => cpu.write_cop0(self.machine, 12, val),
+            33 => cpu.set_lo(val),
+            34 => cpu.set_hi(val),
+            35 => cpu.cop0.write(8, val),
+            36 => cpu.cop0.write(13, val),
+            37 => cpu.set_pc(val),
+            _ => (),
+        }
+    }
+
+    /// Word sized accesses when possible, I/O registers often accept nothing else
+    fn read_memory(&self, addr: u32, len: u32) -> Option<Vec<u8>> {
+        let machine = self.machine;
+        if addr.is_multiple_of(4) && len.is_multiple_of(4) {
+            (0..len / 4)
+                .map(|i| machine.peek::<u32>(addr.wrapping_add(i * 4)).map(u32::to_le_bytes))
+                .collect::<Option<Vec<_>>>()
+                .map(|words| words.concat())
+        } else {
+            (0..len).map(|i| machine.peek::<u8>(addr.wrapping_add(i))).collect()
+        }
+    }
+
+    fn write_memory(&self, addr: u32, data: &[u8]) -> Option<()> {
+        let machine = self.machine;
+        let poked = if addr.is_multiple_of(4) && data.len().is_multiple_of(4) {
+            data.chunks(4).enumerate().all(|(i, word)| {
+                let word = u32::from_le_bytes(word.try_into().unwrap());
+                machine.poke::<u32>(addr.wrapping_add(i as u32 * 4), word)
+            })
+        } else {
+            data.iter().enumerate().all(|(i, byte)| machine.poke::<u8>(addr.wrapping_add(i as u32), *byte))
+        };
+        poked.then_some(())
+    }
+
+    /// Next packet's payload, acknowledging it, or `None` once GDB hangs up
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            let mut byte = [0u8];
+            // skip acks, interrupts while stopped and garbage up to the packet start
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut payload = vec![];
+            loop {
+                if self.stream.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                payload.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            let actual = payload.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            if expected == Some(actual) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&payload).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, payload: &str) -> Result<()> {
+        let checksum = payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", payload, checksum);
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            let mut ack = [0u8];
+            if self.stream.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn stop_reply(reason: Option<StopReason>) -> String {
+    match reason {
+        None | Some(StopReason::Step) | Some(StopReason::Breakpoint { .. }) => format!("S{:02x}", SIGTRAP),
+        Some(StopReason::Watchpoint { addr, kind, .. }) => {
+            let kind = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{:02x}{}:{:08x};", SIGTRAP, kind, addr)
+        },
+        Some(StopReason::Exception { code, .. }) => {
+            let signal = match code {
+                ExceptionsCodes::AddressReadError | ExceptionsCodes::AddressWriteError => SIGSEGV,
+                ExceptionsCodes::FetchError | ExceptionsCodes::DataError => SIGBUS,
+                ExceptionsCodes::ReservedInstruction | ExceptionsCodes::InvalidCoprocessor => SIGILL,
+                ExceptionsCodes::ArithmeticOverflow => SIGFPE,
+                _ => SIGTRAP,
+            };
+            format!("S{:02x}", signal)
+        },
+        Some(StopReason::UnmappedAccess { .. }) | Some(StopReason::Fault(Fault::Bus(_))) => format!("S{:02x}", SIGBUS),
+        Some(StopReason::Fault(Fault::Unimplemented { .. })) => format!("S{:02x}", SIGILL),
+    }
+}
+
+/// Registers travel in target byte order
+fn hex_u32(val: u32) -> String {
+    format!("{:08x}", val.swap_bytes())
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex_u32s(hex: &str) -> Option<Vec<u32>> {
+    let bytes = parse_hex_bytes(hex)?;
+    Some(bytes.chunks_exact(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect())
+}
+
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u32::from_str_radix(addr, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use crate::core::machine::Machine;
+    use super::{GdbStub, Session};
+
+    /// Sends each packet, acks the reply and collects its payload
+    fn client(port: u16, packets: &[&str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut replies = vec![];
+        for payload in packets {
+            let checksum = payload.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+            stream.write_all(format!("${}#{:02x}", payload, checksum).as_bytes()).unwrap();
+            if *payload == "k" {
+                // kill has no reply
+                break;
+            }
+
+            let mut reply = vec![];
+            let mut byte = [0u8];
+            while byte[0] != b'$' {
+                stream.read_exact(&mut byte).unwrap();
+            }
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            stream.read_exact(&mut [0u8; 2]).unwrap();
+            stream.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(reply).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn test_gdb_session() {
+        let machine = Machine::with_program(&[
+            0x24020001, // addiu v0, zero, 1
+            0x0000000D, // break
+        ]);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || client(port, &[
+            "m80010000,8",
+            "p25",
+            "M80001000,4:78563412",
+            "Z0,80010004,4",
+            "c",
+            "s",
+            "p2",
+            "D",
+        ]));
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(GdbStub::new(&machine, stream).run().unwrap(), Session::Detached);
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies, [
+            "010002240d000000",
+            "00000180",
+            "OK",
+            "OK",
+            "S05",
+            "S05",
+            "01000000",
+            "OK",
+        ]);
+        assert_eq!(machine.cpu.pc(), 0x80000080);
+    }
+
+    #[test]
+    fn test_gdb_kill() {
+        let machine = Machine::with_program(&[0x0000000D]);
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = std::thread::spawn(move || client(port, &[
+            "m80010000,800",
+            "m80010000,801",
+            "k",
+        ]));
+        let (stream, _) = listener.accept().unwrap();
+        assert_eq!(GdbStub::new(&machine, stream).run().unwrap(), Session::Killed);
+
+        let replies = client.join().unwrap();
+        assert_eq!(replies[0].len(), 0x1000);
+        assert_eq!(replies[1], "E01");
+        assert_eq!(machine.cpu.pc(), 0x80010000);
+    }
+}
@@ -0,0 +1,239 @@
+pub mod symbols;
+pub mod condition;
+pub mod gdb;
+pub mod repl;
+pub mod profiler;
+pub mod coverage;
+pub mod fault;
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use crate::core::{bus::policy::BusRegion, machine::Machine, mips::cop0::ExceptionsCodes};
+use condition::Condition;
+use fault::Fault;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Why the machine handed control back to whoever is driving it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    /// A single step completed
+    Step,
+    Breakpoint { pc: u32 },
+    /// `old` and `new` are the value before and after a write, or the read one
+    Watchpoint { pc: u32, addr: u32, kind: WatchKind, write: bool, size: u32, old: u32, new: u32 },
+    Exception { pc: u32, code: ExceptionsCodes },
+    /// An access hit a region whose policy is `AccessPolicy::Halt`
+    UnmappedAccess { pc: u32, addr: u32, size: u32, write: bool, region: BusRegion },
+    /// The machine cannot go on, see `fault::report` for the details
+    Fault(Fault),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressSpace {
+    /// Matches every mirror of the range, KUSEG, KSEG0 and KSEG1 alike, and
+    /// the mirrors of RAM in its 8MB window
+    Physical,
+    /// Matches only accesses made through the exact addresses
+    Virtual,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+    pub space: AddressSpace,
+}
+
+impl Watchpoint {
+    /// `physical` folds addresses onto what they land on, RAM mirrors included
+    fn hits(&self, addr: u32, size: u32, write: bool, physical: &impl Fn(u32) -> u32) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        let (start, addr) = match self.space {
+            AddressSpace::Physical => (physical(self.addr), physical(addr)),
+            AddressSpace::Virtual => (self.addr, addr),
+        };
+        kind_matches && addr < start.wrapping_add(self.len) && start < addr.wrapping_add(size)
+    }
+}
+
+/// Breakpoints, watchpoints and the pending stop event of a machine
+#[derive(Clone, Default)]
+pub struct Debugger {
+    breakpoints: RefCell<BTreeMap<u32, Option<Condition>>>,
+    watchpoints: RefCell<Vec<Watchpoint>>,
+    stop: Cell<Option<StopReason>>,
+    /// Stop on faults, that is every exception but interrupts and syscalls
+    pub break_on_exception: Cell<bool>,
+}
+
+impl Debugger {
+    /// Adds or replaces the breakpoint at `addr`
+    pub fn add_breakpoint(&self, addr: u32, condition: Option<Condition>) {
+        self.breakpoints.borrow_mut().insert(addr, condition);
+    }
+    pub fn remove_breakpoint(&self, addr: u32) -> bool {
+        self.breakpoints.borrow_mut().remove(&addr).is_some()
+    }
+    pub fn breakpoints(&self) -> Vec<(u32, Option<Condition>)> {
+        self.breakpoints.borrow().iter().map(|(addr, condition)| (*addr, *condition)).collect()
+    }
+    /// Whether there's a breakpoint at `pc` with its condition met
+    pub fn breakpoint_hit(&self, machine: &Machine, pc: u32) -> bool {
+        let breakpoints = self.breakpoints.borrow();
+        if breakpoints.is_empty() {
+            return false;
+        }
+        match breakpoints.get(&pc) {
+            Some(Some(condition)) => condition.eval(machine),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    pub fn add_watchpoint(&self, watchpoint: Watchpoint) {
+        self.watchpoints.borrow_mut().push(watchpoint);
+    }
+    pub fn remove_watchpoint(&self, watchpoint: Watchpoint) -> bool {
+        let mut watchpoints = self.watchpoints.borrow_mut();
+        let before = watchpoints.len();
+        watchpoints.retain(|wp| *wp != watchpoint);
+        before != watchpoints.len()
+    }
+    /// Removes every watchpoint starting at `addr`
+    pub fn remove_watchpoints_at(&self, addr: u32) -> bool {
+        let mut watchpoints = self.watchpoints.borrow_mut();
+        let before = watchpoints.len();
+        watchpoints.retain(|wp| wp.addr != addr);
+        before != watchpoints.len()
+    }
+    pub fn watchpoints(&self) -> Vec<Watchpoint> {
+        self.watchpoints.borrow().clone()
+    }
+
+    /// Kind of the watchpoint covering a bus access, checked by the bus on
+    /// every data access (CPU and DMA) before it gathers the values to report
+    pub fn watchpoint_at(&self, addr: u32, size: u32, write: bool, physical: impl Fn(u32) -> u32) -> Option<WatchKind> {
+        let watchpoints = self.watchpoints.borrow();
+        if watchpoints.is_empty() {
+            return None;
+        }
+        watchpoints.iter().find(|wp| wp.hits(addr, size, write, &physical)).map(|wp| wp.kind)
+    }
+
+    /// Called by the CPU once it vectored to an exception handler
+    pub fn exception_raised(&self, code: ExceptionsCodes, pc: u32) {
+        let is_fault = !matches!(code, ExceptionsCodes::Interrupt | ExceptionsCodes::Syscall);
+        if is_fault && self.break_on_exception.get() {
+            self.report(StopReason::Exception { pc, code });
+        }
+    }
+
+    /// Requests a stop at the end of the current step, the first event wins
+    pub fn report(&self, reason: StopReason) {
+        if self.stop.get().is_none() {
+            self.stop.set(Some(reason));
+        }
+    }
+
+    pub fn take_stop(&self) -> Option<StopReason> {
+        self.stop.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{machine::Machine, mips::cop0::ExceptionsCodes};
+    use super::*;
+
+    fn machine_with_program() -> Machine {
+        Machine::with_program(&[
+            0x24020001, // addiu v0, zero, 1
+            0x08004004, // j 0x80010010
+            0x24030002, // addiu v1, zero, 2
+            0x24040003, // addiu a0, zero, 3
+            0x24050004, // addiu a1, zero, 4
+            0x0000000D, // break
+        ])
+    }
+
+    #[test]
+    fn test_single_step_covers_delay_slot() {
+        let machine = machine_with_program();
+        assert_eq!(machine.single_step(), StopReason::Step);
+        assert_eq!(machine.cpu.pc(), 0x80010004);
+        assert_eq!(machine.single_step(), StopReason::Step);
+        assert_eq!(machine.cpu.pc(), 0x80010010);
+        assert_eq!(machine.cpu.gpr(3), 2);
+        assert_eq!(machine.cpu.gpr(4), 0);
+    }
+
+    #[test]
+    fn test_breakpoints_and_exceptions() {
+        let machine = machine_with_program();
+        machine.debug.break_on_exception.set(true);
+        machine.debug.add_breakpoint(0x80010010, None);
+
+        assert_eq!(machine.resume(100), Some(StopReason::Breakpoint { pc: 0x80010010 }));
+        assert_eq!(machine.resume(100), Some(StopReason::Breakpoint { pc: 0x80010010 }));
+
+        machine.single_step();
+        assert_eq!(machine.single_step(), StopReason::Exception { pc: 0x80010014, code: ExceptionsCodes::Breakpoint });
+        assert_eq!(machine.cpu.pc(), 0x80000080);
+        assert_eq!(machine.cpu.cop0.return_address_from_trap.get(), 0x80010014);
+    }
+
+    #[test]
+    fn test_conditional_breakpoints() {
+        let machine = machine_with_program();
+        let symbols = machine.symbols.borrow();
+        machine.debug.add_breakpoint(0x80010004, Condition::parse("$v0 == 2", &symbols));
+        machine.debug.add_breakpoint(0x80010010, Condition::parse("v1 != r0", &symbols));
+
+        assert_eq!(machine.resume(100), Some(StopReason::Breakpoint { pc: 0x80010010 }));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        use crate::core::bus::BusDevice;
+        let machine = machine_with_program();
+        machine.debug.add_watchpoint(Watchpoint { addr: 0x80001000, len: 4, kind: WatchKind::Write, space: AddressSpace::Physical });
+        machine.debug.add_watchpoint(Watchpoint { addr: 0x80002000, len: 4, kind: WatchKind::Access, space: AddressSpace::Virtual });
+        machine.write::<u32>(0x80001000, 0x12345678).unwrap();
+        machine.debug.take_stop();
+
+        machine.read::<u32>(0x80001000).unwrap();
+        assert_eq!(machine.debug.take_stop(), None);
+        machine.write::<u16>(0x00001002, 7).unwrap();
+        assert_eq!(machine.debug.take_stop(), Some(StopReason::Watchpoint {
+            pc: machine.cpu.current_pc(), addr: 0x00001002, kind: WatchKind::Write, write: true, size: 2, old: 0x1234, new: 7,
+        }));
+
+        // RAM mirrors are the same physical memory
+        machine.write::<u32>(0x80601000, 9).unwrap();
+        assert!(matches!(machine.debug.take_stop(), Some(StopReason::Watchpoint { addr: 0x80601000, old: 0x00075678, new: 9, .. })));
+        // writes swallowed by isolated caches don't land
+        machine.cpu.cop0.system_status.set(1 << 16);
+        machine.remap();
+        machine.write::<u32>(0x80001000, 10).unwrap();
+        assert_eq!(machine.debug.take_stop(), None);
+        machine.cpu.cop0.system_status.set(0);
+        machine.remap();
+
+        machine.read::<u8>(0xA0002000).unwrap();
+        assert_eq!(machine.debug.take_stop(), None);
+        machine.read::<u8>(0x80002003).unwrap();
+        assert!(matches!(machine.debug.take_stop(), Some(StopReason::Watchpoint { kind: WatchKind::Access, write: false, size: 1, .. })));
+    }
+}
@@ -0,0 +1,274 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use crate::core::{bus::BusDevice, machine::Machine, mips::cop0::ExceptionsCodes};
+
+use super::symbols::SymbolTable;
+
+/// Function entry address, plus the exception for handler frames
+type FrameKey = (u32, Option<ExceptionsCodes>);
+
+#[derive(Clone)]
+struct Frame {
+    key: FrameKey,
+    /// Address that returns from this frame, handlers return with rfe
+    return_address: Option<u32>,
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct FunctionStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Clone, Default)]
+struct ProfilerState {
+    /// One call stack per nesting level: the interrupted code, then every
+    /// exception handler running on top of it
+    contexts: Vec<Vec<Frame>>,
+    /// Return address of a call whose delay slot is executing
+    call: Option<u32>,
+    /// A register jump is in flight, might be a return
+    returning: bool,
+    /// Handler frame of an exception raised by the current instruction
+    exception: Option<Frame>,
+    /// Cycles not yet attributed to the current stack
+    pending: u64,
+    functions: HashMap<FrameKey, FunctionStats>,
+    folded: HashMap<Vec<FrameKey>, u64>,
+    total: u64,
+    in_exceptions: u64,
+}
+
+/// Attributes emulated cycles to functions by tracking calls (jal, jalr,
+/// bltzal, bgezal) and returns (register jumps to a return address).
+/// Exception handlers get their own stacks, so their time isn't charged
+/// to whatever code they interrupted.
+#[derive(Clone, Default)]
+pub struct Profiler {
+    enabled: Cell<bool>,
+    state: RefCell<ProfilerState>,
+}
+
+impl ProfilerState {
+    fn flush(&mut self) {
+        if self.pending == 0 {
+            return;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let Some(stack) = self.contexts.last() else {
+            return;
+        };
+        let path: Vec<FrameKey> = stack.iter().map(|frame| frame.key).collect();
+        for (i, key) in path.iter().enumerate() {
+            let stats = self.functions.entry(*key).or_default();
+            // recursion shouldn't count the same cycles twice
+            if !path[..i].contains(key) {
+                stats.inclusive += pending;
+            }
+            if i == path.len() - 1 {
+                stats.exclusive += pending;
+            }
+        }
+        if stack[0].key.1.is_some() {
+            self.in_exceptions += pending;
+        }
+        self.total += pending;
+        *self.folded.entry(path).or_default() += pending;
+    }
+
+    fn push(&mut self, key: FrameKey, return_address: Option<u32>) {
+        self.flush();
+        self.functions.entry(key).or_default().calls += 1;
+        if let Some(stack) = self.contexts.last_mut() {
+            stack.push(Frame { key, return_address });
+        }
+    }
+
+    /// Unwinds to the caller of the frame returning to `pc`, if any
+    fn returned_to(&mut self, pc: u32) {
+        let Some(stack) = self.contexts.last() else {
+            return;
+        };
+        let Some(depth) = stack.iter().rposition(|frame| frame.return_address == Some(pc)) else {
+            return;
+        };
+        self.flush();
+        let nested = self.contexts.len() > 1;
+        let stack = self.contexts.last_mut().unwrap();
+        stack.truncate(depth);
+        if stack.is_empty() {
+            if nested {
+                self.contexts.pop();
+            } else {
+                // returned from the function profiling started in
+                stack.push(Frame { key: (pc, None), return_address: None });
+            }
+        }
+    }
+}
+
+impl Profiler {
+    pub fn enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    /// Starts attributing cycles, with the code at `pc` as the root frame
+    pub fn enable(&self, pc: u32) {
+        let mut state = self.state.borrow_mut();
+        if state.contexts.is_empty() {
+            state.functions.entry((pc, None)).or_default().calls += 1;
+            state.contexts.push(vec![Frame { key: (pc, None), return_address: None }]);
+        }
+        self.enabled.set(true);
+    }
+
+    pub fn disable(&self) {
+        self.state.borrow_mut().flush();
+        self.enabled.set(false);
+    }
+
+    pub fn reset(&self) {
+        *self.state.borrow_mut() = ProfilerState::default();
+        self.enabled.set(false);
+    }
+
+    /// Called after each step with the instruction executed at `pc`
+    pub fn record(&self, machine: &Machine, pc: u32, cycles: u64) {
+        let mut state = self.state.borrow_mut();
+        state.pending += cycles;
+        let next_pc = machine.cpu.pc();
+
+        // the faulting instruction is charged to the code that ran it
+        if let Some(frame) = state.exception.take() {
+            state.flush();
+            state.call = None;
+            state.returning = false;
+            state.functions.entry(frame.key).or_default().calls += 1;
+            state.contexts.push(vec![frame]);
+            return;
+        }
+
+        // the delay slot of a call or register jump just ran
+        if let Some(return_address) = state.call.take() {
+            if next_pc != return_address {
+                state.push((next_pc, None), Some(return_address));
+            }
+        } else if std::mem::take(&mut state.returning) {
+            state.returned_to(next_pc);
+        }
+
+        let Some(inst) = machine.peek::<u32>(pc) else {
+            return;
+        };
+        match (inst >> 26, inst & 0x3F) {
+            // jal, jalr, bltzal, bgezal
+            (0x03, _) | (0x00, 0x09) => state.call = Some(pc.wrapping_add(8)),
+            (0x01, _) if (inst >> 17) & 0xF == 0x8 => state.call = Some(pc.wrapping_add(8)),
+            // jr
+            (0x00, 0x08) => state.returning = true,
+            // rfe, the handler is done
+            (0x10, 0x10) if state.contexts.len() > 1 => {
+                state.flush();
+                state.contexts.pop();
+            }
+            _ => (),
+        }
+    }
+
+    /// Called by the CPU when it enters an exception handler
+    pub fn exception_raised(&self, code: ExceptionsCodes, handler: u32) {
+        if self.enabled() {
+            self.state.borrow_mut().exception = Some(Frame { key: (handler, Some(code)), return_address: None });
+        }
+    }
+
+    pub fn stats(&self) -> Vec<(FrameKey, FunctionStats)> {
+        let mut state = self.state.borrow_mut();
+        state.flush();
+        let mut stats: Vec<_> = state.functions.iter().map(|(key, stats)| (*key, *stats)).collect();
+        stats.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.0.cmp(&b.0.0)));
+        stats
+    }
+
+    /// Per-function table sorted by inclusive cycles
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        let mut output = format!("{:>12} {:>12} {:>8}  function\n", "inclusive", "exclusive", "calls");
+        for (key, stats) in self.stats() {
+            output += &format!("{:>12} {:>12} {:>8}  {}\n", stats.inclusive, stats.exclusive, stats.calls, frame_name(key, symbols));
+        }
+        let state = self.state.borrow();
+        output += &format!("{} cycles, {} in exception handlers\n", state.total, state.in_exceptions);
+        output
+    }
+
+    /// Folded stacks, one `caller;callee cycles` line per stack, as consumed
+    /// by flamegraph.pl or inferno
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut state = self.state.borrow_mut();
+        state.flush();
+        let mut lines: Vec<String> = state.folded.iter()
+            .map(|(path, cycles)| {
+                let names: Vec<String> = path.iter().map(|key| frame_name(*key, symbols)).collect();
+                format!("{} {}", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+}
+
+fn frame_name((entry, exception): FrameKey, symbols: &SymbolTable) -> String {
+    let name = match symbols.resolve(entry) {
+        Some((sym, _)) => sym.name.clone(),
+        None => format!("{:#010x}", entry),
+    };
+    match exception {
+        Some(code) => format!("[{:?}] {}", code, name),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::machine::Machine;
+
+    #[test]
+    fn test_profile_calls() {
+        let machine = Machine::with_program(&[
+            0x0C004008, // main: jal leaf
+            0x00000000, // nop
+            0x0C004008, // jal leaf
+            0x00000000, // nop
+            0x0000000C, // syscall
+            0x00000000, // nop
+            0x1000FFFF, // b .
+            0x00000000, // nop
+            0x03E00008, // leaf: jr ra
+            0x24030001, // addiu v1, zero, 1
+        ]);
+        // exception handler: jr k0, rfe
+        machine.ram.load(0x80, &[0x08, 0x00, 0x40, 0x03, 0x10, 0x00, 0x00, 0x42]).unwrap();
+        {
+            let mut symbols = machine.symbols.borrow_mut();
+            symbols.insert("main", 0x80010000, 0x20);
+            symbols.insert("leaf", 0x80010020, 8);
+            symbols.insert("handler", 0x80000080, 8);
+        }
+
+        machine.profiler.enable(machine.cpu.pc());
+        for _ in 0..12 {
+            machine.step();
+        }
+        machine.profiler.disable();
+
+        let symbols = machine.symbols.borrow();
+        let folded = machine.profiler.folded(&symbols);
+        assert_eq!(folded, "[Syscall] handler 2\nmain 6\nmain;leaf 4\n");
+        let report = machine.profiler.report(&symbols);
+        assert!(report.contains("          10            6        1  main\n"), "{}", report);
+        assert!(report.contains("           4            4        2  leaf\n"), "{}", report);
+        assert!(report.ends_with("12 cycles, 2 in exception handlers\n"));
+    }
+}
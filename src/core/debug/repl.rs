@@ -0,0 +1,506 @@
+use std::{io::{BufRead, Result, Write}, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::core::{bus::{BusDevice, policy::{AccessPolicy, BusRegion}}, machine::Machine, mips::{Coprocessor, gte::GTE_REGISTER_NAMES, mips::{REG_RA, REG_SP}}};
+
+use super::{AddressSpace, StopReason, WatchKind, Watchpoint, condition::{Condition, parse_number}, fault};
+
+/// How far above $sp `bt` looks for return addresses
+const BACKTRACE_SCAN_SIZE: u32 = 0x400;
+/// Instructions executed per `resume` call while continuing, Ctrl-C is
+/// checked between batches
+const CONTINUE_BATCH: u64 = 1_000_000;
+
+/// Set by Ctrl-C, stops the machine at the end of the current batch
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+const HELP: &str = "\
+break [<addr|symbol> [if <lhs> <op> <rhs>]]   set a breakpoint, or list them
+delete <addr|symbol>                           remove a breakpoint
+watch[/r|/w|/a][v] [<addr|symbol> [len]]       watch reads, writes or any access to a
+                                               physical range (virtual with v), or list
+unwatch <addr|symbol>                          remove the watchpoints at an address
+step [n], next, finish, continue               run the machine, Ctrl-C stops it
+regs, cop0, gte                                dump registers
+x/<count><b|h|w> <addr|symbol>                 dump memory
+poke[/b|/h|/w] <addr|symbol> <value>           write memory
+disas [addr|symbol] [count]                    disassemble
+bt                                             heuristic backtrace
+unmapped [<region> error|log|open|halt]        set or list unmapped access policies
+devices, detach <name>                         list devices, remove an attached one
+events                                         list scheduled device events
+gpu, vram <file>                               show the GPU state, write VRAM as a PPM image
+profile on|off|reset|report                    control the function profiler
+profile folded <file>                          write folded stacks for flamegraphs
+coverage on|off|reset                          control code coverage
+coverage save <prefix>                         write <prefix>.bitmap, .lst and .info
+report [file]                                  print or write a machine state report
+quit\n";
+
+/// Reads commands from stdin until `quit` or EOF, an empty line repeats the
+/// last command.
+pub fn run(machine: &Machine) -> Result<()> {
+    catch_interrupts();
+    let mut repl = Repl::new(machine);
+    let stdin = std::io::stdin();
+    let mut last_command = String::new();
+    loop {
+        print!("(bs) ");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = match line.trim() {
+            "" => last_command.clone(),
+            line => line.to_string(),
+        };
+        if line == "quit" || line == "q" {
+            return Ok(());
+        }
+        match repl.execute(&line) {
+            Ok(output) => print!("{}", output),
+            Err(err) => println!("{}", err),
+        }
+        last_command = line;
+    }
+}
+
+/// Makes Ctrl-C set `INTERRUPTED` instead of killing the process
+#[cfg(unix)]
+fn catch_interrupts() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    extern "C" fn interrupted(_signum: i32) {
+        INTERRUPTED.store(true, Ordering::Relaxed);
+    }
+    // the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, interrupted);
+    }
+}
+
+#[cfg(not(unix))]
+fn catch_interrupts() {}
+
+pub struct Repl<'a> {
+    machine: &'a Machine,
+    /// Stops running commands when set, Ctrl-C's `INTERRUPTED`
+    interrupt: &'a AtomicBool,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(machine: &'a Machine) -> Self {
+        Self { machine, interrupt: &INTERRUPTED }
+    }
+
+    /// Runs one command line, returning what should be printed
+    pub fn execute(&mut self, line: &str) -> std::result::Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("");
+        let args: Vec<&str> = words.collect();
+
+        match (command, args.as_slice()) {
+            ("break" | "b", []) => Ok(self.list_breakpoints()),
+            ("break" | "b", [location, rest @ ..]) => {
+                let addr = self.location(location)?;
+                let condition = match rest {
+                    [] => None,
+                    ["if", condition @ ..] => {
+                        let condition = condition.join(" ");
+                        Some(Condition::parse(&condition, &self.machine.symbols.borrow())
+                            .ok_or(format!("Bad condition \"{}\"", condition))?)
+                    },
+                    _ => return Err("Usage: break <addr|symbol> [if <condition>]".to_string()),
+                };
+                self.machine.debug.add_breakpoint(addr, condition);
+                Ok(format!("Breakpoint at {}\n", self.symbolize(addr)))
+            },
+            ("delete" | "d", [location]) => {
+                let addr = self.location(location)?;
+                match self.machine.debug.remove_breakpoint(addr) {
+                    true => Ok(String::new()),
+                    false => Err(format!("No breakpoint at {}", self.symbolize(addr))),
+                }
+            },
+            ("watch", []) => Ok(self.machine.debug.watchpoints().iter()
+                .map(|wp| format!("{:08x} {:?} {} bytes {:?}\n", wp.addr, wp.kind, wp.len, wp.space))
+                .collect()),
+            (command, [location, rest @ ..]) if command.starts_with("watch") && rest.len() <= 1 => {
+                let flags = command.trim_start_matches("watch").trim_start_matches('/');
+                let kind = match flags.trim_end_matches('v') {
+                    "r" => WatchKind::Read,
+                    "" | "w" => WatchKind::Write,
+                    "a" => WatchKind::Access,
+                    _ => return Err("Usage: watch[/r|/w|/a][v] <addr|symbol> [len]".to_string()),
+                };
+                let space = if flags.ends_with('v') { AddressSpace::Virtual } else { AddressSpace::Physical };
+                let addr = self.location(location)?;
+                let len = match rest.first() {
+                    Some(len) => parse_number(len).ok_or("Bad length")?,
+                    None => 4,
+                };
+                self.machine.debug.add_watchpoint(Watchpoint { addr, len, kind, space });
+                Ok(format!("{:?} watchpoint on {} bytes at {}\n", kind, len, self.symbolize(addr)))
+            },
+            ("unwatch", [location]) => {
+                let addr = self.location(location)?;
+                match self.machine.debug.remove_watchpoints_at(addr) {
+                    true => Ok(String::new()),
+                    false => Err(format!("No watchpoint at {}", self.symbolize(addr))),
+                }
+            },
+            ("step" | "s", []) => Ok(self.stopped(self.machine.single_step())),
+            ("step" | "s", [count]) => {
+                let count = parse_number(count).ok_or("Bad step count")?;
+                self.interrupt.store(false, Ordering::Relaxed);
+                let mut reason = StopReason::Step;
+                for _ in 0..count {
+                    reason = self.machine.single_step();
+                    if reason != StopReason::Step {
+                        break;
+                    }
+                    if self.interrupt.swap(false, Ordering::Relaxed) {
+                        return Ok(format!("Interrupted\n{}", self.stopped(reason)));
+                    }
+                }
+                Ok(self.stopped(reason))
+            },
+            ("next" | "n", []) => {
+                let pc = self.machine.cpu.pc();
+                match self.peek_u32(pc) {
+                    Some(inst) if is_call(inst) => Ok(self.run_to(pc.wrapping_add(8))),
+                    _ => Ok(self.stopped(self.machine.single_step())),
+                }
+            },
+            ("finish", []) => Ok(self.run_to(self.machine.cpu.gpr(REG_RA))),
+            ("continue" | "c", []) => Ok(self.run(|_| false)),
+            ("regs", []) => Ok(fault::registers(&self.machine.cpu)),
+            ("cop0", []) => Ok(fault::cop0_registers(&self.machine.cpu)),
+            ("report", []) => Ok(fault::report(self.machine, None)),
+            ("report", [path]) => std::fs::write(path, fault::report(self.machine, None))
+                .map(|_| format!("Report written to {}\n", path))
+                .map_err(|err| format!("Cannot write {}: {}", path, err)),
+            ("gte", []) => Ok(GTE_REGISTER_NAMES.chunks(4).enumerate()
+                .map(|(row, names)| {
+                    let columns: Vec<String> = names.iter().enumerate()
+                        .map(|(col, name)| format!("{:<8} {:08x}", name, self.machine.cpu.cop2.read((row * 4 + col) as u8)))
+                        .collect();
+                    columns.join("  ") + "\n"
+                })
+                .collect()),
+            ("disas", args) if args.len() <= 2 => {
+                let addr = match args.first() {
+                    Some(location) => self.location(location)?,
+                    None => self.machine.cpu.pc(),
+                };
+                let count = match args.get(1) {
+                    Some(count) => parse_number(count).ok_or("Bad instruction count")?,
+                    None => 8,
+                };
+                Ok((0..count).map(|i| self.disassemble_line(addr.wrapping_add(i * 4))).collect())
+            },
+            ("bt", []) => Ok(self.backtrace()),
+            ("unmapped", []) => Ok(self.machine.bus_policy.all().iter()
+                .map(|(region, policy)| format!("{:<10} {:?}\n", region.name(), policy))
+                .collect()),
+            ("unmapped", [region, policy]) => {
+                let region = BusRegion::parse(region).ok_or(format!("Unknown region \"{}\"", region))?;
+                let policy = AccessPolicy::parse(policy).ok_or(format!("Unknown policy \"{}\"", policy))?;
+                self.machine.bus_policy.set(region, policy);
+                Ok(String::new())
+            },
+            ("devices", []) => {
+                let devices = &self.machine.devices;
+                Ok(devices.builtin().iter().copied().chain(devices.list())
+                    .map(|(name, base, size)| format!("{:08x}-{:08x} {}\n", base, base + size - 1, name))
+                    .collect())
+            },
+            ("events", []) => {
+                let now = self.machine.cpu.cycles();
+                Ok(self.machine.scheduler.pending().iter()
+                    .map(|(cycle, event)| format!("{:>10} (+{}) {:?}\n", cycle, cycle.saturating_sub(now), event))
+                    .collect())
+            },
+            ("gpu", []) => {
+                let gpu = &self.machine.io.gpu;
+                let (start, horizontal, vertical) = gpu.display_area();
+                Ok(format!(
+                    "GPUSTAT  {:08x}\ndisplay  x={} y={} h={:03x}-{:03x} v={:03x}-{:03x}\nbeam     line {} frame {}\n",
+                    gpu.status(), start & 0x3FF, start >> 10,
+                    horizontal & 0xFFF, horizontal >> 12, vertical & 0x3FF, vertical >> 10,
+                    gpu.raster.line(), gpu.raster.frames(),
+                ))
+            },
+            ("vram", [path]) => std::fs::write(path, self.machine.io.gpu.vram.to_ppm())
+                .map(|_| format!("VRAM written to {}\n", path))
+                .map_err(|err| format!("Cannot write {}: {}", path, err)),
+            ("detach", [name]) => match self.machine.devices.detach(name) {
+                true => Ok(String::new()),
+                false => Err(format!("No attached device \"{}\"", name)),
+            },
+            ("profile", ["on"]) => {
+                self.machine.profiler.enable(self.machine.cpu.pc());
+                Ok(String::new())
+            },
+            ("profile", ["off"]) => {
+                self.machine.profiler.disable();
+                Ok(String::new())
+            },
+            ("profile", ["reset"]) => {
+                self.machine.profiler.reset();
+                Ok(String::new())
+            },
+            ("profile", ["report"]) => Ok(self.machine.profiler.report(&self.machine.symbols.borrow())),
+            ("coverage", ["on" | "off"]) => {
+                self.machine.coverage.set_enabled(args[0] == "on");
+                Ok(String::new())
+            },
+            ("coverage", ["reset"]) => {
+                self.machine.coverage.reset();
+                Ok(String::new())
+            },
+            ("coverage", ["save", prefix]) => {
+                self.machine.coverage.save(self.machine, prefix).map_err(|err| format!("Cannot write {}: {}", prefix, err))?;
+                Ok(String::new())
+            },
+            ("profile", ["folded", path]) => {
+                let folded = self.machine.profiler.folded(&self.machine.symbols.borrow());
+                std::fs::write(path, folded).map_err(|err| format!("Cannot write {}: {}", path, err))?;
+                Ok(String::new())
+            },
+            ("help" | "h", []) => Ok(HELP.to_string()),
+            (command, [location]) if command.starts_with("x/") => {
+                let format = &command[2..];
+                let unit = format.chars().last().filter(|c| c.is_ascii_alphabetic()).unwrap_or('w');
+                let count = match format.trim_end_matches(unit) {
+                    "" => 1,
+                    count => parse_number(count).ok_or("Bad count")?,
+                };
+                let addr = self.location(location)?;
+                self.examine(addr, count, unit)
+            },
+            (command, [location, value]) if command == "poke" || command.starts_with("poke/") => {
+                let addr = self.location(location)?;
+                let value = parse_number(value).ok_or("Bad value")?;
+                let machine = self.machine;
+                let poked = match command {
+                    "poke/b" => machine.poke::<u8>(addr, value as u8),
+                    "poke/h" => machine.poke::<u16>(addr, value as u16),
+                    "poke" | "poke/w" => machine.poke::<u32>(addr, value),
+                    _ => return Err("Unknown size, use poke/b, poke/h or poke/w".to_string()),
+                };
+                match poked {
+                    true => Ok(String::new()),
+                    false => Err(format!("Cannot write {:#010x}", addr)),
+                }
+            },
+            _ => Err(format!("Unknown command \"{}\", try \"help\"", line)),
+        }
+    }
+
+    /// Runs until `target` is reached with the stack unwound at least back
+    /// to the current $sp, so recursion doesn't stop early.
+    fn run_to(&self, target: u32) -> String {
+        let sp = self.machine.cpu.gpr(REG_SP);
+        self.run(|machine| machine.cpu.pc() == target && machine.cpu.gpr(REG_SP) >= sp)
+    }
+
+    /// Steps, then runs a batch at a time until a debug event, `done` holds
+    /// or Ctrl-C, returning what to print
+    fn run(&self, done: impl Fn(&Machine) -> bool) -> String {
+        self.interrupt.store(false, Ordering::Relaxed);
+        let mut reason = self.machine.single_step();
+        while reason == StopReason::Step && !done(self.machine) {
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                return format!("Interrupted\n{}", self.stopped(reason));
+            }
+            if let Some(stop) = self.machine.resume_until(CONTINUE_BATCH, &done) {
+                reason = stop;
+            }
+        }
+        self.stopped(reason)
+    }
+
+    fn stopped(&self, reason: StopReason) -> String {
+        let header = match reason {
+            StopReason::Step => String::new(),
+            StopReason::Breakpoint { pc } => format!("Breakpoint at {}\n", self.symbolize(pc)),
+            StopReason::Watchpoint { pc, addr, kind, write: true, size, old, new } =>
+                format!("{:?} watchpoint hit by {}: {} byte write at {:#010x}, {:#x} -> {:#x}\n",
+                    kind, self.symbolize(pc), size, addr, old, new),
+            StopReason::Watchpoint { pc, addr, kind, write: false, size, new, .. } =>
+                format!("{:?} watchpoint hit by {}: {} byte read at {:#010x}, {:#x}\n",
+                    kind, self.symbolize(pc), size, addr, new),
+            StopReason::Exception { pc, code } =>
+                format!("Exception {:?} raised by {}\n", code, self.symbolize(pc)),
+            StopReason::UnmappedAccess { pc, addr, size, write, region } =>
+                format!("{} byte {} {} {:#010x} by {}\n", size, if write { "write to" } else { "read from" },
+                    region.name(), addr, self.symbolize(pc)),
+            StopReason::Fault(fault) => format!("{}\n", fault),
+        };
+        header + &self.disassemble_line(self.machine.cpu.pc())
+    }
+
+    fn list_breakpoints(&self) -> String {
+        self.machine.debug.breakpoints().iter()
+            .map(|(addr, condition)| match condition {
+                Some(condition) => format!("{:08x} {} if {}\n", addr, self.symbolize(*addr), condition),
+                None => format!("{:08x} {}\n", addr, self.symbolize(*addr)),
+            })
+            .collect()
+    }
+
+    fn examine(&self, addr: u32, count: u32, unit: char) -> std::result::Result<String, String> {
+        let (size, per_row) = match unit {
+            'b' => (1, 16),
+            'h' => (2, 8),
+            'w' => (4, 4),
+            _ => return Err(format!("Bad unit '{}', use b, h or w", unit)),
+        };
+        let machine = self.machine;
+        let mut output = String::new();
+        for i in 0..count {
+            let at = addr.wrapping_add(i * size);
+            if i % per_row == 0 {
+                if i != 0 {
+                    output.push('\n');
+                }
+                output += &format!("{:08x}:", at);
+            }
+            let value = match size {
+                1 => machine.peek::<u8>(at).map(|val| format!(" {:02x}", val)),
+                2 => machine.peek::<u16>(at).map(|val| format!(" {:04x}", val)),
+                _ => machine.peek::<u32>(at).map(|val| format!(" {:08x}", val)),
+            };
+            output += &value.ok_or(format!("Cannot read {:#010x}", at))?;
+        }
+        output.push('\n');
+        Ok(output)
+    }
+
+    /// Frames are the PC, $ra and every stack word pointing right after a call
+    fn backtrace(&self) -> String {
+        let cpu = &self.machine.cpu;
+        let is_return_address = |addr: u32| {
+            addr.is_multiple_of(4) && self.peek_u32(addr.wrapping_sub(8)).is_some_and(is_call)
+        };
+
+        let mut frames = vec![cpu.pc()];
+        if is_return_address(cpu.gpr(REG_RA)) {
+            frames.push(cpu.gpr(REG_RA));
+        }
+        let sp = cpu.gpr(REG_SP);
+        for offset in (0..BACKTRACE_SCAN_SIZE).step_by(4) {
+            match self.peek_u32(sp.wrapping_add(offset)) {
+                Some(addr) if is_return_address(addr) && !frames.contains(&addr) => frames.push(addr),
+                _ => (),
+            }
+        }
+        frames.iter().enumerate()
+            .map(|(i, addr)| format!("#{:<2} {:08x} {}\n", i, addr, self.symbolize(*addr)))
+            .collect()
+    }
+
+    fn disassemble_line(&self, addr: u32) -> String {
+        let marker = if addr == self.machine.cpu.pc() { "=>" } else { "  " };
+        fault::instruction_line(self.machine, addr, self.peek_u32(addr), marker)
+    }
+
+    fn peek_u32(&self, addr: u32) -> Option<u32> {
+        self.machine.peek::<u32>(addr)
+    }
+
+    fn location(&self, text: &str) -> std::result::Result<u32, String> {
+        parse_number(text)
+            .or_else(|| self.machine.symbols.borrow().lookup(text))
+            .ok_or(format!("No symbol \"{}\"", text))
+    }
+
+    fn symbolize(&self, addr: u32) -> String {
+        self.machine.symbols.borrow().format(addr)
+    }
+}
+
+/// jal, jalr, bltzal and bgezal
+fn is_call(inst: u32) -> bool {
+    match inst >> 26 {
+        0x03 => true,
+        0x00 => inst & 0x3F == 0x09,
+        0x01 => (inst >> 17) & 0xF == 0x8,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_repl_session() {
+        let machine = Machine::with_program(&[
+            0x0C004004, // jal 0x80010010
+            0x00000000, // nop
+            0x24020007, // addiu v0, zero, 7
+            0x0000000D, // break
+            0x03E00008, // jr ra
+            0x24030001, // addiu v1, zero, 1
+        ]);
+        machine.cpu.set_gpr(29, 0x801FFF00);
+        machine.symbols.borrow_mut().insert("leaf", 0x80010010, 8);
+
+        let mut repl = Repl::new(&machine);
+        assert_eq!(repl.execute("x/2w leaf").unwrap(), "80010010: 03e00008 24030001\n");
+        assert!(repl.execute("break 0x80010008 if $v1 == 1").is_ok());
+        assert!(repl.execute("break 0x80010010 if $v1 == 5").is_ok());
+        assert!(repl.execute("continue").unwrap().starts_with("Breakpoint at 0x80010008\n=> 80010008"));
+        assert_eq!(machine.cpu.gpr(3), 1);
+
+        assert!(repl.execute("poke 0x80010008 0x24020009").is_ok());
+        repl.execute("next").unwrap();
+        assert_eq!(machine.cpu.gpr(2), 9);
+        assert!(repl.execute("frobnicate").is_err());
+
+        assert!(repl.execute("watch/w 0x1000").is_ok());
+        assert!(repl.execute("poke/h 0x80001002 0x55").is_ok());
+        assert!(repl.execute("poke 0x8001000C 0xAC021000").is_ok()); // sw v0, 0x1000(zero)
+        let stop = repl.execute("step").unwrap();
+        assert!(stop.starts_with("Write watchpoint hit by 0x8001000c: 4 byte write at 0x00001000, 0x550000 -> 0x9"), "{}", stop);
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let machine = Machine::with_program(&[
+            0x0C004003, // jal 0x8001000C
+            0x00000000, // nop
+            0x0000000D, // break
+            0x03E00008, // jr ra
+            0x24030001, // addiu v1, zero, 1
+        ]);
+
+        let mut repl = Repl::new(&machine);
+        assert!(repl.execute("next").unwrap().starts_with("=> 80010008"));
+        assert_eq!(machine.cpu.gpr(3), 1);
+    }
+
+    #[test]
+    fn test_interrupt_and_bad_poke() {
+        let machine = Machine::with_program(&[
+            0x1000FFFF, // b .
+            0x00000000, // nop
+        ]);
+        static STOP: AtomicBool = AtomicBool::new(false);
+        let mut repl = Repl { machine: &machine, interrupt: &STOP };
+        assert!(repl.execute("poke/q 0x80010000 0").is_err());
+        assert_eq!(machine.read::<u32>(0x80010000).unwrap(), 0x1000FFFF);
+
+        let interrupt = std::thread::spawn(|| {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            STOP.store(true, Ordering::Relaxed);
+        });
+        assert!(repl.execute("continue").unwrap().starts_with("Interrupted\n"));
+        interrupt.join().unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+
+use crate::core::loader::elf::{Elf, ElfLine, ElfSymbolKind};
+
+/// How far past a label an address still resolves to it
+const LABEL_REACH: u32 = 0x10000;
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    /// Size in bytes, 0 when the source doesn't tell (.map, .SYM labels)
+    pub size: u32,
+}
+
+/// Address to name database used to annotate addresses as `function+offset`
+#[derive(Clone, Default)]
+pub struct SymbolTable {
+    by_address: BTreeMap<u32, Symbol>,
+    /// Source lines from ELF debug info
+    lines: Vec<ElfLine>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| invalid("SYM file is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+impl SymbolTable {
+    pub fn len(&self) -> usize {
+        self.by_address.len()
+    }
+
+    /// Adds a symbol, a sized symbol wins over a label at the same address
+    pub fn insert(&mut self, name: &str, address: u32, size: u32) {
+        match self.by_address.get(&address) {
+            Some(old) if old.size != 0 || size == 0 => (),
+            _ => {
+                self.by_address.insert(address, Symbol { name: name.to_string(), address, size });
+            }
+        }
+    }
+
+    pub fn add_elf(&mut self, elf: &Elf) {
+        for sym in &elf.symbols {
+            if sym.kind != ElfSymbolKind::Other {
+                self.insert(&sym.name, sym.address, sym.size);
+            }
+        }
+        self.lines.extend(elf.lines.iter().cloned());
+    }
+
+    pub fn symbols(&self) -> impl Iterator<Item = &Symbol> {
+        self.by_address.values()
+    }
+
+    pub fn lines(&self) -> &[ElfLine] {
+        &self.lines
+    }
+
+    /// Loads a PsyQ .SYM or a GNU ld .map file, telling them apart by the
+    /// "MND" signature. Returns how many symbols were added.
+    pub fn load_file(&mut self, path: &str) -> Result<usize> {
+        let data = std::fs::read(path)?;
+        let before = self.len();
+        if data.starts_with(b"MND") {
+            self.parse_sym(&data)?;
+        } else {
+            self.parse_map(&String::from_utf8_lossy(&data));
+        }
+        Ok(self.len() - before)
+    }
+
+    /// GNU ld map files list symbols as `<address> <name>` lines, inside the
+    /// memory map section. Section and assignment lines have more columns.
+    pub fn parse_map(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut columns = line.split_whitespace();
+            let (Some(address), Some(name), None) = (columns.next(), columns.next(), columns.next()) else {
+                continue;
+            };
+            let Some(address) = address.strip_prefix("0x") else {
+                continue;
+            };
+            let Ok(address) = u64::from_str_radix(address, 16) else {
+                continue;
+            };
+            let is_identifier = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+            if is_identifier && !name.starts_with('.') {
+                self.insert(name, address as u32, 0);
+            }
+        }
+    }
+
+    /// PsyQ SYM files: "MND" signature, version, unit and padding, then a
+    /// stream of `<u32 address> <u8 tag> <payload>` records. Only labels and
+    /// function starts are kept, debug info records are skipped.
+    pub fn parse_sym(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() < 8 || !data.starts_with(b"MND") {
+            return Err(invalid("not a PsyQ SYM file"));
+        }
+        let mut sym = Cursor { data, pos: 8 };
+        macro_rules! skip { ($len: expr) => {{ sym.take($len)?; }}; }
+        macro_rules! u8 { () => { sym.take(1)?[0] }; }
+        macro_rules! u16 { () => { u16::from_le_bytes(sym.take(2)?.try_into().unwrap()) }; }
+        macro_rules! u32 { () => { u32::from_le_bytes(sym.take(4)?.try_into().unwrap()) }; }
+        macro_rules! name { () => {{ let len = u8!() as usize; String::from_utf8_lossy(sym.take(len)?).into_owned() }}; }
+        macro_rules! skip_name { () => {{ let len = u8!() as usize; skip!(len); }}; }
+
+        while sym.pos < data.len() {
+            let address = u32!();
+            match u8!() {
+                // global and local labels
+                0x01 | 0x02 => { let name = name!(); self.insert(&name, address, 0); },
+                // line number bookkeeping
+                0x80 => (),
+                0x82 => skip!(1),
+                0x84 => skip!(2),
+                0x86 | 0x8A | 0x8E | 0x90 | 0x92 => skip!(4),
+                0x88 => { skip!(4); skip_name!(); },
+                // function start: fp, fsize, retreg, mask, maskoffs, line, file, name
+                0x8C => {
+                    skip!(2 + 4 + 2 + 4 + 4 + 4);
+                    skip_name!();
+                    let name = name!();
+                    self.insert(&name, address, 0);
+                },
+                // definitions: class, type, size, name
+                0x94 => { skip!(2 + 2 + 4); skip_name!(); },
+                // array definitions: class, type, size, dims, tag, name
+                0x96 => {
+                    skip!(2 + 2 + 4);
+                    let dims = u16!() as usize;
+                    skip!(dims * 4);
+                    skip_name!();
+                    skip_name!();
+                },
+                // overlays
+                0x98 => skip!(4 + 4),
+                0x9A => (),
+                tag => return Err(invalid(&format!("unknown SYM record tag {:#x}", tag))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Nearest symbol at or below `addr` and the offset into it. Labels,
+    /// without a size, only cover `LABEL_REACH` bytes of their own segment.
+    pub fn resolve(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let (_, sym) = self.by_address.range(..=addr).next_back()?;
+        let offset = addr - sym.address;
+        let reach = match sym.size {
+            0 if addr >> 29 != sym.address >> 29 => 0,
+            0 => LABEL_REACH,
+            size => size,
+        };
+        (offset < reach).then_some((sym, offset))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.by_address.values().find(|sym| sym.name == name).map(|sym| sym.address)
+    }
+
+    /// `function+0x10` when the address is known, plain hex otherwise
+    pub fn format(&self, addr: u32) -> String {
+        match self.resolve(addr) {
+            Some((sym, 0)) => sym.name.clone(),
+            Some((sym, offset)) => format!("{}+{:#x}", sym.name, offset),
+            None => format!("{:#010x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_map() {
+        let map = "
+.text           0x80010000     0x1234 main.o
+                0x80010000                main
+                0x80010040                update_player
+                0x80018000                _gp = ALIGN (0x10)
+";
+        let mut symbols = SymbolTable::default();
+        symbols.parse_map(map);
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.lookup("update_player"), Some(0x80010040));
+        assert_eq!(symbols.format(0x80010010), "main+0x10");
+        assert_eq!(symbols.format(0x80010040), "update_player");
+    }
+
+    #[test]
+    fn test_parse_sym() {
+        let mut sym = b"MND\x01\x00\x00\x00\x00".to_vec();
+        sym.extend_from_slice(&0x80010000u32.to_le_bytes());
+        sym.push(0x01);
+        sym.push(4);
+        sym.extend_from_slice(b"main");
+        sym.extend_from_slice(&0x80010000u32.to_le_bytes());
+        sym.extend_from_slice(&[0x86, 10, 0, 0, 0]);
+        sym.extend_from_slice(&0x80010100u32.to_le_bytes());
+        sym.push(0x8C);
+        sym.extend_from_slice(&[0; 2 + 4 + 2 + 4 + 4 + 4]);
+        sym.push(6);
+        sym.extend_from_slice(b"game.c");
+        sym.push(4);
+        sym.extend_from_slice(b"draw");
+
+        let mut symbols = SymbolTable::default();
+        symbols.parse_sym(&sym).expect("Error: didn't parse");
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.format(0x80010104), "draw+0x4");
+    }
+
+    #[test]
+    fn test_sized_symbols_dont_overreach() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("small", 0x80010000, 8);
+        assert_eq!(symbols.format(0x80010004), "small+0x4");
+        assert_eq!(symbols.format(0x80010008), "0x80010008");
+    }
+
+    #[test]
+    fn test_labels_dont_overreach() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("label", 0x80010000, 0);
+        assert_eq!(symbols.format(0x8001FFFC), "label+0xfffc");
+        assert_eq!(symbols.format(0x80020000), "0x80020000");
+        // nor into another segment, e.g. the BIOS
+        symbols.insert("high", 0x9FFFFFF0, 0);
+        assert_eq!(symbols.format(0xBFC00000), "0xbfc00000");
+    }
+}
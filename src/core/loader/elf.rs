@@ -0,0 +1,609 @@
+use std::io::{Error, ErrorKind, Read, Result};
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7FELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHN_UNDEF: u16 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0B;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1E;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0E;
+const DW_FORM_LINE_STRP: u64 = 0x1F;
+const DW_FORM_UDATA: u64 = 0x0F;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ElfSymbolKind {
+    Function,
+    Object,
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct ElfSymbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    pub kind: ElfSymbolKind,
+}
+
+/// A PT_LOAD segment, `data` is zero-extended up to `mem_size` when loaded
+pub struct ElfSegment {
+    pub address: u32,
+    pub mem_size: u32,
+    pub data: Vec<u8>,
+}
+
+/// Code in `address..end` was generated for `line` of `file`, from the
+/// DWARF line number program in `.debug_line`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElfLine {
+    pub address: u32,
+    pub end: u32,
+    pub file: String,
+    pub line: u32,
+}
+
+/// An ELF32 little-endian MIPS executable
+pub struct Elf {
+    pub entry: u32,
+    pub segments: Vec<ElfSegment>,
+    pub symbols: Vec<ElfSymbol>,
+    /// Empty when the file was built without debug info
+    pub lines: Vec<ElfLine>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Bounds checked little-endian reads over the file image
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8]> {
+        offset.checked_add(len)
+            .and_then(|end| self.0.get(offset..end))
+            .ok_or_else(|| invalid("ELF file is truncated"))
+    }
+    fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.bytes(offset, 1)?[0])
+    }
+    fn u16(&self, offset: usize) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().unwrap()))
+    }
+    fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().unwrap()))
+    }
+    fn str(&self, offset: usize) -> Result<String> {
+        let tail = self.0.get(offset..).ok_or_else(|| invalid("ELF string out of bounds"))?;
+        let len = tail.iter().position(|b| *b == 0).ok_or_else(|| invalid("ELF string is not terminated"))?;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+impl Elf {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let mut data = vec![];
+        std::fs::File::open(path)?.read_to_end(&mut data)?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let file = Reader(data);
+        if file.bytes(0, 4)? != ELF_MAGIC {
+            return Err(invalid("not an ELF file"));
+        }
+        if file.u8(4)? != ELFCLASS32 || file.u8(5)? != ELFDATA2LSB {
+            return Err(invalid("not a 32-bit little-endian ELF file"));
+        }
+        if file.u16(16)? != ET_EXEC || file.u16(18)? != EM_MIPS {
+            return Err(invalid("not a MIPS executable"));
+        }
+
+        let entry = file.u32(24)?;
+        let ph_offset = file.u32(28)? as usize;
+        let sh_offset = file.u32(32)? as usize;
+        let ph_size = file.u16(42)? as usize;
+        let ph_count = file.u16(44)? as usize;
+        let sh_size = file.u16(46)? as usize;
+        let sh_count = file.u16(48)? as usize;
+        let sh_names = file.u16(50)? as usize;
+
+        let mut segments = vec![];
+        for i in 0..ph_count {
+            let header = ph_offset + i * ph_size;
+            if file.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let offset = file.u32(header + 4)? as usize;
+            let address = file.u32(header + 8)?;
+            let file_size = file.u32(header + 16)? as usize;
+            let mem_size = file.u32(header + 20)?;
+            if mem_size == 0 {
+                continue;
+            }
+            segments.push(ElfSegment {
+                address,
+                mem_size,
+                data: file.bytes(offset, file_size)?.to_vec(),
+            });
+        }
+
+        let mut symbols = vec![];
+        for i in 0..sh_count {
+            let header = sh_offset + i * sh_size;
+            if file.u32(header + 4)? != SHT_SYMTAB {
+                continue;
+            }
+            let offset = file.u32(header + 16)? as usize;
+            let size = file.u32(header + 20)? as usize;
+            let entry_size = (file.u32(header + 36)? as usize).max(16);
+            let strtab = sh_offset + file.u32(header + 24)? as usize * sh_size;
+            let strtab_offset = file.u32(strtab + 16)? as usize;
+
+            // first entry is always the null symbol
+            for sym in (offset..offset + size).step_by(entry_size).skip(1) {
+                let name = file.str(strtab_offset + file.u32(sym)? as usize)?;
+                // undefined symbols are references to other objects, not addresses
+                if name.is_empty() || file.u16(sym + 14)? == SHN_UNDEF {
+                    continue;
+                }
+                let kind = match file.u8(sym + 12)? & 0xF {
+                    STT_FUNC => ElfSymbolKind::Function,
+                    STT_OBJECT => ElfSymbolKind::Object,
+                    _ => ElfSymbolKind::Other,
+                };
+                symbols.push(ElfSymbol {
+                    name,
+                    address: file.u32(sym + 4)?,
+                    size: file.u32(sym + 8)?,
+                    kind,
+                });
+            }
+        }
+
+        let mut lines = vec![];
+        if sh_names != 0 {
+            let names_offset = file.u32(sh_offset + sh_names * sh_size + 16)? as usize;
+            let section = |name: &str| -> Result<Option<&[u8]>> {
+                for i in 0..sh_count {
+                    let header = sh_offset + i * sh_size;
+                    if file.str(names_offset + file.u32(header)? as usize)? == name {
+                        return Ok(Some(file.bytes(file.u32(header + 16)? as usize, file.u32(header + 20)? as usize)?));
+                    }
+                }
+                Ok(None)
+            };
+            // line info only feeds coverage, the program loads without it
+            if let Some(debug_line) = section(".debug_line")? {
+                match parse_debug_line(debug_line, section(".debug_line_str")?, section(".debug_str")?) {
+                    Ok(parsed) => lines = parsed,
+                    Err(err) => eprintln!("Ignoring .debug_line: {}", err),
+                }
+            }
+        }
+
+        Ok(Self { entry, segments, symbols, lines })
+    }
+
+    /// Value of `_gp`, the linker provided base for $gp relative addressing
+    pub fn gp(&self) -> Option<u32> {
+        self.symbols.iter().find(|sym| sym.name == "_gp").map(|sym| sym.address)
+    }
+}
+
+/// Sequential reads over a DWARF section
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| invalid(".debug_line is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn uleb(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+    fn sleb(&mut self) -> Result<i64> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+    fn str(&mut self) -> Result<String> {
+        let tail = &self.data[self.pos.min(self.data.len())..];
+        let len = tail.iter().position(|b| *b == 0).ok_or_else(|| invalid("DWARF string is not terminated"))?;
+        self.pos += len + 1;
+        Ok(String::from_utf8_lossy(&tail[..len]).into_owned())
+    }
+}
+
+fn string_at(section: Option<&[u8]>, offset: u32) -> Result<String> {
+    let section = section.ok_or_else(|| invalid("DWARF string section is missing"))?;
+    Cursor { data: section, pos: offset as usize }.str()
+}
+
+/// Reads a DWARF 5 directory or file entry, returning its path and
+/// directory index
+fn read_entry(cursor: &mut Cursor, formats: &[(u64, u64)], line_str: Option<&[u8]>, debug_str: Option<&[u8]>) -> Result<(String, u64)> {
+    let mut path = String::new();
+    let mut directory = 0;
+    for (content, form) in formats {
+        let mut value = 0;
+        match *form {
+            DW_FORM_STRING => path = cursor.str()?,
+            DW_FORM_LINE_STRP => path = string_at(line_str, cursor.u32()?)?,
+            DW_FORM_STRP => path = string_at(debug_str, cursor.u32()?)?,
+            DW_FORM_UDATA => value = cursor.uleb()?,
+            DW_FORM_DATA1 => value = cursor.u8()? as u64,
+            DW_FORM_DATA2 => value = cursor.u16()? as u64,
+            DW_FORM_DATA4 => value = cursor.u32()? as u64,
+            DW_FORM_DATA8 => { cursor.take(8)?; },
+            DW_FORM_DATA16 => { cursor.take(16)?; },
+            DW_FORM_BLOCK => {
+                let len = cursor.uleb()? as usize;
+                cursor.take(len)?;
+            },
+            form => return Err(invalid(&format!("unsupported DWARF form {:#x} in .debug_line", form))),
+        }
+        if *content == DW_LNCT_DIRECTORY_INDEX {
+            directory = value;
+        }
+    }
+    Ok((path, directory))
+}
+
+fn read_formats(cursor: &mut Cursor) -> Result<Vec<(u64, u64)>> {
+    let count = cursor.u8()?;
+    (0..count).map(|_| Ok((cursor.uleb()?, cursor.uleb()?))).collect()
+}
+
+fn join_path(directory: Option<&String>, name: String) -> String {
+    match directory {
+        Some(directory) if !name.starts_with('/') && !directory.is_empty() => format!("{}/{}", directory, name),
+        _ => name,
+    }
+}
+
+/// Runs the line number programs of every unit in `.debug_line` (DWARF 2
+/// to 5, 32-bit format) and turns their rows into address ranges.
+fn parse_debug_line(data: &[u8], line_str: Option<&[u8]>, debug_str: Option<&[u8]>) -> Result<Vec<ElfLine>> {
+    let mut lines = vec![];
+    let mut unit = Cursor { data, pos: 0 };
+    while unit.pos < data.len() {
+        let length = unit.u32()? as usize;
+        if length >= 0xFFFFFFF0 {
+            return Err(invalid("64-bit DWARF is not supported"));
+        }
+        let mut cursor = Cursor { data: unit.take(length)?, pos: 0 };
+        let version = cursor.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(invalid(&format!("unsupported .debug_line version {}", version)));
+        }
+        if version >= 5 {
+            // address and segment selector sizes
+            cursor.take(2)?;
+        }
+        let header_length = cursor.u32()? as usize;
+        let program = cursor.pos + header_length;
+        let min_inst_length = cursor.u8()? as u32;
+        if version >= 4 {
+            // maximum operations per instruction, always 1 outside of VLIW
+            cursor.u8()?;
+        }
+        // default_is_stmt
+        cursor.u8()?;
+        let line_base = cursor.u8()? as i8 as i64;
+        let line_range = cursor.u8()?;
+        let opcode_base = cursor.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(invalid("bad .debug_line header"));
+        }
+        let opcode_lengths = cursor.take(opcode_base as usize - 1)?;
+
+        let mut directories = vec![];
+        let mut files = vec![];
+        if version >= 5 {
+            let formats = read_formats(&mut cursor)?;
+            for _ in 0..cursor.uleb()? {
+                directories.push(read_entry(&mut cursor, &formats, line_str, debug_str)?.0);
+            }
+            let formats = read_formats(&mut cursor)?;
+            if !formats.iter().any(|(content, _)| *content == DW_LNCT_PATH) {
+                return Err(invalid("file entries without a path"));
+            }
+            for _ in 0..cursor.uleb()? {
+                let (name, directory) = read_entry(&mut cursor, &formats, line_str, debug_str)?;
+                files.push(join_path(directories.get(directory as usize), name));
+            }
+        } else {
+            // index 0 is the compilation directory, the header lists the rest
+            directories.push(String::new());
+            loop {
+                let directory = cursor.str()?;
+                if directory.is_empty() {
+                    break;
+                }
+                directories.push(directory);
+            }
+            // file numbers start at 1
+            files.push(String::new());
+            loop {
+                let name = cursor.str()?;
+                if name.is_empty() {
+                    break;
+                }
+                let directory = cursor.uleb()?;
+                cursor.uleb()?;
+                cursor.uleb()?;
+                files.push(join_path(directories.get(directory as usize), name));
+            }
+        }
+
+        cursor.pos = program;
+        let mut address = 0u32;
+        let mut file = 1u64;
+        let mut line = 1i64;
+        // (address, file, line, end_sequence)
+        let mut rows = vec![];
+        while cursor.pos < cursor.data.len() {
+            let opcode = cursor.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                address = address.wrapping_add((adjusted / line_range) as u32 * min_inst_length);
+                line += line_base + (adjusted % line_range) as i64;
+                rows.push((address, file, line, false));
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = cursor.uleb()? as usize;
+                    let end = cursor.pos + len;
+                    match cursor.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            rows.push((address, file, line, true));
+                            address = 0;
+                            file = 1;
+                            line = 1;
+                        },
+                        DW_LNE_SET_ADDRESS => address = cursor.u32()?,
+                        DW_LNE_DEFINE_FILE => {
+                            let name = cursor.str()?;
+                            let directory = cursor.uleb()?;
+                            files.push(join_path(directories.get(directory as usize), name));
+                        },
+                        _ => (),
+                    }
+                    cursor.pos = end;
+                },
+                DW_LNS_COPY => rows.push((address, file, line, false)),
+                DW_LNS_ADVANCE_PC => address = address.wrapping_add(cursor.uleb()? as u32 * min_inst_length),
+                DW_LNS_ADVANCE_LINE => line += cursor.sleb()?,
+                DW_LNS_SET_FILE => file = cursor.uleb()?,
+                DW_LNS_CONST_ADD_PC => address = address.wrapping_add(((255 - opcode_base) / line_range) as u32 * min_inst_length),
+                DW_LNS_FIXED_ADVANCE_PC => address = address.wrapping_add(cursor.u16()? as u32),
+                // the rest only changes state we don't keep
+                opcode => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        cursor.uleb()?;
+                    }
+                },
+            }
+        }
+
+        // a row covers the code up to the next one of its sequence
+        for pair in rows.windows(2) {
+            let ((start, file, line, end_sequence), (end, ..)) = (pair[0], pair[1]);
+            if !end_sequence && start < end {
+                lines.push(ElfLine {
+                    address: start,
+                    end,
+                    file: files.get(file as usize).cloned().unwrap_or_default(),
+                    line: line as u32,
+                });
+            }
+        }
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine, mips::mips::REG_GP};
+    use super::*;
+
+    /// One PT_LOAD segment with 8 bytes of code and 8 of BSS, plus a symtab
+    /// holding `main`, `_gp` and an undefined `printf`.
+    fn build_elf() -> Vec<u8> {
+        let mut data = vec![0u8; 0x200];
+        let put16 = |data: &mut Vec<u8>, offset: usize, val: u16| data[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+        let put32 = |data: &mut Vec<u8>, offset: usize, val: u32| data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        data[0..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS32;
+        data[5] = ELFDATA2LSB;
+        put16(&mut data, 16, ET_EXEC);
+        put16(&mut data, 18, EM_MIPS);
+        put32(&mut data, 24, 0x80010004);
+        put32(&mut data, 28, 0x34); // phoff
+        put32(&mut data, 32, 0x100); // shoff
+        put16(&mut data, 42, 32);
+        put16(&mut data, 44, 1);
+        put16(&mut data, 46, 40);
+        put16(&mut data, 48, 3);
+
+        // program header
+        put32(&mut data, 0x34, PT_LOAD);
+        put32(&mut data, 0x38, 0x80); // offset
+        put32(&mut data, 0x3C, 0x80010000);
+        put32(&mut data, 0x44, 8); // filesz
+        put32(&mut data, 0x48, 16); // memsz
+        put32(&mut data, 0x80, 0x11111111);
+        put32(&mut data, 0x84, 0x22222222);
+
+        // section 1: symtab at 0x90, 4 entries, linked to section 2
+        put32(&mut data, 0x128 + 4, SHT_SYMTAB);
+        put32(&mut data, 0x128 + 16, 0x90);
+        put32(&mut data, 0x128 + 20, 64);
+        put32(&mut data, 0x128 + 24, 2);
+        put32(&mut data, 0x128 + 36, 16);
+        put32(&mut data, 0x90 + 16, 1);
+        put32(&mut data, 0x90 + 20, 0x80010004);
+        put32(&mut data, 0x90 + 24, 4);
+        data[0x90 + 28] = STT_FUNC;
+        put16(&mut data, 0x90 + 30, 1);
+        put32(&mut data, 0x90 + 32, 6);
+        put32(&mut data, 0x90 + 36, 0x80018000);
+        put16(&mut data, 0x90 + 46, 0xFFF1); // SHN_ABS
+        put32(&mut data, 0x90 + 48, 10);
+        data[0x90 + 60] = STT_FUNC;
+
+        // section 2: strtab at 0xD0
+        put32(&mut data, 0x150 + 16, 0xD0);
+        data[0xD1..0xE0].copy_from_slice(b"main\0_gp\0printf");
+        data
+    }
+
+    #[test]
+    fn test_load_elf() {
+        let elf = Elf::parse(&build_elf()).expect("Error: didn't parse");
+        assert_eq!(elf.symbols.len(), 2);
+        assert_eq!(elf.symbols[0].name, "main");
+        assert_eq!(elf.symbols[0].kind, ElfSymbolKind::Function);
+        assert_eq!(elf.gp(), Some(0x80018000));
+        assert!(elf.symbols.iter().all(|symbol| symbol.name != "printf"));
+
+        let machine = Machine::new();
+        machine.ram.write::<u32>(0x10008, 0xDEADBEEF).unwrap();
+        machine.load_elf(&elf).expect("Error: didn't load");
+
+        assert_eq!(machine.read::<u32>(0x80010000).unwrap(), 0x11111111);
+        assert_eq!(machine.read::<u32>(0x80010004).unwrap(), 0x22222222);
+        assert_eq!(machine.read::<u32>(0x80010008).unwrap(), 0);
+        assert_eq!(machine.cpu.pc(), 0x80010004);
+        assert_eq!(machine.cpu.gpr(REG_GP), 0x80018000);
+        assert_eq!(machine.symbols.borrow().lookup("main"), Some(0x80010004));
+    }
+
+    #[test]
+    fn test_unreadable_debug_line() {
+        let mut data = build_elf();
+        let put16 = |data: &mut Vec<u8>, offset: usize, val: u16| data[offset..offset + 2].copy_from_slice(&val.to_le_bytes());
+        let put32 = |data: &mut Vec<u8>, offset: usize, val: u32| data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        // section 3: names at 0x1E0, section 4: 64-bit DWARF .debug_line at 0x1F0
+        put16(&mut data, 48, 5);
+        put16(&mut data, 50, 3);
+        put32(&mut data, 0x178 + 16, 0x1E0);
+        data[0x1E1..0x1EC].copy_from_slice(b".debug_line");
+        put32(&mut data, 0x1A0, 1);
+        put32(&mut data, 0x1A0 + 16, 0x1F0);
+        put32(&mut data, 0x1A0 + 20, 8);
+        put32(&mut data, 0x1F0, 0xFFFFFFFF);
+
+        let elf = Elf::parse(&data).expect("Error: didn't parse");
+        assert!(elf.lines.is_empty());
+        assert_eq!(elf.symbols.len(), 2);
+    }
+
+    #[test]
+    fn test_rejects_segment_outside_ram() {
+        let mut elf = Elf::parse(&build_elf()).unwrap();
+        elf.segments[0].mem_size = 0xFFFFFFFF;
+        assert!(Machine::new().load_elf(&elf).is_err());
+        elf.segments[0].address = 0x801FFFF8;
+        elf.segments[0].mem_size = 16;
+        assert!(Machine::new().load_elf(&elf).is_err());
+    }
+
+    #[test]
+    fn test_parse_debug_line() {
+        let mut header = vec![
+            1, // min_inst_length
+            1, // default_is_stmt
+            (-5i8) as u8, // line_base
+            14, // line_range
+            13, // opcode_base
+            0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1,
+        ];
+        header.extend(b"src\0\0main.c\0\x01\0\0\0");
+        let mut program = vec![0x00, 5, DW_LNE_SET_ADDRESS];
+        program.extend(0x80010000u32.to_le_bytes());
+        program.extend([
+            DW_LNS_ADVANCE_LINE, 2,
+            DW_LNS_COPY,
+            13 + 8 * 14 + 6, // special: 8 bytes and 1 line forward
+            DW_LNS_ADVANCE_PC, 4,
+            0x00, 1, DW_LNE_END_SEQUENCE,
+        ]);
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend((header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+        let mut section = (unit.len() as u32).to_le_bytes().to_vec();
+        section.extend(unit);
+
+        let lines = parse_debug_line(&section, None, None).expect("Error: didn't parse");
+        assert_eq!(lines, vec![
+            ElfLine { address: 0x80010000, end: 0x80010008, file: "src/main.c".to_string(), line: 3 },
+            ElfLine { address: 0x80010008, end: 0x8001000C, file: "src/main.c".to_string(), line: 4 },
+        ]);
+    }
+}
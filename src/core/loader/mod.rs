@@ -0,0 +1,2 @@
+pub mod psxexe;
+pub mod elf;
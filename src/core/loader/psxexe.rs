@@ -0,0 +1,131 @@
+use std::io::{Error, ErrorKind, Read, Result};
+
+/*
+0000h-0007h ASCII ID "PS-X EXE"
+0008h-000Fh Zerofilled
+0010h       Initial PC                   (usually 80010000h, or higher)
+0014h       Initial GP/R28               (usually 0)
+0018h       Destination Address in RAM   (usually 80010000h, or higher)
+001Ch       Filesize (must be N*800h)    (excluding 800h-byte header)
+0020h       Data section Start Address   (usually 0)
+0024h       Data Section Size in bytes   (usually 0)
+0028h       BSS section Start Address    (usually 0) (when below Size=None)
+002Ch       BSS section Size in bytes    (usually 0) (0=None)
+0030h       Initial SP/R29 & FP/R30 Base (usually 801FFFF0h) (or 0=None)
+0034h       Initial SP/R29 & FP/R30 Offs (usually 0, added to above Base)
+0038h-004Bh Reserved for A(43h) Function (should be zerofilled in exefile)
+004Ch-xxxxh ASCII marker
+0800h...    Code/Data                  (loaded to entry[018h] and up) */
+pub const EXE_MAGIC: &[u8; 8] = b"PS-X EXE";
+pub const EXE_HEADER_SIZE: usize = 0x800;
+
+/// A parsed PS-X EXE file, header fields plus the text segment
+pub struct PsxExe {
+    pub pc: u32,
+    pub gp: u32,
+    pub text_address: u32,
+    pub bss_address: u32,
+    pub bss_size: u32,
+    pub sp_base: u32,
+    pub sp_offset: u32,
+    pub text: Vec<u8>,
+}
+
+impl PsxExe {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let mut data = vec![];
+        std::fs::File::open(path)?.read_to_end(&mut data)?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < EXE_HEADER_SIZE || &data[0..8] != EXE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a PS-X EXE file"));
+        }
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let text_size = word(0x1C) as usize;
+        let text = match data.get(EXE_HEADER_SIZE..EXE_HEADER_SIZE + text_size) {
+            Some(text) => text.to_vec(),
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "PS-X EXE text segment is truncated")),
+        };
+
+        Ok(Self {
+            pc: word(0x10),
+            gp: word(0x14),
+            text_address: word(0x18),
+            bss_address: word(0x28),
+            bss_size: word(0x2C),
+            sp_base: word(0x30),
+            sp_offset: word(0x34),
+            text,
+        })
+    }
+
+    /// Initial SP/FP, `None` when the header leaves the BIOS one in place
+    pub fn stack_pointer(&self) -> Option<u32> {
+        match self.sp_base {
+            0 => None,
+            base => Some(base.wrapping_add(self.sp_offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine, mips::mips::{REG_FP, REG_GP, REG_SP}};
+    use super::*;
+
+    fn build_exe(text: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; EXE_HEADER_SIZE];
+        data[0..8].copy_from_slice(EXE_MAGIC);
+        let mut put = |offset: usize, val: u32| data[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        put(0x10, 0x80010008);
+        put(0x14, 0x80020000);
+        put(0x18, 0x80010000);
+        put(0x1C, text.len() as u32);
+        put(0x28, 0x80010000 + text.len() as u32);
+        put(0x2C, 0x10);
+        put(0x30, 0x801FFF00);
+        put(0x34, 0xF0);
+        data.extend_from_slice(text);
+        data
+    }
+
+    #[test]
+    fn test_rejects_bad_magic() {
+        let mut data = build_exe(&[0; 0x800]);
+        data[0] = b'X';
+        assert!(PsxExe::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_sideload_exe() {
+        let text: Vec<u8> = (0..0x800u32).map(|i| i as u8).collect();
+        let exe = PsxExe::parse(&build_exe(&text)).expect("Error: didn't parse");
+        assert_eq!(exe.stack_pointer(), Some(0x801FFFF0));
+
+        let machine = Machine::new();
+        // garbage where BSS will be, it must get cleared
+        machine.ram.write::<u32>(0x10800, 0xDEADBEEF).unwrap();
+        machine.load_exe(&exe).expect("Error: didn't load");
+
+        assert_eq!(machine.read::<u32>(0x80010000).unwrap(), 0x03020100);
+        assert_eq!(machine.read::<u8>(0x800107FF).unwrap(), 0xFF);
+        assert_eq!(machine.read::<u32>(0x80010800).unwrap(), 0);
+        assert_eq!(machine.cpu.pc(), 0x80010008);
+        assert_eq!(machine.cpu.gpr(REG_GP), 0x80020000);
+        assert_eq!(machine.cpu.gpr(REG_SP), 0x801FFFF0);
+        assert_eq!(machine.cpu.gpr(REG_FP), 0x801FFFF0);
+    }
+
+    #[test]
+    fn test_rejects_bss_outside_ram() {
+        let mut exe = PsxExe::parse(&build_exe(&[0; 0x800])).unwrap();
+        exe.bss_size = 0xFFFFFFFF;
+        assert!(Machine::new().load_exe(&exe).is_err());
+        exe.bss_address = 0x801FFFF0;
+        exe.bss_size = 0x20;
+        assert!(Machine::new().load_exe(&exe).is_err());
+    }
+}
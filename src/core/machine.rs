@@ -1,120 +1,725 @@
-use std::{ptr::NonNull, pin::Pin };
+use std::{cell::{Cell, RefCell}, io::Read};
 
-use super::{mips::mips::Mips, bus::{BusDevice, BusError, memory::{RomMemory, RamMemory, Memory}, io::IOMap, DummyDevice}};
-const RAM_SIZE: u32 = 2 * 1024 * 1024; // 2MiB
+use super::{mips::mips::{Mips, REG_GP, REG_SP, REG_FP}, bus::{BusDevice, BusError, BusErrorKind, Direction, memory::{RomMemory, RamMemory, Memory}, io::{self, IOMap, memcontrol::{RamMapping, RAM_WINDOW_SIZE}, irq::Interrupt, dma::{DmaChannel, DmaMemory}}, policy::{BusPolicy, BusRegion, AccessPolicy}, pages::{Page, PageTable, PAGE_MASK, PAGE_SIZE}, devices::{Attachable, AttachError, Builtin, DeviceMap}}, loader::{psxexe::PsxExe, elf::{Elf, ELF_MAGIC}}, scheduler::{Event, Scheduler}, debug::{symbols::SymbolTable, profiler::Profiler, coverage::Coverage, fault::{self, Fault}, Debugger, StopReason, WatchKind}};
+pub const RAM_SIZE: u32 = 2 * 1024 * 1024; // 2MiB
+/// DTL-H development boards come with 8MiB
+pub const DEV_KIT_RAM_SIZE: u32 = 8 * 1024 * 1024;
 const BIOS_SIZE: u32 = 512 * 1024; // 512KiB
+const BIOS_BASE: u32 = 0x1FC00000;
 const SCRATCHPAD_SIZE: u32 = 4 * 1024; // 4KiB
+const SCRATCHPAD_BASE: u32 = 0x1F800000;
+/// Only the first 1KiB of the scratchpad is mapped
+const SCRATCHPAD_WINDOW: u32 = 0x400;
 pub const MASK_ADDRESS_SPACE: u32 = 0x1FFFFFFF;
+/// Start of the uncached segment, the scratchpad isn't reachable from it
+const KSEG1: u32 = 0xA0000000;
+const KSEG2: u32 = 0xC0000000;
+/// Where the BIOS jumps once the kernel is set up, right before the shell runs
+pub const SHELL_ENTRY_POINT: u32 = 0x80030000;
+/// Hard-wired devices outside the I/O area
+const DEVICES: [Builtin; 3] = [
+    ("ram", 0x00000000, RAM_WINDOW_SIZE),
+    ("scratchpad", SCRATCHPAD_BASE, SCRATCHPAD_WINDOW),
+    ("bios", BIOS_BASE, BIOS_SIZE),
+];
+/// SP/FP handed to side-loaded ELFs, same as the usual PS-X EXE stack base
+pub const DEFAULT_STACK_POINTER: u32 = 0x801FFFF0;
 
+/// Where an access lands once past the page table
+enum Target {
+    /// RAM, at this offset
+    Ram(u32),
+    /// Part of the RAM window nothing answers to, without a bus error
+    HighZ,
+    Scratchpad(u32),
+    /// An I/O port, at this physical address
+    Io(u32),
+    Bios(u32),
+    Unmapped(BusRegion),
+}
+
+/// A whole console. Nothing in it points back to it, so it can be moved,
+/// sent to another thread or cloned to take a snapshot.
+#[derive(Clone)]
 pub struct Machine {
     pub cpu: Mips,
     pub io: IOMap,
     pub ram: RamMemory,
     pub rom: RomMemory,
     pub scratchpad: RamMemory,
-    pub dummy: DummyDevice,
-    _marker: std::marker::PhantomPinned
+    /// What accesses to unmapped or unimplemented regions do
+    pub bus_policy: BusPolicy,
+    /// Fast path of the bus, kept in sync by `remap`
+    pub pages: PageTable,
+    /// Last value seen on the data bus, what open bus reads return
+    data_bus: Cell<u32>,
+    /// When the devices next need to catch up with the CPU
+    pub scheduler: Scheduler,
+    /// Devices attached with `attach`, on top of the built-in ones
+    pub devices: DeviceMap,
+    /// Symbols used to annotate addresses in traces and debuggers
+    pub symbols: RefCell<SymbolTable>,
+    /// Logs every executed instruction to stderr
+    pub trace: Cell<bool>,
+    pub debug: Debugger,
+    pub profiler: Profiler,
+    pub coverage: Coverage,
+    /// Where `run` writes the report of a fault, stderr when unset
+    pub fault_report: RefCell<Option<String>>,
 }
 
 impl Machine {
-    pub fn new() -> Pin<Box<Self>> {
-        let machine = Machine { 
-            cpu: Mips::new(NonNull::dangling()),
-            io: IOMap::default(),
-            ram: RamMemory::new(RAM_SIZE),
-            rom: RomMemory::from(Memory::new(BIOS_SIZE)),
-            scratchpad: RamMemory::new(SCRATCHPAD_SIZE),
-            dummy: DummyDevice::default(),
-            _marker: Default::default()
-        };
-        let mut boxed = Box::pin(machine);
-        unsafe {
-            let ptr = boxed.use_dumb_cheat();
-            let mut_ref: Pin<&mut Self> = Pin::as_mut(&mut boxed);
+    pub fn new() -> Self {
+        Self::build(RomMemory::from(Memory::new(BIOS_SIZE)), RAM_SIZE)
+    }
 
-            Pin::get_unchecked_mut(mut_ref).cpu.machine = ptr;
-            //Pin::get_unchecked_mut(mut_ref).slice = slice;
-        };
-        boxed
+    /// A machine without BIOS about to run `program`, loaded at 0x80010000
+    #[cfg(test)]
+    pub fn with_program(program: &[u32]) -> Self {
+        let machine = Self::new();
+        let bytes: Vec<u8> = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        machine.ram.load(0x10000, &bytes).unwrap();
+        machine.cpu.set_pc(0x80010000);
+        machine
     }
 
-    pub fn new_with_bios(path: &str) -> std::io::Result<Pin<Box<Self>>> {
+    pub fn new_with_bios(path: &str) -> std::io::Result<Self> {
+        Self::new_with_bios_and_ram(path, RAM_SIZE)
+    }
+
+    /// Same as `new_with_bios`, with `ram_size` bytes of RAM installed, as on
+    /// dev kits
+    pub fn new_with_bios_and_ram(path: &str, ram_size: u32) -> std::io::Result<Self> {
         let rom = RomMemory::from_file(path, Some(BIOS_SIZE as _))?;
+        Ok(Self::build(rom, ram_size))
+    }
+
+    fn build(rom: RomMemory, ram_size: u32) -> Self {
         let machine = Machine { 
-            cpu: Mips::new(NonNull::dangling()),
-            rom: rom,
-            ram: RamMemory::new(RAM_SIZE),
+            cpu: Mips::new(),
             io: IOMap::default(),
+            ram: RamMemory::new(ram_size),
+            rom,
             scratchpad: RamMemory::new(SCRATCHPAD_SIZE),
-            dummy: DummyDevice::default(),
-            _marker: Default::default()
+            bus_policy: BusPolicy::default(),
+            pages: PageTable::default(),
+            data_bus: Cell::new(0),
+            scheduler: Scheduler::default(),
+            devices: DeviceMap::new(DEVICES.into_iter().chain(io::DEVICES)),
+            symbols: Default::default(),
+            trace: Cell::new(false),
+            debug: Debugger::default(),
+            profiler: Profiler::default(),
+            coverage: Coverage::new(ram_size, BIOS_BASE, BIOS_SIZE),
+            fault_report: RefCell::new(None),
         };
-        let mut boxed = Box::pin(machine);
-        unsafe {
-            let ptr = boxed.use_dumb_cheat();
-            let mut_ref: Pin<&mut Self> = Pin::as_mut(&mut boxed);
+        machine.remap();
+        machine.schedule_devices();
+        machine
+    }
 
-            Pin::get_unchecked_mut(mut_ref).cpu.machine = ptr;
-            //Pin::get_unchecked_mut(mut_ref).slice = slice;
-        };
-        Ok(boxed)
+    /// Rebuilds the pages backed by RAM, scratchpad and BIOS, called when
+    /// RAM_SIZE or the cache isolation bit change
+    pub fn remap(&self) {
+        let writable = |page| if self.cpu.cop0.caches_isolated() { Page::Slow } else { page };
+        for addr in (0..RAM_WINDOW_SIZE).step_by(PAGE_SIZE as usize) {
+            let page = match self.map_ram(addr) {
+                RamMapping::Ram(offset) => Page::Ram(offset),
+                RamMapping::HighZ | RamMapping::Locked => Page::Slow,
+            };
+            self.pages.map(addr, page, writable(page), false);
+        }
+        self.pages.map(SCRATCHPAD_BASE, Page::Scratchpad, writable(Page::Scratchpad), true);
+        for offset in (0..BIOS_SIZE).step_by(PAGE_SIZE as usize) {
+            self.pages.map(BIOS_BASE + offset, Page::Rom(offset), Page::Slow, false);
+        }
     }
 
-    unsafe fn use_dumb_cheat(&self) -> NonNull<Self> {
-        let fucked = (self as * const Machine) as * mut Machine;
-        return NonNull::new(fucked).unwrap();
+    /// Makes `device` answer to physical addresses `base..base + size`, it
+    /// sees offsets from `base`. The range can't overlap any other device.
+    pub fn attach(&self, name: &'static str, base: u32, size: u32, device: impl Attachable + 'static) -> Result<(), AttachError> {
+        self.devices.attach(name, base, size, device)
     }
 
+    /// Runs forever, dies on the first fault after writing its report
     pub fn run(&self) {
-        self.cpu.run()
+        loop {
+            if let Some(StopReason::Fault(fault)) = self.step() {
+                self.report_fault(&fault);
+                panic!("{}", fault);
+            }
+        }
+    }
+
+    /// Writes the state report of a fault to `fault_report`, or stderr
+    pub fn report_fault(&self, fault: &Fault) {
+        let report = fault::report(self, Some(fault));
+        match self.fault_report.borrow().as_deref() {
+            Some(path) => if let Err(err) = std::fs::write(path, &report) {
+                eprintln!("Cannot write fault report to {}: {}\n{}", path, err, report);
+            },
+            None => eprintln!("{}", report),
+        }
+    }
+
+    /// Executes one instruction, returns the debug event it triggered if any
+    pub fn step(&self) -> Option<StopReason> {
+        let cycles = self.cpu.cycles();
+        self.cpu.step(self);
+        if self.cpu.cycles() >= self.scheduler.deadline() {
+            self.run_events();
+        }
+        if self.coverage.enabled() {
+            self.coverage.record(self.cpu.current_pc());
+        }
+        if self.profiler.enabled() {
+            self.profiler.record(self, self.cpu.current_pc(), self.cpu.cycles() - cycles);
+        }
+        self.debug.take_stop()
+    }
+
+    /// Steps one instruction, or a jump together with its delay slot, so
+    /// execution never stops between the two.
+    pub fn single_step(&self) -> StopReason {
+        if let Some(reason) = self.step() {
+            return reason;
+        }
+        if self.cpu.branch_pending() {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::Step
+    }
+
+    /// Runs at most `budget` instructions, stopping before any breakpoint,
+    /// including one at the current PC: step first to move past it.
+    pub fn resume(&self, budget: u64) -> Option<StopReason> {
+        self.resume_until(budget, |_| false)
+    }
+
+    /// Same as `resume`, also stopping with `None` once `done` holds before
+    /// an instruction
+    pub fn resume_until(&self, budget: u64, done: impl Fn(&Machine) -> bool) -> Option<StopReason> {
+        for _ in 0..budget {
+            if done(self) {
+                return None;
+            }
+            let pc = self.cpu.pc();
+            if self.debug.breakpoint_hit(self, pc) {
+                return Some(StopReason::Breakpoint { pc });
+            }
+            if let Some(reason) = self.step() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    /// Runs until the next instruction to execute is at `pc`, or a fault
+    /// stops the machine
+    pub fn run_until(&self, pc: u32) -> Option<StopReason> {
+        while self.cpu.pc() != pc {
+            if let Some(reason @ StopReason::Fault(_)) = self.step() {
+                return Some(reason);
+            }
+        }
+        None
+    }
+
+    /// Lets the BIOS boot up to the shell, failing if it faults on the way
+    fn boot_to_shell(&self) -> std::io::Result<()> {
+        match self.run_until(SHELL_ENTRY_POINT) {
+            Some(StopReason::Fault(fault)) => {
+                self.report_fault(&fault);
+                Err(std::io::Error::other(format!("BIOS faulted before the shell: {}", fault)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Loads a PS-X EXE or an ELF from disk and starts it, optionally letting
+    /// the BIOS boot up to the shell first so the kernel is initialized.
+    pub fn sideload(&self, path: &str, wait_for_shell: bool) -> std::io::Result<()> {
+        let mut magic = [0u8; 4];
+        let is_elf = std::fs::File::open(path)?.read_exact(&mut magic).is_ok() && &magic == ELF_MAGIC;
+
+        if is_elf {
+            let elf = Elf::from_file(path)?;
+            if wait_for_shell {
+                self.boot_to_shell()?;
+            }
+            self.load_elf(&elf)
+        } else {
+            let exe = PsxExe::from_file(path)?;
+            if wait_for_shell {
+                self.boot_to_shell()?;
+            }
+            self.load_exe(&exe)
+        }
+    }
+
+    /// Copies the text segment into RAM, clears BSS and sets the registers
+    /// from the header, next step will execute the EXE's entry point.
+    pub fn load_exe(&self, exe: &PsxExe) -> std::io::Result<()> {
+        let bad_range = || std::io::Error::new(std::io::ErrorKind::InvalidData, "PS-X EXE doesn't fit in RAM");
+
+        self.ram.load(exe.text_address & MASK_ADDRESS_SPACE, &exe.text).map_err(|_| bad_range())?;
+        let bss_start = exe.bss_address & MASK_ADDRESS_SPACE;
+        if !self.fits_in_ram(bss_start, exe.bss_size) {
+            return Err(bad_range());
+        }
+        self.clear_ram(bss_start, exe.bss_size).map_err(|_| bad_range())?;
+
+        self.cpu.set_gpr(REG_GP, exe.gp);
+        if let Some(sp) = exe.stack_pointer() {
+            self.cpu.set_gpr(REG_SP, sp);
+            self.cpu.set_gpr(REG_FP, sp);
+        }
+        self.cpu.set_pc(exe.pc);
+        Ok(())
+    }
+
+    /// Whether `size` bytes at physical `start` are all in RAM, for sizes
+    /// from file headers that can't be trusted with an allocation
+    fn fits_in_ram(&self, start: u32, size: u32) -> bool {
+        start as u64 + size as u64 <= self.ram.size().unwrap_or(0) as u64
+    }
+
+    /// Zeroes `size` bytes of RAM at `start` a block at a time
+    fn clear_ram(&self, start: u32, size: u32) -> super::bus::Result<()> {
+        const ZEROES: [u8; 0x1000] = [0; 0x1000];
+        for offset in (0..size).step_by(ZEROES.len()) {
+            let len = (size - offset).min(ZEROES.len() as u32) as usize;
+            self.ram.load(start + offset, &ZEROES[..len])?;
+        }
+        Ok(())
+    }
+
+    /// Copies every PT_LOAD segment into RAM, zero-filling past the file
+    /// data, then jumps to the entry point with $gp set to `_gp`.
+    pub fn load_elf(&self, elf: &Elf) -> std::io::Result<()> {
+        let bad_range = || std::io::Error::new(std::io::ErrorKind::InvalidData, "ELF segment doesn't fit in RAM");
+
+        for segment in &elf.segments {
+            let start = segment.address & MASK_ADDRESS_SPACE;
+            let file_size = segment.data.len() as u32;
+            let mem_size = segment.mem_size.max(file_size);
+            if !self.fits_in_ram(start, mem_size) {
+                return Err(bad_range());
+            }
+            self.ram.load(start, &segment.data).map_err(|_| bad_range())?;
+            self.clear_ram(start + file_size, mem_size - file_size).map_err(|_| bad_range())?;
+        }
+
+        if let Some(gp) = elf.gp() {
+            self.cpu.set_gpr(REG_GP, gp);
+        }
+        self.cpu.set_gpr(REG_SP, DEFAULT_STACK_POINTER);
+        self.cpu.set_gpr(REG_FP, DEFAULT_STACK_POINTER);
+        self.cpu.set_pc(elf.entry);
+        self.symbols.borrow_mut().add_elf(elf);
+        Ok(())
     }
 }
 
-impl BusDevice for Machine {
-    fn read<U: super::bus::Unit>(&self, addr: u32 ) -> super::bus::Result<U> {
+impl Machine {
+    /// Instruction fetch, doesn't go through watchpoints
+    pub fn fetch(&self, addr: u32) -> super::bus::Result<u32> {
+        let inst = self.read_bus(addr).map_err(|err| self.bus_error(err, addr, 4, Direction::Fetch))?;
+        self.latch(addr, inst);
+        Ok(inst)
+    }
+
+    /// Records the value of a completed access on the byte lanes it used
+    fn latch<U: super::bus::Unit>(&self, addr: u32, val: U) {
+        let shift = (addr & 3) * 8;
+        let lanes = (u32::MAX >> (32 - U::SIZE * 8)) << shift;
+        let val: u32 = val.into();
+        self.data_bus.set(self.data_bus.get() & !lanes | (val << shift) & lanes);
+    }
+
+    /// Adds the access context to an error coming from a device
+    fn bus_error(&self, err: BusError, addr: u32, size: u32, direction: Direction) -> BusError {
+        err.at(addr, size, direction, self.device_at(addr), self.cpu.current_pc())
+    }
+
+    /// Name of what sits at `addr`, for error messages
+    fn device_at(&self, addr: u32) -> &'static str {
+        if let Some(name) = self.devices.name_at(addr & MASK_ADDRESS_SPACE) {
+            return name;
+        }
+        match addr & MASK_ADDRESS_SPACE {
+            0x00000000..RAM_WINDOW_SIZE => "ram",
+            0x1F000000..0x1F800000 => "expansion 1",
+            0x1F800000..0x1F800400 => "scratchpad",
+            0x1F801000..0x1F802000 => "io",
+            0x1FFE0000..0x1FFE0200 if addr >= KSEG2 => "io",
+            0x1F802000..0x1F803000 => "expansion 2",
+            0x1FA00000..0x1FC00000 => "expansion 3",
+            0x1FC00000..0x1FC80000 => "bios",
+            _ => "unmapped",
+        }
+    }
+
+    fn watch_hit(&self, addr: u32, kind: WatchKind, write: bool, size: u32, old: u32, new: u32) {
+        let pc = self.cpu.current_pc();
+        self.debug.report(StopReason::Watchpoint { pc, addr, kind, write, size, old, new });
+    }
+
+    /// Applies the region's policy to an access nothing answers, `Ok` means
+    /// it completes as open bus
+    fn unmapped_access(&self, region: BusRegion, addr: u32, size: u32, write: bool) -> super::bus::Result<()> {
+        match self.bus_policy.get(region) {
+            AccessPolicy::BusError => return Err(BusErrorKind::BadAddress.into()),
+            AccessPolicy::Log if self.bus_policy.first_access(addr) => {
+                let pc = self.symbols.borrow().format(self.cpu.current_pc());
+                let direction = if write { "write to" } else { "read from" };
+                eprintln!("{}-byte {} {} {:#010x} at {}", size, direction, region.name(), addr, pc);
+            },
+            AccessPolicy::Halt => {
+                let pc = self.cpu.current_pc();
+                self.debug.report(StopReason::UnmappedAccess { pc, addr, size, write, region });
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn unmapped_read<U: super::bus::Unit>(&self, region: BusRegion, addr: u32) -> super::bus::Result<U> {
+        self.unmapped_access(region, addr, U::SIZE, false).map(|_| self.open_bus(addr, region.pulled_up()))
+    }
+
+    /// Value read when nothing drives the data bus: all ones where it's
+    /// pulled up, else whatever the last access left on its lanes
+    fn open_bus<U: super::bus::Unit>(&self, addr: u32, pulled_up: bool) -> U {
+        match pulled_up {
+            true => U::from_u32(u32::MAX),
+            false => U::from_u32(self.data_bus.get() >> ((addr & 3) * 8)),
+        }
+    }
+
+    /// Where a physical address of the RAM window lands, following RAM_SIZE
+    fn map_ram(&self, addr: u32) -> RamMapping {
+        self.io.memcontrol.map_ram(addr, self.ram.size().unwrap() as u32)
+    }
+
+    /// Physical address of `addr` for watchpoints, with RAM mirrors folded
+    /// onto the installed RAM
+    fn physical(&self, addr: u32) -> u32 {
+        match self.map_ram(addr & MASK_ADDRESS_SPACE) {
+            RamMapping::Ram(offset) if addr & MASK_ADDRESS_SPACE < RAM_WINDOW_SIZE => offset,
+            _ => addr & MASK_ADDRESS_SPACE,
+        }
+    }
+
+    /// What the physical side of `vaddr` lands on, for accesses missing the
+    /// page table and attached devices
+    fn decode(&self, vaddr: u32) -> Target {
+        let addr = vaddr & MASK_ADDRESS_SPACE;
+        match addr {
+            0x00000000..RAM_WINDOW_SIZE => match self.map_ram(addr) {
+                RamMapping::Ram(offset) => Target::Ram(offset),
+                RamMapping::HighZ => Target::HighZ,
+                RamMapping::Locked => Target::Unmapped(BusRegion::LockedRam),
+            },
+            0x1F000000..0x1F800000 => Target::Unmapped(BusRegion::Expansion1),
+            0x1F800000..0x1F800400 if vaddr < KSEG1 => Target::Scratchpad(addr & 0x3FF),
+            0x1F801000..0x1F802000 => Target::Io(addr),
+            // cache control has no KUSEG mirror, unlike the other ports
+            0x1FFE0000..0x1FFE0200 if vaddr >= KSEG2 => Target::Io(addr),
+            0x1F802000..0x1F803000 => Target::Unmapped(BusRegion::Expansion2),
+            0x1FA00000..0x1FC00000 => Target::Unmapped(BusRegion::Expansion3),
+            0x1FC00000..0x1FC80000 => Target::Bios(addr & 0x7FFFF),
+            _ => Target::Unmapped(BusRegion::Unmapped),
+        }
+    }
+
+    fn read_bus<U: super::bus::Unit>(&self, addr: u32 ) -> super::bus::Result<U> {
         // word alignment check
         if addr & (U::SIZE - 1) != 0 {
-            return Err(BusError::BadAddress);
+            return Err(BusErrorKind::Misaligned.into());
+        }
+        match self.pages.read(addr) {
+            Page::Ram(offset) => return self.ram.read::<U>(offset + (addr & PAGE_MASK)),
+            Page::Rom(offset) => return self.rom.read::<U>(offset + (addr & PAGE_MASK)),
+            Page::Scratchpad if addr & PAGE_MASK < SCRATCHPAD_WINDOW => return self.scratchpad.read::<U>(addr & PAGE_MASK),
+            _ => (),
+        }
+        if let Some(result) = self.devices.read::<U>(addr & MASK_ADDRESS_SPACE) {
+            return result;
         }
-        let addr = addr & MASK_ADDRESS_SPACE;
 
-        match addr {
-            0x00000000..0x00200000 => self.ram.read::<U>(addr & 0x1FFFFF),
-            0x1F000000..0x1F800000 => Err(BusError::CannotRead),// todo!("Expansion Region 1 (ROM/RAM)"),
-            0x1F800000..0x1F800400 => self.scratchpad.read::<U>(addr & 0x3FF),// todo!("Scratchpad (D-Cache used as Fast RAM)"),
-            0x1F801000..0x1F802000 => self.io.read::<U>(addr),// todo!("I/O Ports"),
-            0x1F802000..0x1F803000 => self.dummy.read(addr),// todo!("Expansion Region 2 (I/O Ports)"),
-            0x1FA00000..0x1FC00000 => Err(BusError::CannotRead),// todo!("Expansion Region 3 (SRAM BIOS region for DTL cards)"),
-            0x1FC00000..0x1FC80000 => self.rom.read::<U>(addr & 0x7FFFF),
-            0x1FFE0000..0x1FFE0200 => self.io.read::<U>(addr), // cache control
-            _ => Err(BusError::BadAddress)
+        match self.decode(addr) {
+            Target::Ram(offset) => self.ram.read::<U>(offset),
+            // nothing drives the bus
+            Target::HighZ => Ok(self.open_bus(addr, false)),
+            Target::Scratchpad(offset) => self.scratchpad.read::<U>(offset),
+            // only ports nothing answers to follow the bus policy, errors
+            // of the devices go through
+            Target::Io(phys) if !self.io.claims(phys) => self.unmapped_read(BusRegion::Io, addr),
+            Target::Io(phys) => self.sync_devices().io.read::<U>(phys),
+            Target::Bios(offset) => self.rom.read::<U>(offset),
+            Target::Unmapped(region) => self.unmapped_read(region, addr),
+        }
+    }
+}
+
+impl BusDevice for Machine {
+    fn read<U: super::bus::Unit>(&self, addr: u32 ) -> super::bus::Result<U> {
+        let val = self.read_bus::<U>(addr).map_err(|err| self.bus_error(err, addr, U::SIZE, Direction::Read))?;
+        self.latch(addr, val);
+        if let Some(kind) = self.debug.watchpoint_at(addr, U::SIZE, false, |addr| self.physical(addr)) {
+            self.watch_hit(addr, kind, false, U::SIZE, val.into(), val.into());
         }
+        Ok(val)
     }
 
     fn write<U: super::bus::Unit>(&self, addr: u32, val: U ) -> super::bus::Result<()> {
+        self.write_bus(addr, val).map_err(|err| self.bus_error(err, addr, U::SIZE, Direction::Write))?;
+        self.latch(addr, val);
+        Ok(())
+    }
+
+    /// No watchpoints, policies nor device side effects: unmapped and
+    /// locked areas can't be peeked
+    fn peek<U: super::bus::Unit>(&self, addr: u32) -> Option<U> {
+        if addr & (U::SIZE - 1) != 0 {
+            return None;
+        }
+        match self.pages.read(addr) {
+            Page::Ram(offset) => return self.ram.peek::<U>(offset + (addr & PAGE_MASK)),
+            Page::Rom(offset) => return self.rom.peek::<U>(offset + (addr & PAGE_MASK)),
+            Page::Scratchpad if addr & PAGE_MASK < SCRATCHPAD_WINDOW => return self.scratchpad.peek::<U>(addr & PAGE_MASK),
+            _ => (),
+        }
+        if let Some(val) = self.devices.peek::<U>(addr & MASK_ADDRESS_SPACE) {
+            return val;
+        }
+        match self.decode(addr) {
+            Target::Ram(offset) => self.ram.peek(offset),
+            Target::HighZ => Some(self.open_bus(addr, false)),
+            Target::Scratchpad(offset) => self.scratchpad.peek(offset),
+            Target::Io(phys) => self.sync_devices().io.peek(phys),
+            Target::Bios(offset) => self.rom.peek(offset),
+            Target::Unmapped(_) => None,
+        }
+    }
+
+    /// Writes through isolated caches and into the BIOS, as a debugger
+    /// setting up a test would want
+    fn poke<U: super::bus::Unit>(&self, addr: u32, val: U) -> bool {
+        if addr & (U::SIZE - 1) != 0 {
+            return false;
+        }
+        if let Some(poked) = self.devices.poke::<U>(addr & MASK_ADDRESS_SPACE, val) {
+            return poked;
+        }
+        match self.decode(addr) {
+            Target::Ram(offset) => self.ram.poke(offset, val),
+            Target::Scratchpad(offset) => self.scratchpad.poke(offset, val),
+            Target::Io(phys) => {
+                let poked = self.sync_devices().io.poke(phys, val);
+                self.io_poked(phys);
+                poked
+            },
+            Target::Bios(offset) => self.rom.poke(offset, val),
+            Target::HighZ | Target::Unmapped(_) => false,
+        }
+    }
+
+    fn size(&self) -> Option<usize> { None }
+}
+
+impl Machine {
+    fn write_bus<U: super::bus::Unit>(&self, addr: u32, val: U ) -> super::bus::Result<()> {
         // word alignment check
         if addr & (U::SIZE - 1) != 0 {
-            return Err(BusError::BadAddress);
+            return Err(BusErrorKind::Misaligned.into());
+        }
+        // isolated caches swallow every write, none of them is watched
+        if self.cpu.cop0.caches_isolated() {
+            return Ok(());
+        }
+        if let Some(kind) = self.debug.watchpoint_at(addr, U::SIZE, true, |addr| self.physical(addr)) {
+            let old = self.peek::<U>(addr).map(Into::into).unwrap_or(0);
+            self.watch_hit(addr, kind, true, U::SIZE, old, val.into());
         }
-        else if self.cpu.cop0.caches_isolated() { // ignore writes if caches
-            return  Ok(());
+        match self.pages.write(addr) {
+            Page::Ram(offset) => return self.ram.write::<U>(offset + (addr & PAGE_MASK), val),
+            Page::Scratchpad if addr & PAGE_MASK < SCRATCHPAD_WINDOW => return self.scratchpad.write(addr & PAGE_MASK, val),
+            _ => (),
+        }
+        if let Some(result) = self.devices.write::<U>(addr & MASK_ADDRESS_SPACE, val) {
+            return result;
         }
-        let addr = addr & MASK_ADDRESS_SPACE;
 
+        match self.decode(addr) {
+            Target::Ram(offset) => self.ram.write::<U>(offset, val),
+            Target::HighZ => Ok(()),
+            Target::Scratchpad(offset) => self.scratchpad.write(offset, val),
+            Target::Io(phys) if !self.io.claims(phys) => self.unmapped_access(BusRegion::Io, addr, U::SIZE, true),
+            Target::Io(phys) => {
+                self.sync_devices().io.write::<U>(phys, val)?;
+                self.io_written(phys);
+                Ok(())
+            },
+            Target::Bios(_) => self.unmapped_access(BusRegion::BiosRom, addr, U::SIZE, true),
+            Target::Unmapped(region) => self.unmapped_access(region, addr, U::SIZE, true),
+        }
+    }
 
+    /// Keeps the machine in sync with I/O registers it depends on, and
+    /// starts what the write asked for
+    fn io_written(&self, addr: u32) {
+        // CHCR and DPCR start transfers, DICR can raise the IRQ
+        if (0x1F801080..0x1F801100).contains(&addr) {
+            self.run_dma();
+        }
+        // GP0(1Fh), directly or through DMA
+        if self.io.gpu.take_irq() {
+            self.io.irq.request(Interrupt::Gpu);
+        }
+        self.io_poked(addr);
+    }
 
-        match addr {
-            0x00000000..0x00200000 => self.ram.write::<U>(addr & 0x1FFFFF, val),
-            0x1F000000..0x1F800000 => Err(BusError::CannotWrite),//todo!("Expansion Region 1 (ROM/RAM)"),
-            0x1F800000..0x1F800400 => self.scratchpad.write(addr & 0x3FF, val),//todo!("Scratchpad (D-Cache used as Fast RAM)"),
-            0x1F801000..0x1F802000 => self.io.write::<U>(addr, val),// todo!("I/O Ports"),
-            0x1F802000..0x1F803000 => self.dummy.write(addr, val),//todo!("Expansion Region 2 (I/O Ports)"),
-            0x1FA00000..0x1FC00000 => Err(BusError::CannotWrite),//todo!("Expansion Region 3 (SRAM BIOS region for DTL cards)"),
-            0x1FC00000..0x1FC80000 => self.rom.write::<U>(addr & 0x7FFFF, val),
-            0x1FFE0000..0x1FFE0200 => self.io.write::<U>(addr, val), // cache control
-            _ => Err(BusError::BadAddress)
+    /// The part of `io_written` without side effects on the guest
+    fn io_poked(&self, addr: u32) {
+        // RAM_SIZE moves the RAM mirrors around
+        if (0x1F801060..0x1F801064).contains(&addr) {
+            self.remap();
         }
+        self.schedule_devices();
     }
 
-    fn size(&self) -> Option<usize> { None }
-}
\ No newline at end of file
+    /// Brings the devices up to the current cycle, before the CPU looks at them
+    fn sync_devices(&self) -> &Self {
+        self.io.sync(self.cpu.cycles());
+        self
+    }
+
+    /// Handles the events due by now, then asks the devices for the next ones
+    fn run_events(&self) {
+        let now = self.cpu.cycles();
+        while let Some(event) = self.scheduler.pop_due(now) {
+            match event {
+                // catching up is all it takes, the devices raise their own IRQs
+                Event::Raster | Event::Timer(_) => self.io.sync(now),
+            }
+        }
+        self.schedule_devices();
+    }
+
+    /// Schedules the next event of every device, from its current state
+    fn schedule_devices(&self) {
+        let now = self.cpu.cycles();
+        self.scheduler.schedule(now + self.io.gpu.raster.cycles_to_edge() as u64, Event::Raster);
+        for index in 0..3 {
+            match self.io.timers.cycles_to_irq(index, &self.io.gpu.raster) {
+                Some(cycles) => self.scheduler.schedule(now + cycles.max(1), Event::Timer(index)),
+                None => self.scheduler.cancel(Event::Timer(index)),
+            }
+        }
+    }
+
+    /// Runs every pending transfer by priority, the CPU waits for them
+    fn run_dma(&self) {
+        while let Some(channel) = self.io.dma.next_channel() {
+            let cycles = match channel {
+                // the GPU, unless something else was connected in its place
+                DmaChannel::Gpu if !self.io.dma.connected(channel) => self.io.dma.transfer_with(channel, &self.io.gpu, self),
+                _ => self.io.dma.transfer(channel, self),
+            };
+            self.cpu.stall(cycles);
+        }
+        if self.io.dma.irq_edge() {
+            self.io.irq.request(Interrupt::Dma);
+        }
+    }
+}
+
+/// DMA sees physical RAM only, mirrored over its 16MB address space
+impl DmaMemory for Machine {
+    fn dma_load(&self, addr: u32) -> u32 {
+        let val = self.ram.read::<u32>(addr % self.ram.size().unwrap() as u32).unwrap_or_default();
+        if let Some(kind) = self.debug.watchpoint_at(addr, 4, false, |addr| self.physical(addr)) {
+            self.watch_hit(addr, kind, false, 4, val, val);
+        }
+        val
+    }
+
+    fn dma_store(&self, addr: u32, val: u32) {
+        let offset = addr % self.ram.size().unwrap() as u32;
+        if let Some(kind) = self.debug.watchpoint_at(addr, 4, true, |addr| self.physical(addr)) {
+            let old = self.ram.peek::<u32>(offset).unwrap_or(0);
+            self.watch_hit(addr, kind, true, 4, old, val);
+        }
+        let _ = self.ram.write::<u32>(offset, val);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn counting_machine() -> Machine {
+        Machine::with_program(&[
+            0x24420001, // addiu v0, v0, 1
+            0xAC020100, // sw v0, 0x100(zero)
+            0x08004000, // j 0x80010000
+            0x00000000, // nop
+        ])
+    }
+
+    #[test]
+    fn test_run_until_stops_on_faults() {
+        let machine = Machine::with_program(&[0x88430000]); // lwl v1, 0(v0)
+        let fault = Fault::Unimplemented { pc: 0x80010000, inst: 0x88430000 };
+        assert_eq!(machine.run_until(SHELL_ENTRY_POINT), Some(StopReason::Fault(fault)));
+        assert!(Machine::with_program(&[0x88430000]).boot_to_shell().is_err());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let machine = counting_machine();
+        machine.resume(40);
+        let snapshot = machine.clone();
+        machine.resume(40);
+        assert_eq!(machine.cpu.gpr(2), 20);
+        assert_eq!(snapshot.cpu.gpr(2), 10);
+        assert_eq!(snapshot.read::<u32>(0x100).unwrap(), 10);
+
+        // a snapshot is a machine of its own, it can run somewhere else
+        let worker = std::thread::spawn(move || {
+            snapshot.resume(40);
+            snapshot
+        });
+        let snapshot = worker.join().unwrap();
+        assert_eq!(snapshot.read::<u32>(0x100).unwrap(), 20);
+        assert_eq!(snapshot.cpu.cycles(), machine.cpu.cycles());
+        assert_eq!(machine.read::<u32>(0x100).unwrap(), 20);
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        use crate::core::{debug::{AddressSpace, WatchKind, Watchpoint}, mips::Coprocessor};
+        let machine = counting_machine();
+        machine.debug.add_watchpoint(Watchpoint { addr: 0x100, len: 4, kind: WatchKind::Access, space: AddressSpace::Physical });
+        machine.bus_policy.set(BusRegion::Expansion1, AccessPolicy::Halt);
+
+        assert!(machine.poke::<u32>(0x80000100, 41));
+        assert_eq!(machine.peek::<u32>(0xA0000100), Some(41));
+        assert_eq!(machine.peek::<u16>(0x1F801060), Some(0xB88));
+        assert_eq!(machine.peek::<u8>(0x1F000000), None);
+        assert_eq!(machine.peek::<u32>(0x80000102), None);
+        assert_eq!(machine.debug.take_stop(), None);
+
+        // the BIOS can be patched, and isolated caches don't get in the way
+        machine.cpu.cop0.write(12, 0x00010000);
+        assert!(machine.poke::<u32>(0xBFC00000, 0x0000000D));
+        assert_eq!(machine.read::<u32>(0xBFC00000).unwrap(), 0x0000000D);
+        assert!(machine.poke::<u32>(0x80000100, 42));
+        assert_eq!(machine.peek::<u32>(0x100), Some(42));
+        assert!(!machine.poke::<u8>(0x1F000000, 0));
+    }
+}
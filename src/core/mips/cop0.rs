@@ -3,6 +3,10 @@ use std::cell::Cell;
 use super::Coprocessor;
 
 const PROCESSOR_ID: u32  = 0x00000002;
+/// SR bit 16, writes go to the (unemulated) cache instead of the bus
+const SR_ISOLATE_CACHE: u32 = 1 << 16;
+/// CAUSE bit 10, the only hardware interrupt line wired on the console
+const CAUSE_HARDWARE_INTERRUPT: u32 = 1 << 10;
 
 /*
 00h INT     Interrupt
@@ -22,12 +26,14 @@ const PROCESSOR_ID: u32  = 0x00000002;
 0Bh CpU     Coprocessor unusable
 0Ch Ov      Arithmetic overflow
 0Dh-1Fh     Not used */
-enum ExceptionsCodes {
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExceptionsCodes {
     Interrupt = 0x0,
     AddressReadError = 0x4,
     AddressWriteError = 0x5,
     FetchError = 0x6,
     DataError = 0x7,
+    Syscall = 0x08,
     Breakpoint = 0x09,
     ReservedInstruction = 0x0A,
     InvalidCoprocessor = 0x0B,
@@ -50,7 +56,13 @@ cop0r14     - EPC - Return Address from Trap (R)
 cop0r15     - PRID - Processor ID (R)
 cop0r16-r31 - Garbage
 cop0r32-r63 - N/A - None such (Control regs) */
-#[derive(Default)]
+/// Implemented registers and their names, for debuggers
+pub const COP0_REGISTERS: [(u8, &str); 11] = [
+    (3, "BPC"), (5, "BDA"), (6, "JUMPDEST"), (7, "DCIC"), (8, "BadVaddr"), (9, "BDAM"),
+    (11, "BPCM"), (12, "SR"), (13, "CAUSE"), (14, "EPC"), (15, "PRID"),
+];
+
+#[derive(Clone, Default)]
 pub struct Cop0 {
     /// cop0r3      - BPC - Breakpoint on execute (R/W)
     pub breakpoint_on_execute: Cell<u32>,
@@ -117,6 +129,38 @@ impl Coprocessor for Cop0 {
 
 impl Cop0 {
     pub fn caches_isolated(&self) -> bool {
-        self.system_status.get() & 0x10009 != 0
+        self.system_status.get() & SR_ISOLATE_CACHE != 0
+    }
+
+    /// Drives CAUSE bit 10, the interrupt controller's line
+    pub fn set_hardware_interrupt(&self, raised: bool) {
+        let cause = self.exception_cause.get() & !CAUSE_HARDWARE_INTERRUPT;
+        self.exception_cause.set(cause | if raised { CAUSE_HARDWARE_INTERRUPT } else { 0 });
+    }
+
+    /// Interrupts enabled in SR (IEc) and one of the unmasked lines raised
+    pub fn interrupt_pending(&self) -> bool {
+        let sr = self.system_status.get();
+        sr & 1 != 0 && sr & self.exception_cause.get() & 0xFF00 != 0
+    }
+
+    /// Records the exception in CAUSE/EPC, pushes the interrupt enable and
+    /// mode bits stack in SR and returns the handler address.
+    pub fn enter_exception(&self, code: ExceptionsCodes, epc: u32, delay_slot: bool) -> u32 {
+        let sr = self.system_status.get();
+        self.system_status.set((sr & !0x3F) | ((sr << 2) & 0x3F));
+
+        let cause = self.exception_cause.get() & !0x8000007C;
+        self.exception_cause.set(cause | ((code as u32) << 2) | ((delay_slot as u32) << 31));
+        self.return_address_from_trap.set(epc);
+
+        // BEV selects the ROM or RAM vectors
+        if sr & (1 << 22) != 0 { 0xBFC00180 } else { 0x80000080 }
+    }
+
+    /// rfe, pops the interrupt enable and mode bits stack
+    pub fn return_from_exception(&self) {
+        let sr = self.system_status.get();
+        self.system_status.set((sr & !0xF) | ((sr >> 2) & 0xF));
     }
 }
\ No newline at end of file
@@ -0,0 +1,130 @@
+use crate::core::debug::symbols::SymbolTable;
+
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Disassembles one instruction fetched from `pc`, branch and jump targets
+/// get annotated with `symbols` when given.
+pub fn disassemble(inst: u32, pc: u32, symbols: Option<&SymbolTable>) -> String {
+    let rs = REG_NAMES[((inst >> 21) & 0x1F) as usize];
+    let rt = REG_NAMES[((inst >> 16) & 0x1F) as usize];
+    let rd = REG_NAMES[((inst >> 11) & 0x1F) as usize];
+    let shamt = (inst >> 6) & 0x1F;
+    let imm = inst & 0xFFFF;
+    let simm = imm as i16;
+    let coproc = (inst >> 26) & 0x3;
+
+    let target = |addr: u32| match symbols.and_then(|symbols| symbols.resolve(addr).map(|_| symbols.format(addr))) {
+        Some(name) => format!("{:#010x} <{}>", addr, name),
+        None => format!("{:#010x}", addr),
+    };
+    let branch = pc.wrapping_add(4).wrapping_add(((simm as i32) << 2) as u32);
+    let jump = (pc.wrapping_add(4) & 0xF0000000) | ((inst & 0x3FFFFFF) << 2);
+
+    if inst == 0 {
+        return "nop".to_string();
+    }
+    match (inst >> 26, inst & 0x3F) {
+        (0x00, 0x00) => format!("sll ${}, ${}, {}", rd, rt, shamt),
+        (0x00, 0x02) => format!("srl ${}, ${}, {}", rd, rt, shamt),
+        (0x00, 0x03) => format!("sra ${}, ${}, {}", rd, rt, shamt),
+        (0x00, 0x04) => format!("sllv ${}, ${}, ${}", rd, rt, rs),
+        (0x00, 0x06) => format!("srlv ${}, ${}, ${}", rd, rt, rs),
+        (0x00, 0x07) => format!("srav ${}, ${}, ${}", rd, rt, rs),
+        (0x00, 0x08) => format!("jr ${}", rs),
+        (0x00, 0x09) => format!("jalr ${}, ${}", rd, rs),
+        (0x00, 0x0C) => "syscall".to_string(),
+        (0x00, 0x0D) => format!("break {:#x}", (inst >> 6) & 0xFFFFF),
+        (0x00, 0x10) => format!("mfhi ${}", rd),
+        (0x00, 0x11) => format!("mthi ${}", rs),
+        (0x00, 0x12) => format!("mflo ${}", rd),
+        (0x00, 0x13) => format!("mtlo ${}", rs),
+        (0x00, 0x18) => format!("mult ${}, ${}", rs, rt),
+        (0x00, 0x19) => format!("multu ${}, ${}", rs, rt),
+        (0x00, 0x1A) => format!("div ${}, ${}", rs, rt),
+        (0x00, 0x1B) => format!("divu ${}, ${}", rs, rt),
+        (0x00, 0x20) => format!("add ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x21) => format!("addu ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x22) => format!("sub ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x23) => format!("subu ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x24) => format!("and ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x25) => format!("or ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x26) => format!("xor ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x27) => format!("nor ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x2A) => format!("slt ${}, ${}, ${}", rd, rs, rt),
+        (0x00, 0x2B) => format!("sltu ${}, ${}, ${}", rd, rs, rt),
+        (0x01, _) => {
+            let name = match ((inst >> 16) & 0x11, (inst >> 16) & 1) {
+                (0x10, _) => "bltzal",
+                (0x11, _) => "bgezal",
+                (_, 0) => "bltz",
+                _ => "bgez",
+            };
+            format!("{} ${}, {}", name, rs, target(branch))
+        },
+        (0x02, _) => format!("j {}", target(jump)),
+        (0x03, _) => format!("jal {}", target(jump)),
+        (0x04, _) => format!("beq ${}, ${}, {}", rs, rt, target(branch)),
+        (0x05, _) => format!("bne ${}, ${}, {}", rs, rt, target(branch)),
+        (0x06, _) => format!("blez ${}, {}", rs, target(branch)),
+        (0x07, _) => format!("bgtz ${}, {}", rs, target(branch)),
+        (0x08, _) => format!("addi ${}, ${}, {}", rt, rs, simm),
+        (0x09, _) => format!("addiu ${}, ${}, {}", rt, rs, simm),
+        (0x0A, _) => format!("slti ${}, ${}, {}", rt, rs, simm),
+        (0x0B, _) => format!("sltiu ${}, ${}, {}", rt, rs, simm),
+        (0x0C, _) => format!("andi ${}, ${}, {:#x}", rt, rs, imm),
+        (0x0D, _) => format!("ori ${}, ${}, {:#x}", rt, rs, imm),
+        (0x0E, _) => format!("xori ${}, ${}, {:#x}", rt, rs, imm),
+        (0x0F, _) => format!("lui ${}, {:#x}", rt, imm),
+        (0x10..=0x13, _) => {
+            let rd = (inst >> 11) & 0x1F;
+            match (inst >> 21) & 0x1F {
+                0x00 => format!("mfc{} ${}, ${}", coproc, rt, rd),
+                0x02 => format!("cfc{} ${}, ${}", coproc, rt, rd),
+                0x04 => format!("mtc{} ${}, ${}", coproc, rt, rd),
+                0x06 => format!("ctc{} ${}, ${}", coproc, rt, rd),
+                0x08 if (inst >> 16) & 1 == 0 => format!("bc{}f {}", coproc, target(branch)),
+                0x08 => format!("bc{}t {}", coproc, target(branch)),
+                0x10 if coproc == 0 && inst & 0x3F == 0x10 => "rfe".to_string(),
+                0x10..=0x1F => format!("cop{} {:#x}", coproc, inst & 0x1FFFFFF),
+                _ => format!(".word {:#010x}", inst),
+            }
+        },
+        (0x20, _) => format!("lb ${}, {}(${})", rt, simm, rs),
+        (0x21, _) => format!("lh ${}, {}(${})", rt, simm, rs),
+        (0x22, _) => format!("lwl ${}, {}(${})", rt, simm, rs),
+        (0x23, _) => format!("lw ${}, {}(${})", rt, simm, rs),
+        (0x24, _) => format!("lbu ${}, {}(${})", rt, simm, rs),
+        (0x25, _) => format!("lhu ${}, {}(${})", rt, simm, rs),
+        (0x26, _) => format!("lwr ${}, {}(${})", rt, simm, rs),
+        (0x28, _) => format!("sb ${}, {}(${})", rt, simm, rs),
+        (0x29, _) => format!("sh ${}, {}(${})", rt, simm, rs),
+        (0x2A, _) => format!("swl ${}, {}(${})", rt, simm, rs),
+        (0x2B, _) => format!("sw ${}, {}(${})", rt, simm, rs),
+        (0x2E, _) => format!("swr ${}, {}(${})", rt, simm, rs),
+        (0x30..=0x33, _) => format!("lwc{} ${}, {}(${})", coproc, (inst >> 16) & 0x1F, simm, rs),
+        (0x38..=0x3B, _) => format!("swc{} ${}, {}(${})", coproc, (inst >> 16) & 0x1F, simm, rs),
+        _ => format!(".word {:#010x}", inst),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let mut symbols = SymbolTable::default();
+        symbols.insert("main", 0x80010000, 0);
+
+        assert_eq!(disassemble(0x24820001, 0, None), "addiu $v0, $a0, 1");
+        assert_eq!(disassemble(0x8FBF0014, 0, None), "lw $ra, 20($sp)");
+        assert_eq!(disassemble(0x0C004000, 0x80020000, Some(&symbols)), "jal 0x80010000 <main>");
+        assert_eq!(disassemble(0x1440FFFF, 0x80010008, Some(&symbols)), "bne $v0, $zero, 0x80010008 <main+0x8>");
+        assert_eq!(disassemble(0x42000010, 0, None), "rfe");
+    }
+}
@@ -1,20 +1,41 @@
+use std::cell::Cell;
+
 use super::Coprocessor;
 
-#[derive(Default)]
+/// Data registers 0-31 then control registers 32-63
+pub const GTE_REGISTER_NAMES: [&str; 64] = [
+    "VXY0", "VZ0", "VXY1", "VZ1", "VXY2", "VZ2", "RGBC", "OTZ",
+    "IR0", "IR1", "IR2", "IR3", "SXY0", "SXY1", "SXY2", "SXYP",
+    "SZ0", "SZ1", "SZ2", "SZ3", "RGB0", "RGB1", "RGB2", "RES1",
+    "MAC0", "MAC1", "MAC2", "MAC3", "IRGB", "ORGB", "LZCS", "LZCR",
+    "RT11RT12", "RT13RT21", "RT22RT23", "RT31RT32", "RT33", "TRX", "TRY", "TRZ",
+    "L11L12", "L13L21", "L22L23", "L31L32", "L33", "RBK", "GBK", "BBK",
+    "LR1LR2", "LR3LG1", "LG2LG3", "LB1LB2", "LB3", "RFC", "GFC", "BFC",
+    "OFX", "OFY", "H", "DQA", "DQB", "ZSF3", "ZSF4", "FLAG",
+];
+
+#[derive(Clone)]
 pub struct Gte {
+    /// Raw register file, no command updates it yet
+    regs: [Cell<u32>; 64],
+}
 
+impl Default for Gte {
+    fn default() -> Self {
+        Self { regs: std::array::from_fn(|_| Cell::new(0)) }
+    }
 }
 
 impl Coprocessor for Gte {
     fn read(&self, reg: u8 ) -> u32 {
-        todo!()
+        self.regs[reg as usize & 0x3F].get()
     }
 
     fn write(&self, reg: u8, val: u32) {
-        todo!()
+        self.regs[reg as usize & 0x3F].set(val)
     }
 
     fn command(&self, command: u32 ) {
         todo!()
     }
-}
\ No newline at end of file
+}
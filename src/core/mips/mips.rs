@@ -1,71 +1,211 @@
-use core::panic;
-use std::{cell::Cell, ptr::NonNull};
+use std::cell::Cell;
 
-use crate::core::{machine::Machine, bus::BusDevice, mips::Coprocessor};
+use crate::core::{machine::Machine, bus::{BusDevice, BusError, BusErrorKind, Direction}, debug::{StopReason, fault::Fault}, mips::Coprocessor};
 
-use super::{cop0::Cop0, gte::Gte, };
+use super::{cop0::{Cop0, ExceptionsCodes}, gte::Gte, disasm::disassemble};
 pub const REG_SP: usize = 29;
 pub const REG_GP: usize = 28;
 pub const REG_FP: usize = 30;
 pub const REG_RA: usize = 31;
 pub const REG_PC_RESET: u32 = 0xbfc00000;
 pub const JMP_PC_MASK: u32 = 0xF0000000;
+/// Executed instructions kept for fault reports
+pub const HISTORY_LEN: usize = 32;
+const EXCEPTION_VECTORS: [u32; 2] = [0x80000080, 0xBFC00180];
+/// The R3000A. It owns no reference to the machine, every step gets the bus
+/// and the devices around it from the caller.
+#[derive(Clone)]
 pub struct Mips {
     pub cop0: Cop0,
     pub cop2: Gte,
     gprs: [Cell<u32>; 32],
     hi_lo: (Cell<u32>, Cell<u32>),
     pc: Cell<(u32, u32)>,
-
-    pub machine: NonNull<Machine>
+    /// Address of the instruction being executed
+    current_pc: Cell<u32>,
+    /// A jump was taken, next instruction is its delay slot
+    branch: Cell<bool>,
+    /// The instruction being executed sits in a delay slot
+    delay_slot: Cell<bool>,
+    /// Emulated cycles elapsed since reset
+    cycles: Cell<u64>,
+    /// Ring of the last executed (pc, instruction) pairs
+    history: [Cell<(u32, u32)>; HISTORY_LEN],
+    /// Instructions ever pushed into `history`
+    history_count: Cell<usize>,
+    /// Most recent access that raised a bus or address error exception
+    pub last_bus_error: Cell<Option<BusError>>,
 }
 
 
 impl Mips {
 
-    pub fn new(machine: NonNull<Machine>) -> Self {
+    pub fn new() -> Self {
         let cpu = Self {
             cop0: Cop0::default(),
             cop2: Gte::default(),
             gprs: Default::default(),
             hi_lo: Default::default(),
             pc: Cell::new((REG_PC_RESET, REG_PC_RESET + 4)),
-            machine
+            current_pc: Cell::new(REG_PC_RESET),
+            branch: Cell::new(false),
+            delay_slot: Cell::new(false),
+            cycles: Cell::new(0),
+            history: Default::default(),
+            history_count: Cell::new(0),
+            last_bus_error: Cell::new(None),
         };
         //for i in 1..31 {
         //    cpu.gprs[i].set(0xDEADBEEF)
         //}
         cpu
     }
-    pub fn run(&self) {
-        let mut counter = 0;
-        loop {
-            let pc = self.step_pc();
+}
 
-            let fetch_next_instruction = self.get_machine().read(pc);
+impl Default for Mips {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            match fetch_next_instruction {
-                Ok( word ) => self.execute(word, pc),
-                Err( err ) => panic!("Error during fetch, {:#?}",err)
-            }
-            counter = counter + 1;
+impl Mips {
+    /// Fetches and executes a single instruction
+    pub fn step(&self, machine: &Machine) {
+        let pc = self.step_pc();
+        self.current_pc.set(pc);
+        self.delay_slot.set(self.branch.replace(false));
+        self.cycles.set(self.cycles.get() + 1);
+
+        // the interrupted instruction runs again once the handler returns
+        self.cop0.set_hardware_interrupt(machine.io.irq.pending());
+        if self.cop0.interrupt_pending() {
+            self.exception(machine, ExceptionsCodes::Interrupt, pc);
+            return;
+        }
 
+        let fetch_next_instruction = machine.fetch(pc);
+
+        match fetch_next_instruction {
+            Ok( word ) => {
+                if machine.trace.get() {
+                    self.trace(machine, word, pc);
+                }
+                let count = self.history_count.get();
+                self.history[count % HISTORY_LEN].set((pc, word));
+                self.history_count.set(count + 1);
+                self.execute(machine, word, pc)
+            },
+            // raising it would fetch from the vector again, forever
+            Err( err ) if EXCEPTION_VECTORS.contains(&pc) => self.fault(machine, Fault::Bus(err)),
+            Err( err ) => self.bus_error(machine, err, pc)
+        }
+    }
+    fn trace(&self, machine: &Machine, inst: u32, pc: u32) {
+        let symbols = machine.symbols.borrow();
+        let location = match symbols.resolve(pc) {
+            Some(_) => symbols.format(pc),
+            None => String::new()
+        };
+        eprintln!("{:08x} {:<32} {}", pc, location, disassemble(inst, pc, Some(&symbols)));
+    }
+    /// Address of the next instruction to be executed
+    pub fn pc(&self) -> u32 {
+        self.pc.get().0
+    }
+    /// Moves execution to `pc`, dropping any pending branch
+    pub fn set_pc(&self, pc: u32) {
+        self.pc.set((pc, pc.wrapping_add(4)));
+        self.branch.set(false);
+    }
+    /// Address of the instruction being executed, or last executed between steps
+    pub fn current_pc(&self) -> u32 {
+        self.current_pc.get()
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+    /// Cycles the CPU spends waiting for the bus, held by DMA for instance
+    pub fn stall(&self, cycles: u64) {
+        self.cycles.set(self.cycles.get() + cycles);
+    }
+    /// Whether the next instruction is the delay slot of a taken jump
+    pub fn branch_pending(&self) -> bool {
+        self.branch.get()
+    }
+    pub fn gpr(&self, reg: usize) -> u32 {
+        self.gprs[reg].get()
+    }
+    pub fn set_gpr(&self, reg: usize, val: u32) {
+        if reg != 0 {
+            self.gprs[reg].set(val);
         }
     }
-    fn get_machine(&self) -> &Machine {
-        unsafe { std::mem::transmute(self.machine) }
+    pub fn hi(&self) -> u32 {
+        self.hi_lo.0.get()
+    }
+    pub fn lo(&self) -> u32 {
+        self.hi_lo.1.get()
     }
+    pub fn set_hi(&self, val: u32) {
+        self.hi_lo.0.set(val)
+    }
+    pub fn set_lo(&self, val: u32) {
+        self.hi_lo.1.set(val)
+    }
+    /// Raises `code` for the instruction at `pc` and jumps to the handler
+    pub fn exception(&self, machine: &Machine, code: ExceptionsCodes, pc: u32) {
+        let delay_slot = self.delay_slot.get();
+        let epc = if delay_slot { pc.wrapping_sub(4) } else { pc };
+        let handler = self.cop0.enter_exception(code, epc, delay_slot);
+        self.set_pc(handler);
+        machine.debug.exception_raised(code, pc);
+        machine.profiler.exception_raised(code, handler);
+    }
+    /// Raises the exception for a failed access made by the instruction at `pc`
+    fn bus_error(&self, machine: &Machine, err: BusError, pc: u32) {
+        let code = match (err.kind, err.direction) {
+            (BusErrorKind::Misaligned, Direction::Write) => ExceptionsCodes::AddressWriteError,
+            (BusErrorKind::Misaligned, _) => ExceptionsCodes::AddressReadError,
+            (_, Direction::Fetch) => ExceptionsCodes::FetchError,
+            _ => ExceptionsCodes::DataError,
+        };
+        if err.kind == BusErrorKind::Misaligned {
+            self.cop0.bad_virtual_address.set(err.addr);
+        }
+        self.last_bus_error.set(Some(err));
+        self.exception(machine, code, pc);
+    }
+    /// Stops the machine on something the guest can't recover from
+    fn fault(&self, machine: &Machine, fault: Fault) {
+        machine.debug.report(StopReason::Fault(fault));
+    }
+    /// Last executed instructions as (pc, instruction), oldest first
+    pub fn history(&self) -> Vec<(u32, u32)> {
+        let count = self.history_count.get();
+        (count.saturating_sub(HISTORY_LEN)..count)
+            .map(|i| self.history[i % HISTORY_LEN].get())
+            .collect()
+    }
+    /// MTC0, the bus is remapped when the cache isolation bit flips
+    pub fn write_cop0(&self, machine: &Machine, reg: u8, val: u32) {
+        let isolated = self.cop0.caches_isolated();
+        self.cop0.write(reg, val);
+        if self.cop0.caches_isolated() != isolated {
+            machine.remap();
+        }
+    }
+
     fn jump(&self, pc: u32) {
         let current = self.pc.get();
         self.pc.set((current.0, pc));
-        
+        self.branch.set(true);
     }
     fn step_pc(&self ) -> u32 {
         let current = self.pc.get();
         self.pc.set((current.1, current.1 + 4));
         current.0
     }
-    fn execute(&self, inst: u32, pc: u32) {
+    fn execute(&self, machine: &Machine, inst: u32, pc: u32) {
         self.gprs[0].set(0);
         macro_rules! shamt {() => {((inst >> 6) &0x1F) as i16};}
         macro_rules! rd {() => {((inst >> 11) &0x1F) as u8};}
@@ -121,10 +261,10 @@ impl Mips {
             },
             
             // syscall
-            (0b000000, 0b001100) => todo!(),
+            (0b000000, 0b001100) => self.exception(machine, ExceptionsCodes::Syscall, pc),
             
             // break
-            (0b000000, 0b001101) => todo!(),
+            (0b000000, 0b001101) => self.exception(machine, ExceptionsCodes::Breakpoint, pc),
             
             // move from hi
             (0b000000, 0b010000) => { set!(rd!(), self.hi_lo.0.get())},
@@ -196,24 +336,24 @@ impl Mips {
             },
             // add
             (0b000000, 0b100000) => {
-                let rs = get!(rs!());
-                let rt = get!(rt!());
+                let rs = get!(rs!()) as i32;
+                let rt = get!(rt!()) as i32;
                 
                 match rs.checked_add(rt) {
-                    Some(rd) => set!(rd!(), rd),
-                    _ => panic!("No exception till now")
+                    Some(rd) => set!(rd!(), rd as u32),
+                    _ => self.exception(machine, ExceptionsCodes::ArithmeticOverflow, pc)
                 }
             },
             // addu
             (0b000000, 0b100001) => set!(rd!(), get!(rs!()).wrapping_add(get!(rt!()))),
             // sub
             (0b000000, 0b100010) => {
-                let rs = get!(rs!());
-                let rt = get!(rt!());
+                let rs = get!(rs!()) as i32;
+                let rt = get!(rt!()) as i32;
                 
                 match rs.checked_sub(rt) {
-                    Some(rd) => set!(rd!(), rd),
-                    _ => panic!("No exception till now")
+                    Some(rd) => set!(rd!(), rd as u32),
+                    _ => self.exception(machine, ExceptionsCodes::ArithmeticOverflow, pc)
                 }
             },
             // subu
@@ -291,11 +431,11 @@ impl Mips {
             },
             // addi 
             (0b001000, _) => {
-                let rs = get!(rs!());
+                let rs = get!(rs!()) as i32;
                 
-                match rs.checked_add(imm!()) {
-                    Some(res) => set!(rt!(), res),
-                    _ => panic!("No exception till now")
+                match rs.checked_add(imm!() as i32) {
+                    Some(res) => set!(rt!(), res as u32),
+                    _ => self.exception(machine, ExceptionsCodes::ArithmeticOverflow, pc)
                 }
             }, 
             // addiu
@@ -328,89 +468,91 @@ impl Mips {
             //    }*/
 //
             //}//Inst::CoprocessorRunCommand { coprocessor: 3, command: coproc_cmd!() },
+            // rfe
+            (0b010000,0b010000) if rs!() == 0b10000 => self.cop0.return_from_exception(),
             (0b010000,0) => {
                 let funct = rs!();
                 match funct {
-                    0b00100 => { self.cop0.write(rd!(), get!(rt!())) },
+                    0b00100 => { self.write_cop0(machine, rd!(), get!(rt!())) },
                     0b00000 => { set!(rd!(), self.cop0.read(rt!()))},
-                    _ => panic!("Panico")
+                    _ => self.fault(machine, Fault::Unimplemented { pc, inst }),
                     //0b00110 => { self.cop0.write(rd!() << 1, get!(rt!()))},
                     //0b00010 => {},
                 }
             }
             (0b100000,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().read::<u8>(addr) {
+                match machine.read::<u8>(addr) {
                     Ok( val ) => set!(rt!(), ((val as i8) as i32) as u32),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, //Inst::LoadByte{ dst:rt!(), base:rs!(), offset:imm!(), sign_extend: true},
             (0b100001,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().read::<u16>(addr) {
+                match machine.read::<u16>(addr) {
                     Ok( val ) => set!(rt!(), ((val as i16) as i32) as u32),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, //Inst::LoadHalfWord{ dst:rt!(), base:rs!(), offset:imm!(), sign_extend: true},
             (0b100010,_) => {
-                todo!("lwl")
+                self.fault(machine, Fault::Unimplemented { pc, inst })
             },
             (0b100011,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().read::<u32>(addr) {
+                match machine.read::<u32>(addr) {
                     Ok( val ) => set!(rt!(),val),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, // Inst::LoadWord{ dst:rt!(), base:rs!(), offset:imm!() },
             (0b100100,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().read::<u8>(addr) {
+                match machine.read::<u8>(addr) {
                     Ok( val ) => set!(rt!(),val as u32),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, // Inst::LoadByte{ dst:rt!(), base:rs!(), offset:imm!() ,sign_extend: false},
             (0b100101,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().read::<u16>(addr) {
+                match machine.read::<u16>(addr) {
                     Ok( val ) => set!(rt!(),val as u32),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, // Inst::LoadHalfWord{ dst:rt!(), base:rs!(), offset:imm!() ,sign_extend: false},//self.op_lhu(instruction, debugger, shared),
-            (0b100110,_) => todo!(), //self.op_lwr(instruction, debugger, shared),
+            (0b100110,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //self.op_lwr(instruction, debugger, shared),
             (0b101000,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().write::<u8>(addr, get!(rt!()) as _) {
+                match machine.write::<u8>(addr, get!(rt!()) as _) {
                     Ok( _ ) => (),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             },//Inst::StoreByte { src: rt!(), base: rs!(), offset: imm!() },//self.op_sb(instruction, debugger, shared, renderer),
             (0b101001,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
         
-                match self.get_machine().write::<u16>(addr, get!(rt!()) as _) {
+                match machine.write::<u16>(addr, get!(rt!()) as _) {
                     Ok( _ ) => (),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             },//Inst::StoreHalfWord { src: rt!(), base: rs!(), offset: imm!() },//self.op_sh(instruction, debugger, shared, renderer),
-            (0b101010,_) => todo!(), //self.op_swl(instruction, debugger, shared, renderer),
+            (0b101010,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //self.op_swl(instruction, debugger, shared, renderer),
             // sw
             (0b101011,_) => {
                 let addr = get!(rs!()).wrapping_add(imm!());
-                match self.get_machine().write::<u32>(addr, get!(rt!())) {
+                match machine.write::<u32>(addr, get!(rt!())) {
                     Ok( _ ) => (),
-                    Err( err ) => panic!("Bus error! {:?}", err)
+                    Err( err ) => self.bus_error(machine, err, pc)
                 }
             }, //Inst::StoreWord { src: rt!(), base: rs!(), offset: imm!() },
-            (0b101110,_) => todo!(), //self.op_swr(instruction, debugger, shared, renderer),
-            (0b110000,_) => todo!(), //Inst::LoadWordIntoCoprocessor { coprocessor: 0, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc0(instruction),
-            (0b110001,_) => todo!(), //Inst::LoadWordIntoCoprocessor { coprocessor: 1, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc1(instruction),
-            (0b110010,_) => todo!(), //Inst::LoadWordIntoCoprocessor { coprocessor: 2, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc2(instruction, debugger, shared),
-            (0b110011,_) => todo!(), //Inst::LoadWordIntoCoprocessor { coprocessor: 3, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc3(instruction),
-            (0b111000,_) => todo!(), //Inst::StoreWordFromCoprocessor { coprocessor: 0, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc0(instruction),
-            (0b111001,_) => todo!(), //Inst::StoreWordFromCoprocessor { coprocessor: 1, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc1(instruction),
-            (0b111010,_) => todo!(), //Inst::StoreWordFromCoprocessor { coprocessor: 2, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc2(instruction, debugger, shared, renderer),
-            (0b111011,_) => todo!(), //Inst::StoreWordFromCoprocessor { coprocessor: 3, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc3(instruction),
-            _        => todo!(), //Inst::Invalid,
+            (0b101110,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //self.op_swr(instruction, debugger, shared, renderer),
+            (0b110000,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::LoadWordIntoCoprocessor { coprocessor: 0, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc0(instruction),
+            (0b110001,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::LoadWordIntoCoprocessor { coprocessor: 1, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc1(instruction),
+            (0b110010,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::LoadWordIntoCoprocessor { coprocessor: 2, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc2(instruction, debugger, shared),
+            (0b110011,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::LoadWordIntoCoprocessor { coprocessor: 3, dst: rt!(), base: rs!(), offset: imm!() },//self.op_lwc3(instruction),
+            (0b111000,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::StoreWordFromCoprocessor { coprocessor: 0, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc0(instruction),
+            (0b111001,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::StoreWordFromCoprocessor { coprocessor: 1, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc1(instruction),
+            (0b111010,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::StoreWordFromCoprocessor { coprocessor: 2, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc2(instruction, debugger, shared, renderer),
+            (0b111011,_) => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::StoreWordFromCoprocessor { coprocessor: 3, src: rt!(), base: rs!(), offset: imm!() },//self.op_swc3(instruction),
+            _        => self.fault(machine, Fault::Unimplemented { pc, inst }), //Inst::Invalid,
         }
 
 
@@ -428,10 +570,10 @@ mod tests {
         machine.cpu.gprs[4].set(n);
 
         {
-            machine.cpu.execute(0x24820001, 0 ); // addiu	r2,r4,1 
-            machine.cpu.execute(0x00440018, 0 ); // mult	r2,r4 
-            machine.cpu.execute(0x00001812, 0 ); // mflo	r3 
-            machine.cpu.execute(0x00031042, 0 ); // srl	r2,r3,0x1 
+            machine.cpu.execute(&machine, 0x24820001, 0 ); // addiu	r2,r4,1 
+            machine.cpu.execute(&machine, 0x00440018, 0 ); // mult	r2,r4 
+            machine.cpu.execute(&machine, 0x00001812, 0 ); // mflo	r3 
+            machine.cpu.execute(&machine, 0x00031042, 0 ); // srl	r2,r3,0x1 
         }
         assert_eq!(machine.cpu.gprs[2].get(), expected);
     }
@@ -1,6 +1,7 @@
 pub mod cop0;
 pub mod gte;
 pub mod mips;
+pub mod disasm;
 pub trait Coprocessor {
     fn read(&self, reg: u8 ) -> u32;
     fn write(&self, reg: u8, val: u32);
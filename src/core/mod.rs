@@ -1,3 +1,6 @@
 pub mod bus;
 pub mod mips;
-pub mod machine;
\ No newline at end of file
+pub mod machine;
+pub mod loader;
+pub mod debug;
+pub mod scheduler;
\ No newline at end of file
@@ -0,0 +1,106 @@
+use std::cell::{Cell, RefCell};
+
+/// Something a device wants to happen at a given cycle
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The beam reaches a blanking edge or a new line
+    Raster,
+    /// A root counter reaches a value it interrupts on
+    Timer(usize),
+}
+
+/// Future device events keyed by the machine's cycle counter. The CPU runs
+/// freely until the earliest one is due, the devices only catch up then or
+/// when the CPU touches them.
+#[derive(Clone)]
+pub struct Scheduler {
+    /// Pending events, latest first
+    events: RefCell<Vec<(u64, Event)>>,
+    /// Cycle of the earliest event, `u64::MAX` when there's none
+    deadline: Cell<u64>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self { events: RefCell::new(vec![]), deadline: Cell::new(u64::MAX) }
+    }
+}
+
+impl Scheduler {
+    /// Makes `event` due at cycle `at`, replacing the pending one of the same
+    /// kind
+    pub fn schedule(&self, at: u64, event: Event) {
+        let mut events = self.events.borrow_mut();
+        events.retain(|(_, pending)| *pending != event);
+        let index = events.partition_point(|(time, _)| *time > at);
+        events.insert(index, (at, event));
+        self.deadline.set(events.last().map_or(u64::MAX, |(time, _)| *time));
+    }
+
+    pub fn cancel(&self, event: Event) {
+        let mut events = self.events.borrow_mut();
+        events.retain(|(_, pending)| *pending != event);
+        self.deadline.set(events.last().map_or(u64::MAX, |(time, _)| *time));
+    }
+
+    /// Cycle the earliest event is due at
+    pub fn deadline(&self) -> u64 {
+        self.deadline.get()
+    }
+
+    /// Removes the earliest event if it's due by `now`
+    pub fn pop_due(&self, now: u64) -> Option<Event> {
+        if self.deadline.get() > now {
+            return None;
+        }
+        let mut events = self.events.borrow_mut();
+        let (_, event) = events.pop()?;
+        self.deadline.set(events.last().map_or(u64::MAX, |(time, _)| *time));
+        Some(event)
+    }
+
+    /// Pending events as (cycle, event), earliest first
+    pub fn pending(&self) -> Vec<(u64, Event)> {
+        self.events.borrow().iter().rev().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::{bus::BusDevice, machine::Machine};
+    use super::*;
+
+    #[test]
+    fn test_ordering() {
+        let scheduler = Scheduler::default();
+        assert_eq!(scheduler.deadline(), u64::MAX);
+        scheduler.schedule(300, Event::Timer(0));
+        scheduler.schedule(100, Event::Raster);
+        scheduler.schedule(200, Event::Timer(1));
+        scheduler.schedule(50, Event::Timer(0));
+        assert_eq!(scheduler.deadline(), 50);
+        assert_eq!(scheduler.pending(), [(50, Event::Timer(0)), (100, Event::Raster), (200, Event::Timer(1))]);
+
+        scheduler.cancel(Event::Timer(0));
+        assert_eq!(scheduler.pop_due(99), None);
+        assert_eq!(scheduler.pop_due(150), Some(Event::Raster));
+        assert_eq!(scheduler.pop_due(150), None);
+        assert_eq!(scheduler.deadline(), 200);
+    }
+
+    #[test]
+    fn test_timer_interrupt_on_time() {
+        let machine = Machine::with_program(&[]);
+        machine.write::<u32>(0x1F801108, 500).unwrap();
+        machine.write::<u32>(0x1F801104, 0x0010).unwrap();
+        let start = machine.cpu.cycles();
+
+        // nothing but nops, the devices are only looked at when the timer is due
+        while machine.io.irq.status() & 1 << 4 == 0 {
+            machine.step();
+        }
+        assert_eq!(machine.cpu.cycles() - start, 500);
+        // one-shot, it won't interrupt again
+        assert!(!machine.scheduler.pending().iter().any(|(_, event)| *event == Event::Timer(0)));
+    }
+}
@@ -1,14 +1,126 @@
-#![feature(pointer_byte_offsets)]
-#![feature(exclusive_range_pattern)]
-
-
 mod core;
-use crate::core::{machine::Machine};
+use std::time::{Duration, Instant};
+
+use crate::core::{machine::{Machine, DEV_KIT_RAM_SIZE}, bus::{memory::RamMemory, policy::{BusRegion, AccessPolicy}}, debug::condition::parse_number};
 fn main() {
     let bios = std::env::var("PSX_BIOS").unwrap();
 
-    let machine = Machine::new_with_bios(&bios).unwrap();
-    machine.run()
+    let mut exe = None;
+    let mut fast_boot = false;
+    let mut trace = false;
+    let mut symbol_files = vec![];
+    let mut gdb_port = None;
+    let mut repl = false;
+    let mut profile = None;
+    let mut coverage = None;
+    let mut dev_kit = false;
+    let mut policies = vec![];
+    let mut fault_report = None;
+    let mut attached_ram = vec![];
+    let mut cycle_budget = None;
+    let mut time_budget = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--exe" => exe = args.next(),
+            "--fast-boot" => fast_boot = true,
+            "--symbols" => symbol_files.extend(args.next()),
+            "--trace" => trace = true,
+            "--gdb" => gdb_port = args.next().map(|port| port.parse::<u16>().unwrap()),
+            "--repl" => repl = true,
+            "--profile" => profile = args.next(),
+            "--coverage" => coverage = args.next(),
+            "--dev-kit" => dev_kit = true,
+            "--unmapped" => policies.extend(args.next()),
+            "--fault-report" => fault_report = args.next(),
+            "--attach-ram" => attached_ram.extend(args.next()),
+            "--cycles" => cycle_budget = args.next().map(|cycles| cycles.parse::<u64>().unwrap()),
+            "--seconds" => time_budget = args.next().map(|seconds| Duration::from_secs_f64(seconds.parse().unwrap())),
+            _ => panic!("Unknown argument {}", arg)
+        }
+    }
+
+    let machine = match dev_kit {
+        true => Machine::new_with_bios_and_ram(&bios, DEV_KIT_RAM_SIZE).unwrap(),
+        false => Machine::new_with_bios(&bios).unwrap(),
+    };
+    machine.trace.set(trace);
+    machine.fault_report.replace(fault_report);
+    for policy in policies {
+        let (region, policy) = policy.split_once('=').unwrap();
+        machine.bus_policy.set(BusRegion::parse(region).unwrap(), AccessPolicy::parse(policy).unwrap());
+    }
+    for range in attached_ram {
+        // extra RAM somewhere in the physical space, e.g. dev kit SRAM
+        let (base, size) = range.split_once(':').unwrap();
+        let (base, size) = (parse_number(base).unwrap(), parse_number(size).unwrap());
+        // device names are static, these live as long as the machine anyway
+        let name = Box::leak(format!("ram@{:08x}", base).into_boxed_str());
+        machine.attach(name, base, size, RamMemory::new(size)).unwrap();
+    }
+    for path in symbol_files {
+        machine.symbols.borrow_mut().load_file(&path).unwrap();
+    }
+    if let Some(exe) = exe {
+        machine.sideload(&exe, !fast_boot).unwrap();
+    }
+    if profile.is_some() {
+        machine.profiler.enable(machine.cpu.pc());
+    }
+    machine.coverage.set_enabled(coverage.is_some());
+    if let Some(port) = gdb_port {
+        if core::debug::gdb::serve(&machine, port).unwrap() == core::debug::gdb::Session::Killed {
+            return;
+        }
+    }
+    if repl {
+        core::debug::repl::run(&machine).unwrap();
+    } else if profile.is_some() || coverage.is_some() {
+        // coverage is saved as it goes, in case the run is interrupted
+        let save_coverage = || if let Some(prefix) = &coverage {
+            machine.coverage.save(&machine, prefix).unwrap();
+        };
+        // report once the machine stops or runs out of budget
+        let reason = run_for(&machine, cycle_budget, time_budget, save_coverage);
+        eprintln!("Stopped: {:?}", reason);
+        if let Some(core::debug::StopReason::Fault(fault)) = reason {
+            machine.report_fault(&fault);
+        }
+        if let Some(path) = profile {
+            let symbols = machine.symbols.borrow();
+            eprintln!("{}", machine.profiler.report(&symbols));
+            std::fs::write(path, machine.profiler.folded(&symbols)).unwrap();
+        }
+        save_coverage();
+    } else {
+        machine.run()
+    }
+}
+
+/// Instructions run between budget checks
+const BUDGET_BATCH: u64 = 100_000;
+/// Wall clock time between two `checkpoint` calls
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs until the machine stops, or for `cycles` CPU cycles or `time` of
+/// wall clock time when given, whichever comes first. `checkpoint` is called
+/// every `CHECKPOINT_INTERVAL` meanwhile.
+fn run_for(machine: &Machine, cycles: Option<u64>, time: Option<Duration>, checkpoint: impl Fn()) -> Option<core::debug::StopReason> {
+    let end_cycle = cycles.map(|cycles| machine.cpu.cycles().saturating_add(cycles));
+    let start = Instant::now();
+    let mut last_checkpoint = start;
+    loop {
+        if last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            checkpoint();
+            last_checkpoint = Instant::now();
+        }
+        if let Some(reason) = machine.resume(BUDGET_BATCH) {
+            return Some(reason);
+        }
+        if end_cycle.is_some_and(|end| machine.cpu.cycles() >= end) || time.is_some_and(|time| start.elapsed() >= time) {
+            return None;
+        }
+    }
 }
 
 